@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+
+use super::bytecode::OpCode;
+use super::program::*;
+
+use crate::bail_if;
+
+// Reads bytecode in the text format that the pretty printer in `debug` writes:
+//
+//     Constants :
+//         #0: String("~")
+//         #1: String("main")
+//         #2: Method(#1, nargs:0, nlocals:0) :
+//               ...
+//               return
+//     Globals :
+//         #2
+//     Entry : #2
+//
+// Hand-written bytecode can also name constants, as in `#format: String("~")`, and refer to them
+// by name anywhere a constant can go. Labels can be bare names, as in `goto loop`, which are added
+// to the end of the constant pool as strings. A `;` starts a comment that runs to the end of the
+// line.
+//
+// The code of each method is laid out in constant pool order, which is also how the compiler lays
+// it out, so assembling the text of a compiled program gives back the same program.
+pub fn assemble(text: &str) -> Result<Program> {
+    let tokens = tokenize(text)?;
+    Assembler::new(tokens).program()
+}
+
+#[derive(PartialEq, Debug, Clone)]
+enum Token {
+    Word(String),
+    Reference(String),
+    Integer(i64),
+    String(String),
+    Punctuation(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{}`", word),
+            Token::Reference(name) => write!(f, "`#{}`", name),
+            Token::Integer(value) => write!(f, "`{}`", value),
+            Token::String(string) => write!(f, "\"{}\"", string.escape_default()),
+            Token::Punctuation(character) => write!(f, "`{}`", character),
+        }
+    }
+}
+
+fn is_name(character: char) -> bool {
+    character.is_ascii_alphanumeric() || character == '_'
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line_number = number + 1;
+        let mut characters = line.chars().peekable();
+        while let Some(&character) = characters.peek() {
+            let token = match character {
+                ';' => break,
+                _ if character.is_whitespace() => { characters.next(); continue }
+                '(' | ')' | ',' | ':' => { characters.next(); Token::Punctuation(character) }
+                '#' => {
+                    characters.next();
+                    let mut name = String::new();
+                    while let Some(&character) = characters.peek().filter(|c| is_name(**c)) {
+                        name.push(character);
+                        characters.next();
+                    }
+                    bail_if!(name.is_empty(), "Line {}: `#` must be followed by a constant number or name.", line_number);
+                    Token::Reference(name)
+                }
+                '"' => {
+                    characters.next();
+                    Token::String(unescape(&mut characters, line_number)?)
+                }
+                _ if character == '-' || character.is_ascii_digit() => {
+                    let mut digits = String::new();
+                    digits.push(character);
+                    characters.next();
+                    while let Some(&character) = characters.peek().filter(|c| c.is_ascii_digit()) {
+                        digits.push(character);
+                        characters.next();
+                    }
+                    let value = digits.parse::<i64>()
+                        .with_context(|| format!("Line {}: `{}` is not a number.", line_number, digits))?;
+                    Token::Integer(value)
+                }
+                _ if is_name(character) => {
+                    let mut word = String::new();
+                    while let Some(&character) = characters.peek().filter(|c| is_name(**c)) {
+                        word.push(character);
+                        characters.next();
+                    }
+                    Token::Word(word)
+                }
+                _ => bail!("Line {}: Unexpected character `{}`.", line_number, character),
+            };
+            tokens.push((token, line_number));
+        }
+    }
+    Ok(tokens)
+}
+
+// Undoes `str::escape_default`, which is how the pretty printer writes strings.
+fn unescape<I>(characters: &mut std::iter::Peekable<I>, line: usize) -> Result<String> where I: Iterator<Item=char> {
+    let mut string = String::new();
+    loop {
+        match characters.next() {
+            None => bail!("Line {}: Unterminated string.", line),
+            Some('"') => return Ok(string),
+            Some('\\') => match characters.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some('r') => string.push('\r'),
+                Some('0') => string.push('\0'),
+                Some(character @ ('\\' | '"' | '\'')) => string.push(character),
+                Some('u') => {
+                    bail_if!(characters.next() != Some('{'), "Line {}: Expected `{{` after `\\u`.", line);
+                    let digits: String = characters.by_ref().take_while(|c| *c != '}').collect();
+                    let character = u32::from_str_radix(&digits, 16).ok()
+                        .and_then(std::char::from_u32)
+                        .with_context(|| format!("Line {}: `\\u{{{}}}` is not a character.", line, digits))?;
+                    string.push(character)
+                }
+                Some(character) => bail!("Line {}: Unknown escape `\\{}`.", line, character),
+                None => bail!("Line {}: Unterminated string.", line),
+            },
+            Some(character) => string.push(character),
+        }
+    }
+}
+
+struct Assembler {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    // Named and numbered constants, by the position they are defined at.
+    definitions: HashMap<String, usize>,
+    defined: usize,
+    // Constants defined in the text, followed by strings for bare label names.
+    constants: Vec<ProgramObject>,
+    label_names: Vec<String>,
+    code: Code,
+    defined_labels: HashMap<ConstantPoolIndex, usize>,
+    used_labels: Vec<(ConstantPoolIndex, usize)>,
+}
+
+impl Assembler {
+    fn new(tokens: Vec<(Token, usize)>) -> Self {
+        // A reference followed by a colon defines the next constant, so every constant can be
+        // numbered before any of them is parsed, and referred to before it is defined.
+        let mut definitions = HashMap::new();
+        let mut defined = 0;
+        for pair in tokens.windows(2) {
+            if let [(Token::Reference(name), _), (Token::Punctuation(':'), _)] = pair {
+                definitions.entry(name.clone()).or_insert(defined);
+                defined += 1;
+            }
+        }
+
+        Assembler {
+            tokens, position: 0, definitions, defined,
+            constants: Vec::new(), label_names: Vec::new(), code: Code::new(),
+            defined_labels: HashMap::new(), used_labels: Vec::new(),
+        }
+    }
+
+    fn program(mut self) -> Result<Program> {
+        self.keyword("Constants")?;
+        self.punctuation(':')?;
+        while let Some(Token::Reference(_)) = self.peek() {
+            self.constant()?;
+        }
+
+        self.keyword("Globals")?;
+        self.punctuation(':')?;
+        let mut globals = Vec::new();
+        while let Some(Token::Reference(_)) = self.peek() {
+            globals.push(self.reference()?);
+        }
+
+        self.keyword("Entry")?;
+        self.punctuation(':')?;
+        let entry = self.reference()?;
+        if let Some((token, line)) = self.tokens.get(self.position) {
+            bail!("Line {}: Unexpected {} after the entry point.", line, token);
+        }
+
+        for (label, line) in self.used_labels.iter() {
+            bail_if!(!self.defined_labels.contains_key(label),
+                     "Line {}: Label {} is never defined.", line, self.label_description(label));
+        }
+
+        let constants = self.constants.into_iter()
+            .chain(self.label_names.into_iter().map(ProgramObject::from_string))
+            .collect::<Vec<ProgramObject>>();
+        Program::from(self.code, ConstantPool::from(constants), Globals::from(globals), Entry::from(entry))
+    }
+
+    fn constant(&mut self) -> Result<()> {
+        let line = self.line();
+        let name = match self.next()? {
+            Token::Reference(name) => name,
+            token => bail!("Line {}: Expected a constant, found {}.", line, token),
+        };
+        let position = self.constants.len();
+        if let Ok(number) = name.parse::<usize>() {
+            bail_if!(number != position, "Line {}: Constant #{} is defined at position {}.", line, number, position);
+        } else {
+            bail_if!(self.definitions[&name] != position, "Line {}: Constant #{} is already defined.", line, name);
+        }
+        self.punctuation(':')?;
+
+        let line = self.line();
+        let object = match self.word()?.as_str() {
+            "Null" => ProgramObject::Null,
+            "Int" => {
+                self.punctuation('(')?;
+                let value = self.integer(i32::MIN as i64, i32::MAX as i64)?;
+                self.punctuation(')')?;
+                ProgramObject::from_i32(value as i32)
+            }
+            "Bool" => {
+                self.punctuation('(')?;
+                let value = match self.word()?.as_str() {
+                    "true" => true,
+                    "false" => false,
+                    word => bail!("Line {}: Expected `true` or `false`, found `{}`.", line, word),
+                };
+                self.punctuation(')')?;
+                ProgramObject::from_bool(value)
+            }
+            "String" => {
+                self.punctuation('(')?;
+                let string = match self.next()? {
+                    Token::String(string) => string,
+                    token => bail!("Line {}: Expected a string, found {}.", line, token),
+                };
+                self.punctuation(')')?;
+                ProgramObject::from_string(string)
+            }
+            "Slot" => {
+                self.punctuation('(')?;
+                let name = self.reference()?;
+                self.punctuation(')')?;
+                ProgramObject::Slot { name }
+            }
+            "Class" => {
+                self.punctuation('(')?;
+                // `object begin end` has no members.
+                let mut members = Vec::new();
+                if self.peek() != Some(&Token::Punctuation(')')) {
+                    members.push(self.reference()?);
+                    while self.peek() == Some(&Token::Punctuation(',')) {
+                        self.next()?;
+                        members.push(self.reference()?);
+                    }
+                }
+                self.punctuation(')')?;
+                ProgramObject::Class(members)
+            }
+            "Method" => {
+                self.punctuation('(')?;
+                let name = self.reference()?;
+                self.punctuation(',')?;
+                self.keyword("nargs")?;
+                self.punctuation(':')?;
+                let parameters = self.integer(0, u8::MAX as i64)?;
+                self.punctuation(',')?;
+                self.keyword("nlocals")?;
+                self.punctuation(':')?;
+                let locals = self.integer(0, u16::MAX as i64)?;
+                self.punctuation(')')?;
+                self.punctuation(':')?;
+
+                let mut opcodes = Vec::new();
+                while let Some(Token::Word(_)) = self.peek() {
+                    if self.at_section() { break }
+                    opcodes.push(self.instruction()?);
+                }
+                ProgramObject::Method {
+                    name,
+                    parameters: Arity::new(parameters as u8),
+                    locals: Size::new(locals as u16),
+                    code: self.code.append(opcodes),
+                }
+            }
+            word => bail!("Line {}: Unknown kind of constant `{}`.", line, word),
+        };
+        self.constants.push(object);
+        Ok(())
+    }
+
+    fn instruction(&mut self) -> Result<OpCode> {
+        let line = self.line();
+        let mnemonic = self.word()?;
+        let opcode = match mnemonic.as_str() {
+            "lit" => OpCode::Literal { index: self.reference()? },
+            "get" | "set" => {
+                let kind = self.word()?;
+                match (mnemonic.as_str(), kind.as_str()) {
+                    ("get", "local") => OpCode::GetLocal { index: self.local()? },
+                    ("set", "local") => OpCode::SetLocal { index: self.local()? },
+                    ("get", "global") => OpCode::GetGlobal { name: self.reference()? },
+                    ("set", "global") => OpCode::SetGlobal { name: self.reference()? },
+                    ("get", "slot") => OpCode::GetField { name: self.reference()? },
+                    ("set", "slot") => OpCode::SetField { name: self.reference()? },
+                    _ => bail!("Line {}: Unknown instruction `{} {}`.", line, mnemonic, kind),
+                }
+            }
+            "object" => OpCode::Object { class: self.reference()? },
+            "array" => OpCode::Array,
+            "call" => {
+                if self.peek() == Some(&Token::Word("slot".to_owned())) {
+                    self.next()?;
+                    OpCode::CallMethod { name: self.reference()?, arguments: self.arity()? }
+                } else {
+                    OpCode::CallFunction { name: self.reference()?, arguments: self.arity()? }
+                }
+            }
+            "printf" => OpCode::Print { format: self.reference()?, arguments: self.arity()? },
+            "label" => {
+                let name = self.label()?;
+                let previous = self.defined_labels.insert(name, line);
+                bail_if!(previous.is_some(), "Line {}: Label {} is already defined on line {}.",
+                         line, self.label_description(&name), previous.unwrap());
+                OpCode::Label { name }
+            }
+            "goto" => {
+                let label = self.label()?;
+                self.used_labels.push((label, line));
+                OpCode::Jump { label }
+            }
+            "branch" => {
+                let label = self.label()?;
+                self.used_labels.push((label, line));
+                OpCode::Branch { label }
+            }
+            "return" => OpCode::Return,
+            "drop" => OpCode::Drop,
+            "closure" => OpCode::Closure { function: self.reference()?, captured: self.arity()? },
+            _ => bail!("Line {}: Unknown instruction `{}`.", line, mnemonic),
+        };
+        Ok(opcode)
+    }
+
+    // A reference to a constant, or a bare name, which stands for a string constant with that
+    // name added after all the others.
+    fn label(&mut self) -> Result<ConstantPoolIndex> {
+        match self.peek() {
+            Some(Token::Word(_)) => {
+                let name = self.word()?;
+                let position = match self.label_names.iter().position(|label| *label == name) {
+                    Some(position) => position,
+                    None => { self.label_names.push(name); self.label_names.len() - 1 }
+                };
+                self.index(self.defined + position)
+            }
+            _ => self.reference(),
+        }
+    }
+
+    fn label_description(&self, label: &ConstantPoolIndex) -> String {
+        let position = label.as_usize();
+        match position.checked_sub(self.defined) {
+            Some(position) => format!("`{}`", self.label_names[position]),
+            None => format!("#{}", position),
+        }
+    }
+
+    fn reference(&mut self) -> Result<ConstantPoolIndex> {
+        let line = self.line();
+        let name = match self.next()? {
+            Token::Reference(name) => name,
+            token => bail!("Line {}: Expected a constant, found {}.", line, token),
+        };
+        let position = match name.parse::<usize>() {
+            Ok(number) => {
+                bail_if!(number >= self.defined, "Line {}: There is no constant #{}.", line, number);
+                number
+            }
+            Err(_) => *self.definitions.get(&name)
+                .with_context(|| format!("Line {}: There is no constant named #{}.", line, name))?,
+        };
+        self.index(position)
+    }
+
+    fn index(&self, position: usize) -> Result<ConstantPoolIndex> {
+        bail_if!(position > u16::MAX as usize, "Line {}: Too many constants.", self.line());
+        Ok(ConstantPoolIndex::from_usize(position))
+    }
+
+    fn local(&mut self) -> Result<LocalFrameIndex> {
+        self.integer(0, u16::MAX as i64).map(|index| LocalFrameIndex::new(index as u16))
+    }
+
+    fn arity(&mut self) -> Result<Arity> {
+        self.integer(0, u8::MAX as i64).map(|arity| Arity::new(arity as u8))
+    }
+
+    fn integer(&mut self, min: i64, max: i64) -> Result<i64> {
+        let line = self.line();
+        match self.next()? {
+            Token::Integer(value) if value >= min && value <= max => Ok(value),
+            Token::Integer(value) => bail!("Line {}: {} is not between {} and {}.", line, value, min, max),
+            token => bail!("Line {}: Expected a number, found {}.", line, token),
+        }
+    }
+
+    fn word(&mut self) -> Result<String> {
+        let line = self.line();
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => bail!("Line {}: Expected a word, found {}.", line, token),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<()> {
+        let line = self.line();
+        match self.next()? {
+            Token::Word(word) if word == keyword => Ok(()),
+            token => bail!("Line {}: Expected `{}`, found {}.", line, keyword, token),
+        }
+    }
+
+    fn punctuation(&mut self, character: char) -> Result<()> {
+        let line = self.line();
+        match self.next()? {
+            Token::Punctuation(found) if found == character => Ok(()),
+            token => bail!("Line {}: Expected `{}`, found {}.", line, character, token),
+        }
+    }
+
+    // `Globals :` ends the last method.
+    fn at_section(&self) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word == "Globals")
+            && matches!(self.tokens.get(self.position + 1), Some((Token::Punctuation(':'), _)))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.tokens.get(self.position).map(|(token, _)| token.clone())
+            .with_context(|| format!("Line {}: Unexpected end of input.", self.line()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.position)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+}
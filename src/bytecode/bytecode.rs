@@ -1,4 +1,6 @@
-use std::io::{Write, Read};
+use std::io::Write;
+
+use anyhow::Context;
 
 use super::serializable;
 use super::serializable::*;
@@ -18,8 +20,9 @@ pub enum OpCode {
      *
      * The [ProgramObject] retrieved from the [ConstantPool] is *guaranteed* to be one of:
      *  - [ProgramObject::Integer],
-     *  - [ProgramObject::Boolean], or
-     *  - [ProgramObject::Null].
+     *  - [ProgramObject::Boolean],
+     *  - [ProgramObject::Null], or
+     *  - [ProgramObject::String], which is copied into a new string on the heap.
      *
      * Serialized as opcode `0x01`.
      *
@@ -29,8 +32,9 @@ pub enum OpCode {
      * [ProgramObject::Boolean]: ../objects/enum.ProgramObject.html#variant.Boolean
      * [ProgramObject::Integer]: ../objects/enum.ProgramObject.html#variant.Integer
      * [ProgramObject::Null]: ../objects/enum.ProgramObject.html#variant.Null
+     * [ProgramObject::String]: ../objects/enum.ProgramObject.html#variant.String
      */
-    Literal { index: /*Integer|Null|Boolean*/ ConstantPoolIndex }, // rename to constant
+    Literal { index: /*Integer|Null|Boolean|String*/ ConstantPoolIndex }, // rename to constant
 
     /**
      * ## Push the value of local variable onto stack
@@ -247,6 +251,20 @@ pub enum OpCode {
      * Serialized as opcode `0x10`.
      */
     Drop,
+
+    /**
+     * ## Create a closure
+     *
+     * Pops `captured` values from the `OperandStack`. Then, creates a new closure on the heap that
+     * calls the `ProgramObject::Method` at the index specified by `function`, and pushes it onto
+     * the `OperandStack`.
+     *
+     * When the closure is called, the captured values fill the first local slots of its frame,
+     * right after the arguments, starting with the deepest value on the stack (last popped).
+     *
+     * Not part of Feeny. Serialized as opcode `0x11`.
+     */
+    Closure { function: /*Method*/ ConstantPoolIndex, captured: Arity },
 }
 
 
@@ -284,37 +302,45 @@ impl Serializable for OpCode {
             Jump { label } => { label.serialize(sink) },
             Return => { Ok(()) },
             Drop => { Ok(()) },
+            Closure { function, captured } => {
+                function.serialize(sink)?;
+                captured.serialize(sink)
+            },
             // Skip => { Ok(()) },
         }
     }
 
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        let tag = serializable::read_u8(input);
+    fn from_bytes<R: Input>(input: &mut R) -> anyhow::Result<Self> {
+        let offset = input.offset();
+        let tag = serializable::read_u8(input)?;
 
         use OpCode::*;
-        match tag {
-            0x00 => Label        { name:      ConstantPoolIndex::from_bytes(input)  },
-            0x01 => Literal      { index:     ConstantPoolIndex::from_bytes(input)  },
-            0x02 => Print        { format:    ConstantPoolIndex::from_bytes(input),
-                                   arguments: Arity::from_bytes(input)              },
+        let opcode = match tag {
+            0x00 => Label        { name:      ConstantPoolIndex::from_bytes(input)? },
+            0x01 => Literal      { index:     ConstantPoolIndex::from_bytes(input)? },
+            0x02 => Print        { format:    ConstantPoolIndex::from_bytes(input)?,
+                                   arguments: Arity::from_bytes(input)?             },
             0x03 => Array        {                                                  },
-            0x04 => Object       { class:     ConstantPoolIndex::from_bytes(input)  },
-            0x05 => GetField { name:      ConstantPoolIndex::from_bytes(input)  },
-            0x06 => SetField { name:      ConstantPoolIndex::from_bytes(input)  },
-            0x07 => CallMethod   { name:      ConstantPoolIndex::from_bytes(input),
-                                   arguments: Arity::from_bytes(input)              },
-            0x08 => CallFunction { name:  ConstantPoolIndex::from_bytes(input),
-                                   arguments: Arity::from_bytes(input)              },
-            0x09 => SetLocal     { index:     LocalFrameIndex::from_bytes(input)    },
-            0x0A => GetLocal     { index:     LocalFrameIndex::from_bytes(input)    },
-            0x0B => SetGlobal    { name:      ConstantPoolIndex::from_bytes(input)  },
-            0x0C => GetGlobal    { name:      ConstantPoolIndex::from_bytes(input)  },
-            0x0D => Branch       { label:     ConstantPoolIndex::from_bytes(input)  },
-            0x0E => Jump         { label:     ConstantPoolIndex::from_bytes(input)  },
+            0x04 => Object       { class:     ConstantPoolIndex::from_bytes(input)? },
+            0x05 => GetField { name:      ConstantPoolIndex::from_bytes(input)? },
+            0x06 => SetField { name:      ConstantPoolIndex::from_bytes(input)? },
+            0x07 => CallMethod   { name:      ConstantPoolIndex::from_bytes(input)?,
+                                   arguments: Arity::from_bytes(input)?             },
+            0x08 => CallFunction { name:  ConstantPoolIndex::from_bytes(input)?,
+                                   arguments: Arity::from_bytes(input)?             },
+            0x09 => SetLocal     { index:     LocalFrameIndex::from_bytes(input)?   },
+            0x0A => GetLocal     { index:     LocalFrameIndex::from_bytes(input)?   },
+            0x0B => SetGlobal    { name:      ConstantPoolIndex::from_bytes(input)? },
+            0x0C => GetGlobal    { name:      ConstantPoolIndex::from_bytes(input)? },
+            0x0D => Branch       { label:     ConstantPoolIndex::from_bytes(input)? },
+            0x0E => Jump         { label:     ConstantPoolIndex::from_bytes(input)? },
             0x0F => Return,
             0x10 => Drop,
-            tag  => panic!("Cannot deserialize opcode: unknown tag {}", tag)
-        }
+            0x11 => Closure      { function:  ConstantPoolIndex::from_bytes(input)?,
+                                   captured:  Arity::from_bytes(input)?             },
+            tag  => anyhow::bail!("Expected an opcode tag at byte {}, found 0x{:02X}", offset, tag),
+        };
+        Ok(opcode)
     }
 }
 
@@ -339,17 +365,28 @@ impl OpCode {
             Jump         { label: _                  } => 0x0E,
             Return                                     => 0x0F,
             Drop                                       => 0x10,
+            Closure      { function: _, captured: _  } => 0x11,
             // Skip => 0xFF,
         }
     }
 
-    pub fn read_opcode_vector<R: Read>(reader: &mut R) -> Vec<OpCode> {
-        let length = serializable::read_u32_as_usize(reader);
+    // Whether the opcode is not part of the Feeny instruction set.
+    pub fn is_extended(&self) -> bool {
+        self.to_hex() > 0x10
+    }
+
+    // Reads opcodes that will be placed in the code starting from the given address.
+    pub fn read_opcode_vector<R: Input>(reader: &mut R, start: Address) -> anyhow::Result<Vec<OpCode>> {
+        let length = serializable::read_u32_as_usize(reader)?;
         let mut opcodes: Vec<OpCode> = Vec::new();
-        for _ in 0..length {
-            opcodes.push(OpCode::from_bytes(reader));
+        for i in 0..length {
+            let offset = reader.offset();
+            let opcode = OpCode::from_bytes(reader).with_context(|| {
+                format!("Cannot read opcode at address {} (byte {})", start.offset(i), offset)
+            })?;
+            opcodes.push(opcode);
         }
-        opcodes
+        Ok(opcodes)
     }
 
     pub fn write_opcode_vector<W: Write>(sink: &mut W, vector: &Vec<&OpCode>) -> anyhow::Result<()> {
@@ -398,6 +435,8 @@ impl std::fmt::Display for OpCode {
                 write!(f, "return"),
             OpCode::Drop =>
                 write!(f, "drop"),
+            OpCode::Closure { function, captured } =>
+                write!(f, "closure {} {}", function, captured),
         }
     }
 }
\ No newline at end of file
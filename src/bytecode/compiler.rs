@@ -2,21 +2,25 @@ use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 
 use crate::parser::*;
+use crate::parser::diagnostics::SourceFile;
 
 use super::bytecode::OpCode;
 use super::program::Program;
 use crate::bytecode::program::*;
+use crate::bytecode::debug_info::{DebugInfo, LocalName, MethodLocals};
 
 use crate::bail_if;
 
 use anyhow::*;
 
+#[derive(Clone)]
 pub struct ProgramGenerator {
     pub constant_pool: ConstantPool,
     pub labels: LabelGenerator,
     pub completed_code: Code,
     pub globals: Globals,
     pub entry: Entry,
+    pub local_names: Vec<MethodLocals>,
 }
 
 impl ProgramGenerator {
@@ -27,6 +31,12 @@ impl ProgramGenerator {
             completed_code: Code::new(),
             globals: Globals::new(),
             entry: Entry::new(),
+            local_names: Vec::new(),
+        }
+    }
+    pub fn record_local_names(&mut self, method: ConstantPoolIndex, names: Vec<LocalName>) {
+        if !names.is_empty() {
+            self.local_names.push(MethodLocals { method, names })
         }
     }
     pub fn materialize(self) -> Result<Program> {
@@ -41,10 +51,12 @@ impl ProgramGenerator {
             globals: self.globals,
             entry: self.entry,
             labels,
+            debug: None,
         })
     }
 }
 
+#[derive(Clone)]
 pub struct LabelGenerator {
     names: HashSet<String>, groups: usize
 }
@@ -86,7 +98,7 @@ impl LabelGroup<'_> {
     }
 }
 
-pub fn compile(ast: &AST) -> Result<Program> {
+fn generate(ast: &AST) -> Result<ProgramGenerator> {
     let mut program = ProgramGenerator::new();
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::Top;
@@ -95,7 +107,21 @@ pub fn compile(ast: &AST) -> Result<Program> {
     ast.compile_into(&mut program, &mut active_buffer, &mut global_environment, &mut current_frame, true)?;
 
     program.completed_code.extend(active_buffer);
-    program.materialize()
+    Ok(program)
+}
+
+pub fn compile(ast: &AST) -> Result<Program> {
+    generate(ast)?.materialize()
+}
+
+// Compiles the AST and keeps a line table that maps instructions back to the source, as well as
+// the names of local variables.
+pub fn compile_with_debug_info(ast: &AST, source: &SourceFile) -> Result<Program> {
+    let mut generator = generate(ast)?;
+    let local_names = std::mem::take(&mut generator.local_names);
+    let mut program = generator.materialize()?;
+    program.debug = Some(DebugInfo::from_spans(&program.code, source).with_locals(local_names));
+    Ok(program)
 }
 
 type Scope = usize;
@@ -121,17 +147,47 @@ impl Frame {
     }
 }
 
-#[derive(PartialEq,Debug,Clone)]
+#[derive(Debug,Clone)]
 pub struct Environment {
     locals: HashMap<(Scope, String), LocalFrameIndex>,
     scopes: Vec<Scope>,
     scope_sequence: Scope,
     unique_number: usize,
+    live_ranges: Vec<LiveRange>,
+    captured: Vec<LocalFrameIndex>,
+    enclosed: Vec<LocalFrameIndex>,
+    assigned: Vec<LocalFrameIndex>,
+    loops: Vec<LoopLabels>,
+    operands: usize,
+}
+
+// Where `break` and `continue` jump to in the innermost loop, and how many operands were on the
+// stack when it started. The end label is only emitted if something breaks out of the loop.
+#[derive(PartialEq,Debug,Clone)]
+struct LoopLabels { condition: ConstantPoolIndex, end: String, operands: usize, keep_result: bool, broken: bool }
+
+// Where in the function's buffer a local is live: from the instruction that first sets it to the
+// end of the scope it was declared in. Only used for debug info.
+#[derive(Debug,Clone)]
+struct LiveRange { index: LocalFrameIndex, name: String, scope: Scope, start: usize, end: Option<usize> }
+
+// Live ranges are bookkeeping for debug info, and the locals that closures capture or that are
+// assigned to are only used to report errors, so neither affects what the environment resolves.
+impl PartialEq for Environment {
+    fn eq(&self, other: &Self) -> bool {
+        self.locals == other.locals
+            && self.scopes == other.scopes
+            && self.scope_sequence == other.scope_sequence
+            && self.unique_number == other.unique_number
+            && self.captured == other.captured
+            && self.loops == other.loops
+            && self.operands == other.operands
+    }
 }
 
 impl Environment {
     pub fn new() -> Self {
-        Environment { locals: HashMap::new(), scopes: vec!(0), scope_sequence: 0, unique_number: 0 }
+        Environment { locals: HashMap::new(), scopes: vec!(0), scope_sequence: 0, unique_number: 0, live_ranges: Vec::new(), captured: Vec::new(), enclosed: Vec::new(), assigned: Vec::new(), loops: Vec::new(), operands: 0 }
     }
 
     pub fn from_locals(locals: Vec<String>) -> Self {
@@ -141,7 +197,7 @@ impl Environment {
             local_map.insert((0, local), LocalFrameIndex::from_usize(i));
         }
 
-        Environment { locals: local_map, scopes: vec!(0), scope_sequence: 0, unique_number: 0 }
+        Environment { locals: local_map, scopes: vec!(0), scope_sequence: 0, unique_number: 0, live_ranges: Vec::new(), captured: Vec::new(), enclosed: Vec::new(), assigned: Vec::new(), loops: Vec::new(), operands: 0 }
     }
 
 
@@ -152,7 +208,7 @@ impl Environment {
             local_map.insert((level, local), LocalFrameIndex::from_usize(i));
         }
 
-        Environment { locals: local_map, scopes: vec!(0), scope_sequence: level + 1, unique_number: 0 }
+        Environment { locals: local_map, scopes: vec!(0), scope_sequence: level + 1, unique_number: 0, live_ranges: Vec::new(), captured: Vec::new(), enclosed: Vec::new(), assigned: Vec::new(), loops: Vec::new(), operands: 0 }
     }
 
     fn current_scope(&self) -> Scope {
@@ -202,6 +258,29 @@ impl Environment {
         return false;
     }
 
+    // Captured variables are locals of a closure that hold copies of the enclosing function's locals.
+    fn register_captured(&mut self, id: &str) -> LocalFrameIndex {
+        let index = self.register_local(id);
+        self.captured.push(index);
+        index
+    }
+
+    fn is_captured(&self, index: &LocalFrameIndex) -> bool {
+        self.captured.contains(index)
+    }
+
+    // A closure copies the locals it captures, so a local that is assigned to anywhere in its
+    // function cannot also be captured. Both return whether the local is still only one of them.
+    fn capture_from(&mut self, index: LocalFrameIndex) -> bool {
+        self.enclosed.push(index);
+        !self.assigned.contains(&index)
+    }
+
+    fn assign(&mut self, index: LocalFrameIndex) -> bool {
+        self.assigned.push(index);
+        !self.enclosed.contains(&index)
+    }
+
     fn in_outermost_scope(&self) -> bool {
         assert!(!self.scopes.is_empty());
         self.scopes.len() == 1
@@ -220,6 +299,32 @@ impl Environment {
         self.scopes.pop()
             .expect("Cannot leave scope: the scope stack is empty");
     }
+
+    fn mark_live(&mut self, index: LocalFrameIndex, name: &str, address: Address) {
+        let scope = self.current_scope();
+        self.live_ranges.push(LiveRange { index, name: name.to_owned(), scope, start: address.value_usize(), end: None });
+    }
+
+    // Ends the live ranges of the locals declared in the scope being left.
+    fn leave_scope_at(&mut self, address: Address) {
+        let scope = self.current_scope();
+        self.live_ranges.iter_mut()
+            .filter(|range| range.scope == scope && range.end.is_none())
+            .for_each(|range| range.end = Some(address.value_usize()));
+        self.leave_scope();
+    }
+
+    // Live ranges become absolute once the function's buffer lands in the completed code. Locals
+    // whose scope never ended live until the end of the function.
+    fn local_names(&self, start: Address, length: usize) -> Vec<LocalName> {
+        self.live_ranges.iter()
+            .map(|range| {
+                let end = range.end.unwrap_or(length);
+                let live = AddressRange::from(start.value_usize() + range.start, end - range.start);
+                LocalName { index: range.index, name: range.name.clone(), live }
+            })
+            .collect()
+    }
 }
 
 pub trait Compiled {
@@ -235,6 +340,21 @@ pub trait Compiled {
 
 impl Compiled for AST {
     fn compile_into(&self, program: &mut ProgramGenerator, active_buffer: &mut Code, global_environment: &mut Environment, current_frame: &mut Frame, keep_result: bool) -> Result<()> {
+        let start = active_buffer.upcoming_address();
+        let result = self.compile_node(program, active_buffer, global_environment, current_frame, keep_result);
+        match self.span() {
+            Some(span) => {
+                result.map_err(|error| span.attach_to(error))?;
+                active_buffer.annotate(start, span);
+            }
+            None => result?,
+        }
+        Ok(())
+    }
+}
+
+impl AST {
+    fn compile_node(&self, program: &mut ProgramGenerator, active_buffer: &mut Code, global_environment: &mut Environment, current_frame: &mut Frame, keep_result: bool) -> Result<()> {
         match self {
             AST::Integer(value) => {
                 let constant = ProgramObject::Integer(*value);
@@ -250,6 +370,13 @@ impl Compiled for AST {
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
+            AST::String(value) => {
+                let constant = ProgramObject::String(value.to_string());
+                let index = program.constant_pool.register(constant);
+                active_buffer.emit(OpCode::Literal { index });
+                active_buffer.emit_unless(OpCode::Drop, keep_result);
+            }
+
             AST::Null => {
                 let constant = ProgramObject::Null;
                 let index = program.constant_pool.register(constant);
@@ -257,19 +384,31 @@ impl Compiled for AST {
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::Variable { name: Identifier(name), value } => {
+            AST::Variable { name: Identifier(name), value, .. } => {
+                // A closure captures values, and its own value does not exist until it is created.
+                if let AST::Lambda { parameters, body, .. } = value.deref() {
+                    let defines_local = match current_frame {
+                        Frame::Local(_) => true,
+                        Frame::Top => !global_environment.in_outermost_scope(),
+                    };
+                    bail_if!(defines_local && !is_local(name, global_environment, current_frame)
+                                 && free_variables(parameters, body).contains(name),
+                             "Cannot use `{}` in the function assigned to it: a local function cannot refer to itself, use a named function instead", name);
+                }
                 value.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
                 match current_frame {
                     Frame::Local(environment) => {
                         let index = environment.register_new_local(name)
-                            .expect(&format!("Cannot register new variable {}", &name))
+                            .map_err(|error| anyhow!("Cannot register new variable {}: {}", name, error))?
                             .clone();
+                        environment.mark_live(index, name, active_buffer.upcoming_address());
                         active_buffer.emit(OpCode::SetLocal { index });
                     },
                     Frame::Top if !global_environment.in_outermost_scope() => {
                         let index = global_environment.register_new_local(name)
-                            .expect(&format!("Cannot register new variable {}", &name))
+                            .map_err(|error| anyhow!("Cannot register new variable {}: {}", name, error))?
                             .clone();
+                        global_environment.mark_live(index, name, active_buffer.upcoming_address());
                         active_buffer.emit(OpCode::SetLocal { index });
                     },
                     _ => {
@@ -277,14 +416,14 @@ impl Compiled for AST {
                         let slot_index =
                             program.constant_pool.register(ProgramObject::Slot { name: name_index });
                         program.globals.register(slot_index)
-                            .expect(&format!("Cannot register new global {}", name));
+                            .with_context(|| format!("Cannot register new global {}", name))?;
                         active_buffer.emit(OpCode::SetGlobal { name: name_index });
                     },
                 }
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::AccessVariable { name: Identifier(name) } => {
+            AST::AccessVariable { name: Identifier(name), .. } => {
                 match current_frame {
                     Frame::Local(environment) if environment.has_local(name) => {
                         let index = environment.register_local(name).clone();
@@ -299,17 +438,22 @@ impl Compiled for AST {
                         active_buffer.emit(OpCode::GetGlobal { name: index });
                     },
                 }
+                active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::AssignVariable { name: Identifier(name), value } => {
+            AST::AssignVariable { name: Identifier(name), value, .. } => {
                 match current_frame {
                     Frame::Local(environment) if environment.has_local(name) => {
                         let index = environment.register_local(name).clone(); // FIXME error if does not exists
+                        bail_if!(environment.is_captured(&index) || !environment.assign(index),
+                                 "Cannot assign to `{}`: a function captures the value of a variable, not the variable itself", name);
                         value.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;    // FIXME scoping!!!
                         active_buffer.emit(OpCode::SetLocal { index });
                     },
                     Frame::Top if !global_environment.in_outermost_scope() && global_environment.has_local(name) => {
                         let index = global_environment.register_local(name).clone(); // FIXME error if does not exists
+                        bail_if!(!global_environment.assign(index),
+                                 "Cannot assign to `{}`: a function captures the value of a variable, not the variable itself", name);
                         value.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;    // FIXME scoping!!!
                         active_buffer.emit(OpCode::SetLocal { index });
                     },
@@ -322,7 +466,7 @@ impl Compiled for AST {
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::Conditional { condition, consequent, alternative } => {
+            AST::Conditional { condition, consequent, alternative, .. } => {
                 let label_generator = program.labels.create_group();
                 let consequent_label = label_generator.generate_name("if:consequent")?;
                 let end_label = label_generator.generate_name("if:end")?;
@@ -343,38 +487,54 @@ impl Compiled for AST {
                 //program.labels.set(end_label, program.code.current_address())?;
             }
 
-            AST::Loop { condition, body } => {
-                let label_generator = program.labels.create_group();
-                let body_label = label_generator.generate_name("loop:body")?;
-                let condition_label = label_generator.generate_name("loop:condition")?;
-
-                let body_label_index =
-                    program.constant_pool.register(ProgramObject::from_str(&body_label));
-                let condition_label_index =
-                    program.constant_pool.register(ProgramObject::from_str(&condition_label));
-
-                active_buffer.emit(OpCode::Jump { label: condition_label_index });
-                active_buffer.emit(OpCode::Label { name: body_label_index });
-                //program.labels.set(body_label, program.code.current_address())?;
-                (**body).compile_into(program, active_buffer, global_environment, current_frame, false)?;
-                active_buffer.emit(OpCode::Label { name: condition_label_index });
-                //program.labels.set(condition_label, program.code.current_address())?;
-                (**condition).compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                active_buffer.emit(OpCode::Branch { label: body_label_index });
+            AST::Loop { condition, body, .. } => {
+                compile_loop(condition, body, true, program, active_buffer, global_environment, current_frame, keep_result)?;
+            }
 
-                if keep_result {
-                    let constant = ProgramObject::Null;
-                    let index = program.constant_pool.register(constant);
-                    active_buffer.emit(OpCode::Literal { index });
+            AST::Break { value, .. } => {
+                let environment = frame_environment(global_environment, current_frame);
+                let innermost = environment.loops.last_mut()
+                    .ok_or_else(|| anyhow!("Cannot use `break` outside of a loop"))?;
+                innermost.broken = true;
+                let innermost = innermost.clone();
+                let pending = environment.operands - innermost.operands;
+
+                value.deref().compile_into(program, active_buffer, global_environment, current_frame, innermost.keep_result)?;
+                if innermost.keep_result {
+                    drop_operands_below_result(pending, active_buffer, global_environment, current_frame)?;
+                } else {
+                    (0..pending).for_each(|_| active_buffer.emit(OpCode::Drop));
                 }
+                let end = program.constant_pool.register(ProgramObject::from_str(&innermost.end));
+                active_buffer.emit(OpCode::Jump { label: end });
             }
 
-            AST::Array { size, value } => {
+            AST::Return { value, .. } => {
+                let pending = match current_frame {
+                    Frame::Local(environment) => environment.operands,
+                    Frame::Top => bail!("Cannot use `return` outside of a function"),
+                };
+                value.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
+                drop_operands_below_result(pending, active_buffer, global_environment, current_frame)?;
+                active_buffer.emit(OpCode::Return);
+            }
+
+            AST::Continue { .. } => {
+                let environment = frame_environment(global_environment, current_frame);
+                let innermost = environment.loops.last()
+                    .ok_or_else(|| anyhow!("Cannot use `continue` outside of a loop"))?;
+                let pending = environment.operands - innermost.operands;
+                let condition = innermost.condition;
+                (0..pending).for_each(|_| active_buffer.emit(OpCode::Drop));
+                active_buffer.emit(OpCode::Jump { label: condition });
+            }
+
+            AST::Array { size, value, .. } => {
                 match value.deref() {
-                    AST::Boolean(_) | AST::Integer(_) | AST::Null |
-                    AST::AccessVariable { name:_ } | AST::AccessField { object:_, field:_ } => {
-                        size.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                        value.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
+                    AST::Boolean(_) | AST::Integer(_) | AST::String(_) | AST::Null |
+                    AST::AccessVariable { .. } | AST::AccessField { .. } => {
+                        compile_operands(vec![size.deref(), value.deref()],
+                                         program, active_buffer, global_environment, current_frame)?;
                         active_buffer.emit(OpCode::Array);
                         active_buffer.emit_unless(OpCode::Drop, keep_result);
                     },
@@ -424,9 +584,8 @@ impl Compiled for AST {
                         //   ::array[::i] <- eval VALUE;
                         //   ::i <- ::i + 1;
                         // end;
-                        let loop_de_loop = AST::loop_de_loop(
-                            comparison,
-                            AST::block(vec![set_array, increment_i]));
+                        let condition = comparison;
+                        let body = AST::block(vec![set_array, increment_i]);
 
                         // ::array
                         let array = AST::access_variable(array_id);
@@ -443,15 +602,15 @@ impl Compiled for AST {
                         size_definition.compile_into(program, active_buffer, global_environment, current_frame, false)?;
                         array_definition.compile_into(program, active_buffer, global_environment, current_frame, false)?;
                         i_definition.compile_into(program, active_buffer, global_environment, current_frame, false)?;
-                        loop_de_loop.compile_into(program, active_buffer, global_environment, current_frame, false)?;
+                        compile_loop(&condition, &body, false, program, active_buffer, global_environment, current_frame, false)?;
                         array.compile_into(program, active_buffer, global_environment, current_frame, keep_result)?;
                     }
                 }
             }
 
-            AST::AccessArray { array, index } => {
-                (**array).compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                (**index).compile_into(program, active_buffer, global_environment, current_frame, true)?;
+            AST::AccessArray { array, index, .. } => {
+                compile_operands(vec![array.deref(), index.deref()],
+                                 program, active_buffer, global_environment, current_frame)?;
 
                 let name = program.constant_pool.register(ProgramObject::String("get".to_string()));
 
@@ -459,10 +618,9 @@ impl Compiled for AST {
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::AssignArray { array, index, value } => {
-                (**array).compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                (**index).compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                (**value).compile_into(program, active_buffer, global_environment, current_frame, true)?;
+            AST::AssignArray { array, index, value, .. } => {
+                compile_operands(vec![array.deref(), index.deref(), value.deref()],
+                                 program, active_buffer, global_environment, current_frame)?;
 
                 let name = program.constant_pool.register(ProgramObject::String("set".to_string()));
 
@@ -470,20 +628,19 @@ impl Compiled for AST {
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::Print { format, arguments } => {
+            AST::Print { format, arguments, .. } => {
                 let format: ConstantPoolIndex =
                     program.constant_pool.register(ProgramObject::String(format.to_string()));
 
-                for argument in arguments.iter() {
-                    argument.compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                }
+                compile_operands(arguments.iter().map(Box::deref),
+                                 program, active_buffer, global_environment, current_frame)?;
 
                 let arguments = Arity::from_usize(arguments.len());
                 active_buffer.emit(OpCode::Print { format, arguments });
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::Function { name: Identifier(name), parameters, body } => {
+            AST::Function { name: Identifier(name), parameters, body, .. } => {
                 // let end_label = program.labels.generate_name(format!("λ:{}", name))?;
                 // let end_label_index =
                 //     program.constant_pool.register(ProgramObject::from_str(&end_label));
@@ -495,16 +652,18 @@ impl Compiled for AST {
 
                 let mut child_environment = Environment::new();
                 for parameter in parameters.into_iter() { // TODO Environment::from
-                    child_environment.register_local(parameter.as_str());
+                    let index = child_environment.register_local(parameter.as_str());
+                    child_environment.mark_live(index, parameter.as_str(), Address::from_usize(0));
                 }
                 let mut child_frame = &mut Frame::Local(child_environment);
 
                 (**body).compile_into(program, &mut function_buffer, global_environment, &mut child_frame, true)?;
 
-                let locals_in_frame = match child_frame {
-                    Frame::Local(child_environment) => child_environment.count_locals(),
+                let child_environment = match child_frame {
+                    Frame::Local(child_environment) => child_environment,
                     Frame::Top => unreachable!(),
                 };
+                let locals_in_frame = child_environment.count_locals();
 
                 function_buffer.emit(OpCode::Return);
 
@@ -512,6 +671,7 @@ impl Compiled for AST {
                 //program.labels.set(end_label, program.code.current_address())?;
 
                 let (start_address, function_length) = program.completed_code.extend(function_buffer);
+                let local_names = child_environment.local_names(start_address, function_length);
 
                 // XXX finish slab
 
@@ -526,35 +686,70 @@ impl Compiled for AST {
                 };
 
                 let constant = program.constant_pool.register(method);
+                program.record_local_names(constant, local_names);
                 program.globals.register(constant)?;
             }
 
-            AST::CallFunction { name: Identifier(name), arguments } => {
+            AST::CallFunction { name: Identifier(name), arguments, .. } if is_local(name, global_environment, current_frame) => {
+                // A local variable holding a closure.
+                let call = AST::CallMethod {
+                    object: Box::new(AST::access_variable(Identifier::from(name.as_str()))),
+                    name: Identifier::from("call"),
+                    arguments: arguments.clone(),
+                    span: None,
+                };
+                call.compile_into(program, active_buffer, global_environment, current_frame, keep_result)?;
+            }
+
+            AST::CallFunction { name: Identifier(name), arguments, .. } => {
                 let index = program.constant_pool.register(ProgramObject::String(name.to_string()));
-                for argument in arguments.iter() {
-                    argument.compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                }
+                compile_operands(arguments.iter().map(Box::deref),
+                                 program, active_buffer, global_environment, current_frame)?;
                 let arity = Arity::from_usize(arguments.len());
                 active_buffer.emit(OpCode::CallFunction { name: index, arguments: arity });
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::Object { extends, members } => {
+            AST::Lambda { parameters, body, .. } => {
+                let captured: Vec<String> = free_variables(parameters, body).into_iter()
+                    .filter(|name| is_local(name, global_environment, current_frame))
+                    .collect();
+                for name in captured.iter() {
+                    let environment = frame_environment(global_environment, current_frame);
+                    let index = environment.register_local(name);
+                    bail_if!(!environment.capture_from(index),
+                             "Cannot assign to `{}`: a function captures the value of a variable, not the variable itself", name);
+                }
+                let function = compile_function_definition("λ", false, parameters, &captured, body.deref(),
+                                                           program, global_environment, current_frame)?;
+                for name in captured.iter() {
+                    AST::access_variable(Identifier::from(name.as_str()))
+                        .compile_into(program, active_buffer, global_environment, current_frame, true)?;
+                }
+                active_buffer.emit(OpCode::Closure { function, captured: Arity::from_usize(captured.len()) });
+                active_buffer.emit_unless(OpCode::Drop, keep_result);
+            }
+
+            AST::Object { extends, members, .. } => {
                 (**extends).compile_into(program, active_buffer, global_environment, current_frame, true)?;
+                let operands = frame_environment(global_environment, current_frame).operands;
+                frame_environment(global_environment, current_frame).operands += 1;
 
-                let slots: Result<Vec<ConstantPoolIndex>> = members.iter().map(|m| m.deref()).map(|m| match m {
-                    AST::Function { name, parameters, body } => {
-                        compile_function_definition(name.as_str(), true, parameters, body.deref(),
+                let slots: Result<Vec<ConstantPoolIndex>> = members.iter().map(|m| match m.deref() {
+                    AST::Function { name, parameters, body, .. } => {
+                        compile_function_definition(name.as_str(), true, parameters, &[], body.deref(),
                                                     program, global_environment, current_frame)
 
                     }
-                    AST::Variable { name: Identifier(name), value } => {
+                    AST::Variable { name: Identifier(name), value, .. } => {
                         (*value).compile_into(program, active_buffer, global_environment, current_frame, true)?;
+                        frame_environment(global_environment, current_frame).operands += 1;
                         let index = program.constant_pool.register(ProgramObject::from_str(name));
                         Ok(program.constant_pool.register(ProgramObject::slot_from_index(index)))
                     },
                     _ => panic!("Object definition: cannot define a member from {:?}", m)
                 }).collect();
+                frame_environment(global_environment, current_frame).operands = operands;
 
                 let class = ProgramObject::Class(slots?);
                 let class_index = program.constant_pool.register(class);
@@ -575,33 +770,32 @@ impl Compiled for AST {
                     child.deref().compile_into(program, active_buffer, global_environment, current_frame, last && keep_result)?;
                 }
 
+                let end = active_buffer.upcoming_address();
                 match current_frame {
-                    Frame::Local(environment) => environment.leave_scope(),
-                    Frame::Top => global_environment.leave_scope(),
+                    Frame::Local(environment) => environment.leave_scope_at(end),
+                    Frame::Top => global_environment.leave_scope_at(end),
                 }
             }
 
-            AST::AccessField { object, field: Identifier(name) } => {
+            AST::AccessField { object, field: Identifier(name), .. } => {
                 object.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
                 let index = program.constant_pool.register(ProgramObject::from_str(name));
                 active_buffer.emit(OpCode::GetField { name: index });
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::AssignField { object, field: Identifier(name), value } => {
-                object.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                value.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
+            AST::AssignField { object, field: Identifier(name), value, .. } => {
+                compile_operands(vec![object.deref(), value.deref()],
+                                 program, active_buffer, global_environment, current_frame)?;
                 let index = program.constant_pool.register(ProgramObject::from_str(name));
                 active_buffer.emit(OpCode::SetField { name: index });
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
             }
 
-            AST::CallMethod { object, name: Identifier(name), arguments } => {
+            AST::CallMethod { object, name: Identifier(name), arguments, .. } => {
                 let index = program.constant_pool.register(ProgramObject::from_str(name));
-                object.deref().compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                for argument in arguments.iter() {
-                    argument.compile_into(program, active_buffer, global_environment, current_frame, true)?;
-                }
+                compile_operands(std::iter::once(object.deref()).chain(arguments.iter().map(Box::deref)),
+                                 program, active_buffer, global_environment, current_frame)?;
                 let arity = Arity::from_usize(arguments.len() + 1);
                 active_buffer.emit(OpCode::CallMethod { name: index, arguments: arity });
                 active_buffer.emit_unless(OpCode::Drop, keep_result);
@@ -633,6 +827,8 @@ impl Compiled for AST {
                 };
 
                 let function_index = program.constant_pool.register(method);
+                let names = global_environment.local_names(start_address, function_length);
+                program.record_local_names(function_index, names);
                 program.entry.set(function_index);
             }
         };
@@ -644,6 +840,7 @@ impl Compiled for AST {
 fn compile_function_definition(name: &str,
                                receiver: bool,
                                parameters: &Vec<Identifier>,
+                               captured: &[String],
                                body: &AST,
                                program: &mut ProgramGenerator,
                                global_environment: &mut Environment,
@@ -660,25 +857,33 @@ fn compile_function_definition(name: &str,
 
     let mut child_environment = Environment::new();
     if receiver {
-        child_environment.register_local("this");
+        let index = child_environment.register_local("this");
+        child_environment.mark_live(index, "this", Address::from_usize(0));
     }
     for parameter in parameters.into_iter() { // TODO Environment::from
-        child_environment.register_local(parameter.as_str());
+        let index = child_environment.register_local(parameter.as_str());
+        child_environment.mark_live(index, parameter.as_str(), Address::from_usize(0));
+    }
+    for name in captured {
+        let index = child_environment.register_captured(name);
+        child_environment.mark_live(index, name, Address::from_usize(0));
     }
     let mut child_frame = &mut Frame::Local(child_environment);
 
     body.compile_into(program, &mut function_buffer, global_environment, &mut child_frame, true)?;
 
-    let locals_in_frame = match child_frame {
-        Frame::Local(child_environment) => child_environment.count_locals(),
+    let child_environment = match child_frame {
+        Frame::Local(child_environment) => child_environment,
         Frame::Top => unreachable!(),
     };
+    let locals_in_frame = child_environment.count_locals();
     //child_environment.remove_frame();
 
     function_buffer.emit(OpCode::Return);
     // function_buffer.emit(OpCode::Label { name: end_label_index });
 
     let (start_address, length) = program.completed_code.extend(function_buffer);
+    let local_names = child_environment.local_names(start_address, length);
 
     //program.labels.set(end_label, program.code.current_address())?;
 
@@ -692,5 +897,186 @@ fn compile_function_definition(name: &str,
         code: AddressRange::new(start_address, length),
     };
 
-    Ok(program.constant_pool.register(method))
+    let method_index = program.constant_pool.register(method);
+    program.record_local_names(method_index, local_names);
+    Ok(method_index)
+}
+
+// The environment of the function whose code is being generated.
+fn frame_environment<'a>(global_environment: &'a mut Environment, current_frame: &'a mut Frame) -> &'a mut Environment {
+    match current_frame {
+        Frame::Local(environment) => environment,
+        Frame::Top => global_environment,
+    }
+}
+
+// Compiles expressions whose values stay on the operand stack until the instruction that consumes
+// them, counting them so that `break` and `continue` can drop them.
+fn compile_operands<'a, I>(expressions: I,
+                           program: &mut ProgramGenerator,
+                           active_buffer: &mut Code,
+                           global_environment: &mut Environment,
+                           current_frame: &mut Frame) -> Result<()> where I: IntoIterator<Item=&'a AST> {
+    let operands = frame_environment(global_environment, current_frame).operands;
+    for expression in expressions {
+        expression.compile_into(program, active_buffer, global_environment, current_frame, true)?;
+        frame_environment(global_environment, current_frame).operands += 1;
+    }
+    frame_environment(global_environment, current_frame).operands = operands;
+    Ok(())
+}
+
+// Drops the operands that are still pending below the result on top of the stack, going through a
+// hidden local because there is no instruction to swap operands.
+fn drop_operands_below_result(pending: usize,
+                              active_buffer: &mut Code,
+                              global_environment: &mut Environment,
+                              current_frame: &mut Frame) -> Result<()> {
+    if pending == 0 {
+        return Ok(())
+    }
+    let unique_number = global_environment.generate_unique_number();
+    let index = frame_environment(global_environment, current_frame)
+        .register_new_local(&format!("::result_{}", unique_number))
+        .map_err(|error| anyhow!("Cannot register a local for the result: {}", error))?;
+    active_buffer.emit(OpCode::SetLocal { index });
+    active_buffer.emit(OpCode::Drop);
+    (0..pending).for_each(|_| active_buffer.emit(OpCode::Drop));
+    active_buffer.emit(OpCode::GetLocal { index });
+    Ok(())
+}
+
+// Loops generated by the compiler itself are not `breakable`, so that `break` and `continue` in
+// code they wrap refer to a loop in the source.
+fn compile_loop(condition: &AST,
+                body: &AST,
+                breakable: bool,
+                program: &mut ProgramGenerator,
+                active_buffer: &mut Code,
+                global_environment: &mut Environment,
+                current_frame: &mut Frame,
+                keep_result: bool) -> Result<()> {
+    let label_generator = program.labels.create_group();
+    let body_label = label_generator.generate_name("loop:body")?;
+    let condition_label = label_generator.generate_name("loop:condition")?;
+    let end_label = label_generator.generate_name("loop:end")?;
+
+    let body_label_index =
+        program.constant_pool.register(ProgramObject::from_str(&body_label));
+    let condition_label_index =
+        program.constant_pool.register(ProgramObject::from_str(&condition_label));
+
+    if breakable {
+        let environment = frame_environment(global_environment, current_frame);
+        let labels = LoopLabels {
+            condition: condition_label_index, end: end_label,
+            operands: environment.operands, keep_result, broken: false,
+        };
+        environment.loops.push(labels);
+    }
+
+    active_buffer.emit(OpCode::Jump { label: condition_label_index });
+    active_buffer.emit(OpCode::Label { name: body_label_index });
+    //program.labels.set(body_label, program.code.current_address())?;
+    body.compile_into(program, active_buffer, global_environment, current_frame, false)?;
+    active_buffer.emit(OpCode::Label { name: condition_label_index });
+    //program.labels.set(condition_label, program.code.current_address())?;
+    condition.compile_into(program, active_buffer, global_environment, current_frame, true)?;
+    active_buffer.emit(OpCode::Branch { label: body_label_index });
+
+    if keep_result {
+        let constant = ProgramObject::Null;
+        let index = program.constant_pool.register(constant);
+        active_buffer.emit(OpCode::Literal { index });
+    }
+
+    if breakable {
+        let labels = frame_environment(global_environment, current_frame).loops.pop()
+            .expect("Cannot leave loop: the loop stack is empty");
+        if labels.broken {
+            let end_label_index = program.constant_pool.register(ProgramObject::from_str(&labels.end));
+            active_buffer.emit(OpCode::Label { name: end_label_index });
+        }
+    }
+    Ok(())
+}
+
+fn is_local(name: &str, global_environment: &Environment, current_frame: &Frame) -> bool {
+    match current_frame {
+        Frame::Local(environment) => environment.has_local(name),
+        Frame::Top => !global_environment.in_outermost_scope() && global_environment.has_local(name),
+    }
+}
+
+// Variables a lambda uses but does not define, in order of first use, including those used by
+// lambdas nested in it. Object methods and named functions cannot see locals, so they are skipped.
+fn free_variables(parameters: &[Identifier], body: &AST) -> Vec<String> {
+    // A name is only defined by a `let` that comes before the use, in the same block or one
+    // enclosing it, so `let x = x + 1` still uses the `x` from outside.
+    fn use_name(name: &str, used: &mut Vec<String>, scopes: &[HashSet<String>]) {
+        let defined = scopes.iter().any(|scope| scope.contains(name));
+        if !defined && !used.iter().any(|used| used == name) {
+            used.push(name.to_owned())
+        }
+    }
+    fn visit(ast: &AST, used: &mut Vec<String>, scopes: &mut Vec<HashSet<String>>) {
+        match ast {
+            AST::Integer(_) | AST::Boolean(_) | AST::String(_) | AST::Null | AST::Function { .. } | AST::Continue { .. } => {}
+            AST::Variable { name, value, .. } => {
+                visit(value, used, scopes);
+                scopes.last_mut().unwrap().insert(name.as_str().to_owned());
+            }
+            AST::AccessVariable { name, .. } => use_name(name.as_str(), used, scopes),
+            AST::AssignVariable { name, value, .. } => {
+                use_name(name.as_str(), used, scopes);
+                visit(value, used, scopes);
+            }
+            AST::CallFunction { name, arguments, .. } => {
+                use_name(name.as_str(), used, scopes);
+                arguments.iter().for_each(|argument| visit(argument, used, scopes));
+            }
+            AST::Lambda { parameters, body, .. } => {
+                free_variables(parameters, body).iter().for_each(|name| use_name(name, used, scopes));
+            }
+            AST::Object { extends, members, .. } => {
+                visit(extends, used, scopes);
+                for member in members.iter() {
+                    if let AST::Variable { value, .. } = member.deref() {
+                        visit(value, used, scopes);
+                    }
+                }
+            }
+            AST::Array { size, value, .. } => { visit(size, used, scopes); visit(value, used, scopes); }
+            AST::AccessField { object, .. } => visit(object, used, scopes),
+            AST::AccessArray { array, index, .. } => { visit(array, used, scopes); visit(index, used, scopes); }
+            AST::AssignField { object, value, .. } => { visit(object, used, scopes); visit(value, used, scopes); }
+            AST::AssignArray { array, index, value, .. } => {
+                visit(array, used, scopes);
+                visit(index, used, scopes);
+                visit(value, used, scopes);
+            }
+            AST::CallMethod { object, arguments, .. } => {
+                visit(object, used, scopes);
+                arguments.iter().for_each(|argument| visit(argument, used, scopes));
+            }
+            AST::Top(children) | AST::Block(children) => {
+                scopes.push(HashSet::new());
+                children.iter().for_each(|child| visit(child, used, scopes));
+                scopes.pop();
+            }
+            AST::Loop { condition, body, .. } => { visit(condition, used, scopes); visit(body, used, scopes); }
+            AST::Break { value, .. } | AST::Return { value, .. } => visit(value, used, scopes),
+            AST::Conditional { condition, consequent, alternative, .. } => {
+                visit(condition, used, scopes);
+                visit(consequent, used, scopes);
+                visit(alternative, used, scopes);
+            }
+            AST::Print { arguments, .. } => arguments.iter().for_each(|argument| visit(argument, used, scopes)),
+        }
+    }
+
+    let mut used = Vec::new();
+    let mut scopes = vec![parameters.iter().map(|parameter| parameter.as_str().to_owned()).collect()];
+    visit(body, &mut used, &mut scopes);
+    used
 }
@@ -245,6 +245,11 @@ impl UglyPrint for OpCode {
             OpCode::Drop => {
                 write_string!(sink, indent, "drop");
             },
+            OpCode::Closure { function, captured } => {
+                write_string!(sink, indent, "closure ");
+                function.pretty_print_no_indent(sink);
+                captured.pretty_print_indent(sink, 1);
+            },
         }
     }
 }
@@ -0,0 +1,162 @@
+use std::io::Write;
+
+use anyhow::*;
+
+use crate::bytecode::program::{Address, AddressRange, Code, ConstantPoolIndex, LocalFrameIndex};
+use crate::bytecode::serializable::{self, Serializable, Input};
+use crate::parser::Span;
+use crate::parser::diagnostics::SourceFile;
+
+// Bits of the flags byte in the header, or of the one that follows the entry point in headerless
+// files. Headerless programs without debug info end at the entry point, as in the Feeny format.
+pub const FLAG_DEBUG_LINES: u8 = 0x01;
+pub const FLAG_DEBUG_LOCALS: u8 = 0x02;
+
+// Maps ranges of instructions to the source they were compiled from. Entries are sorted by
+// address and do not overlap. Local variable names are kept per method, each with the range of
+// instructions where the name is in scope.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct DebugInfo { pub files: Vec<String>, pub lines: Vec<LineEntry>, pub locals: Vec<MethodLocals> }
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct LineEntry { pub code: AddressRange, pub file: usize, pub line: usize, pub column: usize, pub span: Span }
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct MethodLocals { pub method: ConstantPoolIndex, pub names: Vec<LocalName> }
+
+// The same frame slot can hold different variables in sibling scopes, so names are only valid
+// within their live range.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct LocalName { pub index: LocalFrameIndex, pub name: String, pub live: AddressRange }
+
+// Attached as context to runtime errors raised by instructions that have an entry in the table.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct SourceLocation { pub file: String, pub line: usize, pub column: usize, pub span: Span }
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl DebugInfo {
+    // Consecutive instructions compiled from the same AST node share one entry.
+    pub fn from_spans(code: &Code, source: &SourceFile) -> Self {
+        let mut lines: Vec<LineEntry> = Vec::new();
+        for (address, span) in code.spans() {
+            if let Some(last) = lines.last_mut() {
+                let next = last.code.start().value_usize() + last.code.length();
+                if last.span == span && next == address.value_usize() {
+                    last.code = AddressRange::new(*last.code.start(), last.code.length() + 1);
+                    continue
+                }
+            }
+            let (line, column) = source.location(span.start);
+            lines.push(LineEntry { code: AddressRange::new(address, 1), file: 0, line, column, span });
+        }
+        DebugInfo { files: vec![source.name.clone()], lines, locals: Vec::new() }
+    }
+
+    pub fn with_locals(self, locals: Vec<MethodLocals>) -> Self {
+        DebugInfo { locals, ..self }
+    }
+
+    pub fn flags(&self) -> u8 {
+        FLAG_DEBUG_LINES | if self.locals.is_empty() { 0 } else { FLAG_DEBUG_LOCALS }
+    }
+
+    pub fn locate(&self, address: Address) -> Option<SourceLocation> {
+        self.lines.iter()
+            .find(|entry| entry.code.contains(&address))
+            .map(|entry| SourceLocation {
+                file: self.files.get(entry.file).cloned().unwrap_or_else(|| "?".to_owned()),
+                line: entry.line,
+                column: entry.column,
+                span: entry.span,
+            })
+    }
+
+    // Every local variable that is in scope at the address.
+    pub fn locals_at(&self, address: Address) -> impl Iterator<Item=&LocalName> {
+        self.locals.iter()
+            .flat_map(|method| method.names.iter())
+            .filter(move |local| local.live.contains(&address))
+    }
+
+    pub fn local_name(&self, address: Address, index: &LocalFrameIndex) -> Option<&str> {
+        self.locals_at(address)
+            .find(|local| local.index == *index)
+            .map(|local| local.name.as_str())
+    }
+}
+
+// Not Serializable: the sections that are present depend on the flags byte, which the program
+// writes and reads before the debug info.
+impl DebugInfo {
+    pub fn serialize<W: Write>(&self, sink: &mut W) -> Result<()> {
+        serializable::write_usize_as_u16(sink, self.files.len())?;
+        for file in self.files.iter() {
+            serializable::write_utf8(sink, file)?;
+        }
+        serializable::write_usize_as_u32(sink, self.lines.len())?;
+        for entry in self.lines.iter() {
+            serializable::write_usize_as_u32(sink, entry.code.start().value_usize())?;
+            serializable::write_usize_as_u32(sink, entry.code.length())?;
+            serializable::write_usize_as_u16(sink, entry.file)?;
+            serializable::write_usize_as_u32(sink, entry.line)?;
+            serializable::write_usize_as_u32(sink, entry.column)?;
+            serializable::write_usize_as_u32(sink, entry.span.start)?;
+            serializable::write_usize_as_u32(sink, entry.span.end)?;
+        }
+        if self.flags() & FLAG_DEBUG_LOCALS != 0 {
+            serializable::write_usize_as_u16(sink, self.locals.len())?;
+            for method in self.locals.iter() {
+                method.method.serialize(sink)?;
+                serializable::write_usize_as_u16(sink, method.names.len())?;
+                for local in method.names.iter() {
+                    local.index.serialize(sink)?;
+                    serializable::write_utf8(sink, &local.name)?;
+                    serializable::write_usize_as_u32(sink, local.live.start().value_usize())?;
+                    serializable::write_usize_as_u32(sink, local.live.length())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes<R: Input>(input: &mut R, flags: u8) -> Result<Self> {
+        let files = (0..serializable::read_u16_as_usize(input)?)
+            .map(|_| serializable::read_utf8(input))
+            .collect::<Result<Vec<String>>>()?;
+        let lines = (0..serializable::read_u32_as_usize(input)?)
+            .map(|_| {
+                let start = serializable::read_u32_as_usize(input)?;
+                let length = serializable::read_u32_as_usize(input)?;
+                let file = serializable::read_u16_as_usize(input)?;
+                let line = serializable::read_u32_as_usize(input)?;
+                let column = serializable::read_u32_as_usize(input)?;
+                let span = Span::new(serializable::read_u32_as_usize(input)?,
+                                     serializable::read_u32_as_usize(input)?);
+                Ok(LineEntry { code: AddressRange::from(start, length), file, line, column, span })
+            })
+            .collect::<Result<Vec<LineEntry>>>()?;
+        let locals = if flags & FLAG_DEBUG_LOCALS == 0 { Vec::new() } else {
+            (0..serializable::read_u16_as_usize(input)?)
+                .map(|_| {
+                    let method = ConstantPoolIndex::from_bytes(input)?;
+                    let names = (0..serializable::read_u16_as_usize(input)?)
+                        .map(|_| {
+                            let index = LocalFrameIndex::from_bytes(input)?;
+                            let name = serializable::read_utf8(input)?;
+                            let start = serializable::read_u32_as_usize(input)?;
+                            let length = serializable::read_u32_as_usize(input)?;
+                            Ok(LocalName { index, name, live: AddressRange::from(start, length) })
+                        })
+                        .collect::<Result<Vec<LocalName>>>()?;
+                    Ok(MethodLocals { method, names })
+                })
+                .collect::<Result<Vec<MethodLocals>>>()?
+        };
+        Ok(DebugInfo { files, lines, locals })
+    }
+}
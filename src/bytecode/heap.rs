@@ -4,35 +4,106 @@ use indexmap::IndexMap;
 use crate::bytecode::state::OperandStack;
 use crate::bytecode::program::{ProgramObject, ConstantPoolIndex, AddressRange, Arity, Size};
 
+use crate::bytecode::heap_log::{HeapLog, HeapLogFormat, HeapEvent, AllocationSite};
+
+use std::collections::HashSet;
 use std::path::PathBuf;
-use std::fs::{File, create_dir_all};
-use std::time::SystemTime;
-use std::io::Write;
+use std::time::Instant;
 use std::mem::size_of;
 
-macro_rules! heap_log {
-    (START -> $file:expr) => {
-        if let Some(file) = &mut $file {
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
-            write!(file, "{},S,0\n", timestamp).unwrap();
-        }
-    };
-    (ALLOCATE -> $file:expr, $memory:expr) => {
-        if let Some(file) = &mut $file {
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
-            write!(file, "{},A,{}\n", timestamp, $memory).unwrap();
+// The heap is not collected until it grows past this many bytes, and the threshold never drops
+// below it after a collection either.
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum GarbageCollector {
+    MarkAndSweep,
+    Copying,
+    Generational,
+    None,
+}
+
+impl std::str::FromStr for GarbageCollector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mark-sweep" | "mark-and-sweep" | "ms"  => Ok(Self::MarkAndSweep),
+            "copying" | "copy" | "semispace"        => Ok(Self::Copying),
+            "generational" | "gen"                  => Ok(Self::Generational),
+            "none" | "off"                          => Ok(Self::None),
+            collector => Err(anyhow::anyhow!("Unknown garbage collector: {}", collector))
         }
-    };
-    (GC -> $file:expr, $memory:expr) => {
-        if let Some(file) = &mut $file {
-            let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
-            write!(file, "{},G,{}\n", timestamp, $memory).unwrap();
+    }
+}
+
+// A minor collection only traces the nursery, starting from the roots the state considers
+// remembered and from the old objects in the remembered set. A major collection traces everything.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Collection {
+    Minor,
+    Major,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct GenerationConfig {
+    pub nursery_size: usize,  /* in KB */
+    pub old_size: usize,      /* in MB */
+    pub promotion_age: usize, /* in minor collections survived */
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig { nursery_size: 256, old_size: 1, promotion_age: 2 }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct HeapConfig {
+    pub size: usize, /* in MB */
+    pub log: Option<PathBuf>,
+    pub log_format: HeapLogFormat,
+    pub collector: GarbageCollector,
+    pub generations: GenerationConfig,
+    pub stress: bool,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        HeapConfig {
+            size: 0,
+            log: None,
+            log_format: HeapLogFormat::Csv,
+            collector: GarbageCollector::MarkAndSweep,
+            generations: GenerationConfig::default(),
+            stress: false,
         }
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Generation {
+    Nursery { survived: usize },
+    Old,
+}
+
 #[derive(Debug)]
-pub struct Heap{ max_size: usize, size: usize, log: Option<File>, memory: Vec<HeapObject> }
+pub struct Heap {
+    max_size: usize,
+    size: usize,
+    young: usize,
+    threshold: usize,
+    minimum_threshold: usize,
+    nursery_size: usize,
+    promotion_age: usize,
+    collector: GarbageCollector,
+    stress: bool,
+    log: Option<HeapLog>,
+    memory: Vec<Option<HeapObject>>,
+    generations: Vec<Generation>,
+    remembered: HashSet<HeapIndex>,
+    free: Vec<HeapIndex>,
+}
 
 impl Eq for Heap {}
 impl PartialEq for Heap {
@@ -45,55 +116,395 @@ impl Heap {
     pub fn set_size(&mut self, size: usize /* in MB */) {
         self.max_size = size * 1024 * 1024 /* in B */
     }
-    pub fn set_log(&mut self, path: PathBuf) {
-
-        let mut dir = path.clone();
-        dir.pop();
-        create_dir_all(dir).unwrap();
-
-        let mut file = File::create(path).unwrap();
-        write!(file, "timestamp,event,heap\n").unwrap();
-
-        heap_log!(START -> Some(&mut file));
-        self.log = Some(file)
+    pub fn set_log(&mut self, path: PathBuf, format: HeapLogFormat) {
+        self.log = Some(HeapLog::create(path, format).unwrap())
+    }
+    pub fn is_logging(&self) -> bool {
+        self.log.is_some()
+    }
+    pub fn set_collector(&mut self, collector: GarbageCollector) {
+        self.collector = collector
+    }
+    // In stress mode every allocation is preceded by a full collection and a heap integrity check.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress
+    }
+    pub fn is_stressed(&self) -> bool {
+        self.stress
+    }
+    pub fn set_generations(&mut self, config: GenerationConfig) {
+        self.nursery_size = config.nursery_size * 1024 /* in B */;
+        self.minimum_threshold = config.old_size * 1024 * 1024 /* in B */;
+        self.threshold = self.minimum_threshold;
+        self.promotion_age = config.promotion_age;
     }
     pub fn new() -> Self {
-        Heap { max_size: 0, log: None, memory: Vec::new(), size: 0 }
+        let config = GenerationConfig::default();
+        Heap {
+            max_size: 0,
+            size: 0,
+            young: 0,
+            threshold: INITIAL_GC_THRESHOLD,
+            minimum_threshold: INITIAL_GC_THRESHOLD,
+            nursery_size: config.nursery_size * 1024,
+            promotion_age: config.promotion_age,
+            collector: GarbageCollector::MarkAndSweep,
+            stress: false,
+            log: None,
+            memory: Vec::new(),
+            generations: Vec::new(),
+            remembered: HashSet::new(),
+            free: Vec::new(),
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn configure(&mut self, config: HeapConfig) {
+        self.set_size(config.size);
+        self.set_collector(config.collector);
+        self.set_generations(config.generations);
+        self.set_stress(config.stress);
+        if let Some(log) = config.log {
+            self.set_log(log, config.log_format);
+        }
     }
     pub fn allocate(&mut self, object: HeapObject) -> HeapIndex {
+        self.allocate_at(object, &AllocationSite::default())
+    }
+    pub fn allocate_at(&mut self, object: HeapObject, site: &AllocationSite) -> HeapIndex {
         self.size += object.size();
-        heap_log!(ALLOCATE -> self.log, self.size);
-        let index = HeapIndex::from(self.memory.len());
-        self.memory.push(object);
-        index
+        self.young += object.size();
+        if let Some(log) = &mut self.log {
+            log.write(&HeapEvent::allocation(self.size, object.kind(), object.size(), site));
+        }
+        match self.free.pop() {
+            Some(index) => {
+                self.memory[index.as_usize()] = Some(object);
+                self.generations[index.as_usize()] = Generation::Nursery { survived: 0 };
+                index
+            }
+            None => {
+                let index = HeapIndex::from(self.memory.len());
+                self.memory.push(Some(object));
+                self.generations.push(Generation::Nursery { survived: 0 });
+                index
+            }
+        }
+    }
+    pub fn objects(&self) -> impl Iterator<Item=(HeapIndex, &HeapObject)> {
+        self.memory.iter().enumerate()
+            .filter_map(|(slot, object)| object.as_ref().map(|object| (HeapIndex::from(slot), object)))
+    }
+    pub fn is_young(&self, pointer: &Pointer) -> bool {
+        match pointer {
+            Pointer::Reference(index) => self.is_young_index(index),
+            _ => false,
+        }
+    }
+    fn is_young_index(&self, index: &HeapIndex) -> bool {
+        matches!(self.generations.get(index.as_usize()), Some(Generation::Nursery { .. }))
+    }
+    // Must be called whenever a pointer is stored into a heap object, so that old objects pointing
+    // into the nursery are traced by minor collections.
+    pub fn write_barrier(&mut self, owner: &HeapIndex, value: &Pointer) {
+        if self.collector == GarbageCollector::Generational
+            && !self.is_young_index(owner) && self.is_young(value) {
+            self.remembered.insert(*owner);
+        }
     }
     pub fn dereference(&self, index: &HeapIndex) -> Result<&HeapObject> {
         self.memory.get(index.as_usize())
+            .and_then(|slot| slot.as_ref())
             .with_context(||
                 format!("Cannot dereference object from the heap at index: `{}`", index))
     }
     pub fn dereference_mut(&mut self, index: &HeapIndex) -> Result<&mut HeapObject> {
         self.memory.get_mut(index.as_usize())
+            .and_then(|slot| slot.as_mut())
             .with_context(||
                 format!("Cannot dereference object from the heap at index: `{}`", index))
     }
+    pub fn next_collection(&self, requested: usize) -> Option<Collection> {
+        if self.stress {
+            return Some(Collection::Major)
+        }
+        let over_limit = self.max_size != 0 && self.size + requested > self.max_size;
+        match self.collector {
+            GarbageCollector::Generational => {
+                let old = self.size - self.young;
+                if over_limit || old > self.threshold {
+                    Some(Collection::Major)
+                } else if self.young + requested > self.nursery_size {
+                    Some(Collection::Minor)
+                } else {
+                    None
+                }
+            }
+            _ if over_limit || self.size + requested > self.threshold => Some(Collection::Major),
+            _ => None,
+        }
+    }
+    pub fn ensure_capacity(&self, requested: usize) -> Result<()> {
+        if self.max_size != 0 && self.size + requested > self.max_size {
+            let collected = self.collector != GarbageCollector::None;
+            return Err(HeapExhausted { requested, limit: self.max_size, live: self.size, collected }.into())
+        }
+        Ok(())
+    }
+    // For minor collections the roots only need to include the remembered parts of the state, major
+    // collections need all of them.
+    pub fn collect_garbage<'a, I>(&mut self, roots: I, collection: Collection) where I: IntoIterator<Item=&'a mut Pointer> {
+        let start = Instant::now();
+        let (copied, reclaimed) = match (self.collector, collection) {
+            (GarbageCollector::None, _) => return,
+            (GarbageCollector::Generational, Collection::Minor) => {
+                let (promoted, reclaimed) = self.collect_nursery(roots);
+                self.log_collection(true, promoted, reclaimed, start);
+                return
+            }
+            (GarbageCollector::Generational, Collection::Major) => {
+                let result = self.mark_and_sweep(roots);
+                self.promote_all();
+                result
+            }
+            (GarbageCollector::MarkAndSweep, _) => self.mark_and_sweep(roots),
+            (GarbageCollector::Copying, _) => self.copy(roots),
+        };
+        self.threshold = std::cmp::max(self.minimum_threshold, 2 * self.size);
+        self.log_collection(false, copied, reclaimed, start);
+    }
+    fn log_collection(&mut self, minor: bool, copied: usize, reclaimed: usize, start: Instant) {
+        let pause = start.elapsed().as_nanos() as u64;
+        if let Some(log) = &mut self.log {
+            log.write(&HeapEvent::collection(minor, self.size, reclaimed, copied, pause));
+        }
+    }
+    pub fn verify<I>(&self, roots: I) -> Result<()> where I: IntoIterator<Item=HeapIndex> {
+        let mut visited = vec![false; self.memory.len()];
+        let mut worklist: Vec<(Option<HeapIndex>, HeapIndex)> =
+            roots.into_iter().map(|index| (None, index)).collect();
+        while let Some((referrer, index)) = worklist.pop() {
+            let object = match (self.dereference(&index), referrer) {
+                (Ok(object), _) => object,
+                (Err(_), None) =>
+                    bail!("Heap integrity violation: root points to dangling index `{}`", index),
+                (Err(_), Some(referrer)) =>
+                    bail!("Heap integrity violation: object at `{}` points to dangling index `{}`",
+                          referrer, index),
+            };
+            if visited[index.as_usize()] {
+                continue
+            }
+            visited[index.as_usize()] = true;
+            if let HeapObject::Object(instance) = object {
+                if let Pointer::Reference(parent) = &instance.parent {
+                    bail_if!(self.dereference(parent).is_err(),
+                             "Heap integrity violation: object at `{}` has dangling parent `{}`",
+                             index, parent);
+                }
+            }
+            worklist.extend(object.references().into_iter().map(|reference| (Some(index), reference)));
+        }
+        Ok(())
+    }
+    fn mark_and_sweep<'a, I>(&mut self, roots: I) -> (usize, usize) where I: IntoIterator<Item=&'a mut Pointer> {
+        let roots = roots.into_iter().flat_map(|pointer| pointer.as_heap_reference().copied());
+        let marked = self.mark(roots);
+        let reclaimed = self.sweep(&marked);
+        (0, reclaimed)
+    }
+    fn mark<I>(&self, roots: I) -> Vec<bool> where I: IntoIterator<Item=HeapIndex> {
+        let mut marked = vec![false; self.memory.len()];
+        let mut worklist: Vec<HeapIndex> = roots.into_iter().collect();
+        while let Some(index) = worklist.pop() {
+            let slot = index.as_usize();
+            if slot >= marked.len() || marked[slot] {
+                continue
+            }
+            marked[slot] = true;
+            if let Some(object) = &self.memory[slot] {
+                worklist.extend(object.references());
+            }
+        }
+        marked
+    }
+    fn sweep(&mut self, marked: &[bool]) -> usize {
+        let mut reclaimed = 0;
+        for (slot, live) in marked.iter().enumerate() {
+            if *live { continue }
+            if let Some(object) = self.memory[slot].take() {
+                reclaimed += object.size();
+                if let Generation::Nursery { .. } = self.generations[slot] {
+                    self.young -= object.size();
+                }
+                self.free.push(HeapIndex::from(slot));
+            }
+        }
+        self.size -= reclaimed;
+        reclaimed
+    }
+    // Traces only nursery objects: old objects are assumed live and the ones that may point into
+    // the nursery are in the remembered set. Survivors age and are promoted once they are old
+    // enough. Returns the promoted and the reclaimed bytes.
+    fn collect_nursery<'a, I>(&mut self, roots: I) -> (usize, usize) where I: IntoIterator<Item=&'a mut Pointer> {
+        let remembered: Vec<HeapIndex> = self.remembered.drain().collect();
+        let remembered_references: Vec<HeapIndex> = remembered.iter()
+            .flat_map(|index| self.memory[index.as_usize()].as_ref())
+            .flat_map(|object| object.references())
+            .collect();
+        let roots = roots.into_iter()
+            .flat_map(|pointer| pointer.as_heap_reference().copied())
+            .chain(remembered_references);
+
+        let mut marked = vec![false; self.memory.len()];
+        let mut worklist: Vec<HeapIndex> = roots.collect();
+        while let Some(index) = worklist.pop() {
+            let slot = index.as_usize();
+            if slot >= marked.len() || marked[slot] || !self.is_young_index(&index) {
+                continue
+            }
+            marked[slot] = true;
+            if let Some(object) = &self.memory[slot] {
+                worklist.extend(object.references());
+            }
+        }
+
+        let mut promoted = Vec::new();
+        let mut promoted_size = 0;
+        for (slot, &live) in marked.iter().enumerate() {
+            let survived = match (&self.generations[slot], &self.memory[slot]) {
+                (Generation::Nursery { survived }, Some(_)) => *survived + 1,
+                _ => continue,
+            };
+            if !live {
+                continue
+            }
+            if survived >= self.promotion_age {
+                let size = self.memory[slot].as_ref().unwrap().size();
+                self.generations[slot] = Generation::Old;
+                self.young -= size;
+                promoted_size += size;
+                promoted.push(HeapIndex::from(slot));
+            } else {
+                self.generations[slot] = Generation::Nursery { survived };
+            }
+        }
+        let reclaimed = self.sweep_nursery(&marked);
+
+        // Only old objects that still point into the nursery need to stay remembered.
+        self.remembered = remembered.into_iter().chain(promoted)
+            .filter(|index| {
+                self.memory[index.as_usize()].as_ref()
+                    .is_some_and(|object| object.references().iter().any(|r| self.is_young_index(r)))
+            })
+            .collect();
+        (promoted_size, reclaimed)
+    }
+    fn sweep_nursery(&mut self, marked: &[bool]) -> usize {
+        let nursery: Vec<bool> = (0..self.memory.len())
+            .map(|slot| marked[slot] || !self.is_young_index(&HeapIndex::from(slot)))
+            .collect();
+        self.sweep(&nursery)
+    }
+    fn promote_all(&mut self) {
+        for generation in self.generations.iter_mut() {
+            *generation = Generation::Old;
+        }
+        self.young = 0;
+        self.remembered.clear();
+    }
+    // Cheney-style semispace collection: live objects are evacuated into a fresh, compacted
+    // to-space in breadth-first order and every reference is rewritten to the new location.
+    fn copy<'a, I>(&mut self, roots: I) -> (usize, usize) where I: IntoIterator<Item=&'a mut Pointer> {
+        let mut from_space = std::mem::take(&mut self.memory);
+        let mut forwarding: Vec<Option<HeapIndex>> = vec![None; from_space.len()];
+        let mut to_space: Vec<Option<HeapObject>> = Vec::new();
+
+        for pointer in roots {
+            Heap::forward(pointer, &mut from_space, &mut to_space, &mut forwarding);
+        }
+
+        let mut scan = 0;
+        while scan < to_space.len() {
+            let mut object = to_space[scan].take().unwrap();
+            for pointer in object.pointers_mut() {
+                Heap::forward(pointer, &mut from_space, &mut to_space, &mut forwarding);
+            }
+            to_space[scan] = Some(object);
+            scan += 1;
+        }
+
+        let copied: usize = to_space.iter().flatten().map(|object| object.size()).sum();
+        let reclaimed = self.size - copied;
+
+        self.generations = vec![Generation::Nursery { survived: 0 }; to_space.len()];
+        self.memory = to_space;
+        self.free.clear();
+        self.size = copied;
+        self.young = copied;
+        (copied, reclaimed)
+    }
+    fn forward(pointer: &mut Pointer,
+               from_space: &mut [Option<HeapObject>],
+               to_space: &mut Vec<Option<HeapObject>>,
+               forwarding: &mut [Option<HeapIndex>]) {
+
+        let old = match pointer {
+            Pointer::Reference(index) => index.as_usize(),
+            _ => return,
+        };
+        if old >= forwarding.len() {
+            return
+        }
+        if forwarding[old].is_none() {
+            if let Some(object) = from_space[old].take() {
+                forwarding[old] = Some(HeapIndex::from(to_space.len()));
+                to_space.push(Some(object));
+            }
+        }
+        if let Some(new) = forwarding[old] {
+            *pointer = Pointer::Reference(new);
+        }
+    }
 }
 
 impl From<Vec<HeapObject>> for Heap {
     fn from(objects: Vec<HeapObject>) -> Self {
+        let size = objects.iter().map(|o| o.size()).sum();
         Heap {
-            size: objects.iter().map(|o| o.size()).sum(),
-            max_size: 0,
-            log: None,
-            memory: objects
+            size,
+            young: size,
+            generations: vec![Generation::Nursery { survived: 0 }; objects.len()],
+            memory: objects.into_iter().map(Some).collect(),
+            ..Heap::new()
         }
     }
 }
 
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct HeapExhausted { pub requested: usize, pub limit: usize, pub live: usize, pub collected: bool }
+
+impl std::fmt::Display for HeapExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.collected {
+            write!(f, "Heap exhausted: cannot allocate {} B, heap limit is {} B and {} B are still live \
+                       after garbage collection", self.requested, self.limit, self.live)
+        } else {
+            write!(f, "Heap exhausted: cannot allocate {} B, heap limit is {} B and {} B are in use \
+                       with garbage collection disabled", self.requested, self.limit, self.live)
+        }
+    }
+}
+
+impl std::error::Error for HeapExhausted {}
+
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum HeapObject {
     Array(ArrayInstance),
-    Object(ObjectInstance)
+    Object(ObjectInstance),
+    String(String),
+    Closure(ClosureInstance),
 }
 
 impl HeapObject {
@@ -104,13 +515,25 @@ impl HeapObject {
     pub fn as_object_instance(&self) -> Result<&ObjectInstance> {
         match self {
             HeapObject::Object(instance) => Ok(instance),
-            array => Err(anyhow!("Attempt to cast an array as an object instance `{}`.", array)),
+            object => Err(anyhow!("Attempt to cast the {} `{}` as an object instance.", object.kind(), object)),
         }
     }
     pub fn as_object_instance_mut(&mut self) -> Result<&mut ObjectInstance> {
         match self {
             HeapObject::Object(instance) => Ok(instance),
-            array => Err(anyhow!("Attempt to cast an array as an object instance `{}`.", array)),
+            object => Err(anyhow!("Attempt to cast the {} `{}` as an object instance.", object.kind(), object)),
+        }
+    }
+    pub fn as_array_instance(&self) -> Result<&ArrayInstance> {
+        match self {
+            HeapObject::Array(instance) => Ok(instance),
+            object => Err(anyhow!("Attempt to cast the {} `{}` as an array instance.", object.kind(), object)),
+        }
+    }
+    pub fn as_array_instance_mut(&mut self) -> Result<&mut ArrayInstance> {
+        match self {
+            HeapObject::Array(instance) => Ok(instance),
+            object => Err(anyhow!("Attempt to cast the {} `{}` as an array instance.", object.kind(), object)),
         }
     }
     #[allow(dead_code)]
@@ -121,6 +544,18 @@ impl HeapObject {
     pub fn empty_array() -> Self {
         HeapObject::Array(ArrayInstance::new())
     }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            HeapObject::String(string) => Some(string),
+            _ => None,
+        }
+    }
+    pub fn as_closure_instance(&self) -> Result<&ClosureInstance> {
+        match self {
+            HeapObject::Closure(instance) => Ok(instance),
+            object => Err(anyhow!("Attempt to cast the {} `{}` as a closure.", object.kind(), object)),
+        }
+    }
     pub fn from_pointers(v: Vec<Pointer>) -> Self {
         HeapObject::Array(ArrayInstance::from(v))
     }
@@ -132,6 +567,45 @@ impl HeapObject {
         match self {
             HeapObject::Array(array) => array.evaluate_as_string(heap),
             HeapObject::Object(object) => object.evaluate_as_string(heap),
+            HeapObject::String(string) => Ok(string.clone()),
+            HeapObject::Closure(_) => Ok("<function>".to_owned()),
+        }
+    }
+    pub fn references(&self) -> Vec<HeapIndex> {
+        match self {
+            HeapObject::Array(array) => {
+                array.iter().flat_map(|pointer| pointer.as_heap_reference()).copied().collect()
+            }
+            HeapObject::Object(object) => {
+                let parent = object.parent.as_heap_reference().copied();
+                let fields = object.fields.values()
+                    .flat_map(|pointer| pointer.as_heap_reference()).copied();
+                parent.into_iter().chain(fields).collect()
+            }
+            HeapObject::String(_) => Vec::new(),
+            HeapObject::Closure(closure) => {
+                closure.captured.iter().flat_map(|pointer| pointer.as_heap_reference()).copied().collect()
+            }
+        }
+    }
+    pub fn pointers_mut(&mut self) -> Vec<&mut Pointer> {
+        match self {
+            HeapObject::Array(array) => array.iter_mut().collect(),
+            HeapObject::Object(object) => {
+                let parent = std::iter::once(&mut object.parent);
+                let fields = object.fields.values_mut();
+                parent.chain(fields).collect()
+            }
+            HeapObject::String(_) => Vec::new(),
+            HeapObject::Closure(closure) => closure.captured.iter_mut().collect(),
+        }
+    }
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HeapObject::Array(_) => "array",
+            HeapObject::Object(_) => "object",
+            HeapObject::String(_) => "string",
+            HeapObject::Closure(_) => "closure",
         }
     }
     pub fn size(&self) -> usize {
@@ -154,6 +628,10 @@ impl HeapObject {
                     }).sum();
                 header + fields + methods
             }
+            HeapObject::String(string) => size_of::<String>() + string.len(),
+            HeapObject::Closure(closure) => {
+                size_of::<ClosureInstance>() + closure.captured.len() * size_of::<Pointer>()
+            }
         }
     }
 }
@@ -163,6 +641,8 @@ impl std::fmt::Display for HeapObject {
         match self {
             HeapObject::Array(array) => write!(f, "{}", array),
             HeapObject::Object(object) => write!(f, "{}", object),
+            HeapObject::String(string) => write!(f, "{:?}", string),
+            HeapObject::Closure(closure) => write!(f, "{}", closure),
         }
     }
 }
@@ -177,9 +657,12 @@ impl ArrayInstance {
         ArrayInstance(vec![])
     }
     #[allow(dead_code)]
-    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&Pointer> + 'a {
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a Pointer> + 'a {
         self.0.iter()
     }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut Pointer> {
+        self.0.iter_mut()
+    }
     #[allow(dead_code)]
     pub fn length(&self) -> usize {
         self.0.len()
@@ -292,6 +775,23 @@ impl std::fmt::Display for ObjectInstance {
     }
 }
 
+// A function value: the method to run, and the values of the enclosing function's locals it
+// refers to, copied when the closure was created.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ClosureInstance {
+    pub function: ConstantPoolIndex,
+    pub captured: Vec<Pointer>,
+}
+
+impl std::fmt::Display for ClosureInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "closure({}, [{}])", self.function, self.captured.iter()
+            .map(|pointer| format!("{}", pointer))
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Hash, Clone, Copy, Ord, PartialOrd)]
 pub struct HeapIndex(usize);
 
@@ -0,0 +1,279 @@
+use anyhow::*;
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use std::fs::{File, create_dir_all};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::bytecode::heap::{HeapObject, Pointer};
+use crate::bytecode::state::State;
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct HeapDumpConfig {
+    pub at_exit: Option<PathBuf>,
+    pub on_error: Option<PathBuf>,
+}
+
+// Pointers are dumped as JSON scalars, except references, which become `{"ref": index}`.
+#[derive(Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum DumpValue {
+    Null,
+    Integer(i32),
+    Boolean(bool),
+    Reference { #[serde(rename = "ref")] reference: usize },
+}
+
+impl From<&Pointer> for DumpValue {
+    fn from(pointer: &Pointer) -> Self {
+        match pointer {
+            Pointer::Null => DumpValue::Null,
+            Pointer::Integer(i) => DumpValue::Integer(*i),
+            Pointer::Boolean(b) => DumpValue::Boolean(*b),
+            Pointer::Reference(index) => DumpValue::Reference { reference: index.as_usize() },
+        }
+    }
+}
+
+impl std::fmt::Display for DumpValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DumpValue::Null => write!(f, "null"),
+            DumpValue::Integer(i) => write!(f, "{}", i),
+            DumpValue::Boolean(b) => write!(f, "{}", b),
+            DumpValue::Reference { reference } => write!(f, "#{}", reference),
+        }
+    }
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct DumpFrame {
+    pub return_address: Option<usize>,
+    pub locals: Vec<DumpValue>,
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct DumpRoots {
+    pub globals: IndexMap<String, DumpValue>,
+    pub frames: Vec<DumpFrame>,
+    pub operands: Vec<DumpValue>,
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DumpObject {
+    Array { index: usize, elements: Vec<DumpValue> },
+    Object { index: usize, parent: DumpValue, fields: IndexMap<String, DumpValue>, methods: Vec<String> },
+    String { index: usize, value: String },
+    Closure { index: usize, function: usize, captured: Vec<DumpValue> },
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct HeapSnapshot {
+    pub roots: DumpRoots,
+    pub objects: Vec<DumpObject>,
+}
+
+impl HeapSnapshot {
+    pub fn from(state: &State) -> Self {
+        let mut globals: Vec<(&String, &Pointer)> = state.frame_stack.globals.iter_named().collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let roots = DumpRoots {
+            globals: globals.into_iter()
+                .map(|(name, pointer)| (name.clone(), DumpValue::from(pointer)))
+                .collect(),
+            frames: state.frame_stack.iter()
+                .map(|frame| DumpFrame {
+                    return_address: frame.return_address.map(|address| address.value_usize()),
+                    locals: frame.iter().map(DumpValue::from).collect(),
+                })
+                .collect(),
+            operands: state.operand_stack.iter().map(DumpValue::from).collect(),
+        };
+
+        let objects = state.heap.objects()
+            .map(|(index, object)| match object {
+                HeapObject::Array(array) => DumpObject::Array {
+                    index: index.as_usize(),
+                    elements: array.iter().map(DumpValue::from).collect(),
+                },
+                HeapObject::Object(object) => DumpObject::Object {
+                    index: index.as_usize(),
+                    parent: DumpValue::from(&object.parent),
+                    fields: object.fields.iter()
+                        .map(|(name, pointer)| (name.clone(), DumpValue::from(pointer)))
+                        .collect(),
+                    methods: object.methods.keys().cloned().collect(),
+                },
+                HeapObject::String(string) => DumpObject::String {
+                    index: index.as_usize(),
+                    value: string.clone(),
+                },
+                HeapObject::Closure(closure) => DumpObject::Closure {
+                    index: index.as_usize(),
+                    function: closure.function.as_usize(),
+                    captured: closure.captured.iter().map(DumpValue::from).collect(),
+                },
+            })
+            .collect();
+
+        HeapSnapshot { roots, objects }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_dot(&self) -> String {
+        fn escape(string: &str) -> String {
+            string.replace('\\', "\\\\").replace('"', "\\\"")
+                .replace('|', "\\|").replace('{', "\\{").replace('}', "\\}")
+                .replace('<', "\\<").replace('>', "\\>")
+        }
+        fn slot(value: &DumpValue) -> String {
+            match value {
+                DumpValue::Reference { .. } => "•".to_owned(),
+                value => escape(&value.to_string()),
+            }
+        }
+
+        let mut dot = String::new();
+        let mut edges = Vec::new();
+        dot.push_str("digraph heap {\n");
+        dot.push_str("  node [shape=record, fontname=\"monospace\"];\n");
+
+        let mut root_slots = Vec::new();
+        let mut add_root = |port: String, label: String, value: &DumpValue| {
+            if let DumpValue::Reference { reference } = value {
+                edges.push(format!("  roots:{} -> o{};", port, reference));
+            }
+            root_slots.push(format!("<{}> {} = {}", port, escape(&label), slot(value)));
+        };
+        for (position, (name, value)) in self.roots.globals.iter().enumerate() {
+            add_root(format!("g{}", position), name.clone(), value);
+        }
+        for (depth, frame) in self.roots.frames.iter().enumerate() {
+            for (local, value) in frame.locals.iter().enumerate() {
+                add_root(format!("f{}_{}", depth, local), format!("frame {} local {}", depth, local), value);
+            }
+        }
+        for (position, value) in self.roots.operands.iter().enumerate() {
+            add_root(format!("s{}", position), format!("operand {}", position), value);
+        }
+        dot.push_str(&format!("  roots [label=\"{{roots|{}}}\", style=bold];\n", root_slots.join("|")));
+
+        for object in self.objects.iter() {
+            match object {
+                DumpObject::Array { index, elements } => {
+                    let slots: Vec<String> = elements.iter().enumerate()
+                        .map(|(position, value)| {
+                            if let DumpValue::Reference { reference } = value {
+                                edges.push(format!("  o{}:e{} -> o{};", index, position, reference));
+                            }
+                            format!("<e{}> {}", position, slot(value))
+                        })
+                        .collect();
+                    dot.push_str(&format!("  o{} [label=\"{{array #{}|{{{}}}}}\"];\n",
+                                          index, index, slots.join("|")));
+                }
+                DumpObject::Object { index, parent, fields, methods } => {
+                    if let DumpValue::Reference { reference } = parent {
+                        edges.push(format!("  o{} -> o{} [style=dashed, label=\"parent\"];", index, reference));
+                    }
+                    let mut slots: Vec<String> = fields.iter().enumerate()
+                        .map(|(position, (name, value))| {
+                            if let DumpValue::Reference { reference } = value {
+                                edges.push(format!("  o{}:f{} -> o{};", index, position, reference));
+                            }
+                            format!("<f{}> {} = {}", position, escape(name), slot(value))
+                        })
+                        .collect();
+                    if !methods.is_empty() {
+                        let methods: Vec<String> = methods.iter().map(|name| escape(name)).collect();
+                        slots.push(format!("methods: {}", methods.join(", ")));
+                    }
+                    let parent = match parent {
+                        DumpValue::Reference { .. } => String::new(),
+                        parent => format!(" extends {}", escape(&parent.to_string())),
+                    };
+                    dot.push_str(&format!("  o{} [label=\"{{object #{}{}|{}}}\"];\n",
+                                          index, index, parent, slots.join("|")));
+                }
+                DumpObject::String { index, value } => {
+                    dot.push_str(&format!("  o{} [label=\"{{string #{}|{}}}\"];\n",
+                                          index, index, escape(&format!("{:?}", value))));
+                }
+                DumpObject::Closure { index, function, captured } => {
+                    let slots: Vec<String> = captured.iter().enumerate()
+                        .map(|(position, value)| {
+                            if let DumpValue::Reference { reference } = value {
+                                edges.push(format!("  o{}:c{} -> o{};", index, position, reference));
+                            }
+                            format!("<c{}> {}", position, slot(value))
+                        })
+                        .collect();
+                    dot.push_str(&format!("  o{} [label=\"{{closure #{} of method #{}|{{{}}}}}\"];\n",
+                                          index, index, function, slots.join("|")));
+                }
+            }
+        }
+
+        for edge in edges {
+            dot.push_str(&edge);
+            dot.push('\n');
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // One line per global and heap object, for the REPL.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in self.roots.globals.iter() {
+            text.push_str(&format!("{} = {}\n", name, value));
+        }
+        for object in self.objects.iter() {
+            match object {
+                DumpObject::Array { index, elements } => {
+                    let elements: Vec<String> = elements.iter().map(|value| value.to_string()).collect();
+                    text.push_str(&format!("#{}: array [{}]\n", index, elements.join(", ")));
+                }
+                DumpObject::Object { index, parent, fields, methods } => {
+                    let fields: Vec<String> = fields.iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect();
+                    text.push_str(&format!("#{}: object extends {} ({})", index, parent, fields.join(", ")));
+                    if !methods.is_empty() {
+                        text.push_str(&format!(" methods: {}", methods.join(", ")));
+                    }
+                    text.push('\n');
+                }
+                DumpObject::String { index, value } => {
+                    text.push_str(&format!("#{}: string {:?}\n", index, value));
+                }
+                DumpObject::Closure { index, function, captured } => {
+                    let captured: Vec<String> = captured.iter().map(|value| value.to_string()).collect();
+                    text.push_str(&format!("#{}: closure of method #{} [{}]\n", index, function, captured.join(", ")));
+                }
+            }
+        }
+        text
+    }
+
+    // Files ending in `.dot` or `.gv` get the GraphViz rendering, everything else gets JSON.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let contents = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("dot") | Some("gv") => self.to_dot(),
+            _ => self.to_json(),
+        };
+        let mut file = File::create(path)
+            .with_context(|| format!("Cannot create heap dump `{}`", path.display()))?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
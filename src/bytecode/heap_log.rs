@@ -0,0 +1,283 @@
+use anyhow::*;
+use indexmap::IndexMap;
+use serde::{Serialize, Deserialize};
+
+use std::fs::{File, create_dir_all};
+use std::io::{BufRead, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::bytecode::program::{Address, Program};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum HeapLogFormat {
+    Csv,
+    JsonLines,
+}
+
+impl std::str::FromStr for HeapLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv"                           => Ok(Self::Csv),
+            "jsonl" | "json-lines" | "json" => Ok(Self::JsonLines),
+            format => Err(anyhow!("Unknown heap log format: {}", format))
+        }
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,event,heap,kind,size,address,function,live,freed,copied,pause";
+
+// Event codes: `S` starts the log, `A` is an allocation, `N` is a minor (nursery) collection, and
+// `G` is a major or full collection. Timestamps and pauses are in nanoseconds, sizes in bytes.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct HeapEvent {
+    pub timestamp: u64,
+    pub event: String,
+    pub heap: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub address: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub function: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub live: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub freed: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub copied: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub pause: Option<u64>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct AllocationSite { pub address: Option<Address>, pub function: Option<String> }
+
+impl AllocationSite {
+    pub fn new(program: &Program, address: Option<Address>) -> Self {
+        let function = address.as_ref()
+            .and_then(|address| program.enclosing_method(address))
+            .map(|name| name.to_owned());
+        AllocationSite { address, function }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+impl HeapEvent {
+    pub fn start() -> Self {
+        HeapEvent { timestamp: now(), event: "S".to_owned(), ..Default::default() }
+    }
+    pub fn allocation(heap: usize, kind: &str, size: usize, site: &AllocationSite) -> Self {
+        HeapEvent {
+            timestamp: now(),
+            event: "A".to_owned(),
+            heap,
+            kind: Some(kind.to_owned()),
+            size: Some(size),
+            address: site.address.map(|address| address.value_usize()),
+            function: site.function.clone(),
+            ..Default::default()
+        }
+    }
+    pub fn collection(minor: bool, heap: usize, freed: usize, copied: usize, pause: u64) -> Self {
+        HeapEvent {
+            timestamp: now(),
+            event: if minor { "N" } else { "G" }.to_owned(),
+            heap,
+            live: Some(heap),
+            freed: Some(freed),
+            copied: Some(copied),
+            pause: Some(pause),
+            ..Default::default()
+        }
+    }
+    pub fn is_allocation(&self) -> bool { self.event == "A" }
+    pub fn is_collection(&self) -> bool { self.event == "N" || self.event == "G" }
+
+    pub fn to_csv(&self) -> String {
+        fn column<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map_or(String::new(), |value| value.to_string())
+        }
+        format!("{},{},{},{},{},{},{},{},{},{},{}",
+                self.timestamp, self.event, self.heap,
+                column(&self.kind), column(&self.size), column(&self.address), column(&self.function),
+                column(&self.live), column(&self.freed), column(&self.copied), column(&self.pause))
+    }
+    pub fn from_csv(line: &str) -> Result<Self> {
+        fn column<T: std::str::FromStr>(value: &str) -> Result<Option<T>> where T::Err: std::fmt::Display {
+            if value.is_empty() { return Ok(None) }
+            value.parse().map(Some).map_err(|e| anyhow!("Cannot parse `{}`: {}", value, e))
+        }
+        let columns: Vec<&str> = line.split(',').collect();
+        // Logs written before kinds and sites were recorded only have five columns:
+        // timestamp,event,heap,copied,reclaimed.
+        match columns.as_slice() {
+            [timestamp, event, heap, kind, size, address, function, live, freed, copied, pause] =>
+                Ok(HeapEvent {
+                    timestamp: timestamp.parse()?,
+                    event: event.to_string(),
+                    heap: heap.parse()?,
+                    kind: column(kind)?,
+                    size: column(size)?,
+                    address: column(address)?,
+                    function: column(function)?,
+                    live: column(live)?,
+                    freed: column(freed)?,
+                    copied: column(copied)?,
+                    pause: column(pause)?,
+                }),
+            [timestamp, event, heap, copied, reclaimed] =>
+                Ok(HeapEvent {
+                    timestamp: timestamp.parse()?,
+                    event: event.to_string(),
+                    heap: heap.parse()?,
+                    copied: column(copied)?,
+                    freed: column(reclaimed)?,
+                    ..Default::default()
+                }),
+            _ => bail!("Cannot parse heap log line, unexpected number of columns: `{}`", line),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HeapLog { file: BufWriter<File>, format: HeapLogFormat }
+
+impl HeapLog {
+    pub fn create(path: PathBuf, format: HeapLogFormat) -> Result<Self> {
+        let mut dir = path.clone();
+        dir.pop();
+        create_dir_all(dir)?;
+
+        let mut file = BufWriter::new(File::create(&path)
+            .with_context(|| format!("Cannot create heap log `{}`", path.display()))?);
+        if format == HeapLogFormat::Csv {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        let mut log = HeapLog { file, format };
+        log.write(&HeapEvent::start());
+        Ok(log)
+    }
+    pub fn write(&mut self, event: &HeapEvent) {
+        match self.format {
+            HeapLogFormat::Csv => writeln!(self.file, "{}", event.to_csv()),
+            HeapLogFormat::JsonLines => writeln!(self.file, "{}", serde_json::to_string(event).unwrap()),
+        }.unwrap()
+    }
+}
+
+// Reads either format: JSON-lines logs are recognized by their first line being a JSON object.
+pub fn read_heap_log<R: BufRead>(input: R) -> Result<Vec<HeapEvent>> {
+    let mut events = Vec::new();
+    let mut lines = input.lines().enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .peekable();
+
+    let json = match lines.peek() {
+        Some((_, Ok(line))) => line.trim_start().starts_with('{'),
+        _ => false,
+    };
+    for (number, line) in lines {
+        let line = line?;
+        if !json && line.starts_with("timestamp,") {
+            continue
+        }
+        let event = if json {
+            serde_json::from_str(&line).map_err(anyhow::Error::from)
+        } else {
+            HeapEvent::from_csv(line.trim_end())
+        };
+        events.push(event.with_context(|| format!("Error in heap log at line {}", number + 1))?);
+    }
+    Ok(events)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct SiteSummary { pub allocations: usize, pub bytes: usize }
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct HeapReport {
+    pub duration: u64,
+    pub allocations: usize,
+    pub allocated: usize,
+    pub peak: usize,
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    pub freed: usize,
+    pub total_pause: u64,
+    pub max_pause: u64,
+    pub sites: IndexMap<String, SiteSummary>,
+}
+
+impl HeapReport {
+    pub fn from(events: &[HeapEvent]) -> Self {
+        let mut report = HeapReport::default();
+        if let (Some(first), Some(last)) = (events.first(), events.last()) {
+            report.duration = last.timestamp.saturating_sub(first.timestamp);
+        }
+        let mut previous_heap = 0;
+        for event in events {
+            report.peak = std::cmp::max(report.peak, event.heap);
+            if event.is_allocation() {
+                // Old logs only record the heap size, so the allocation size is the growth.
+                let size = event.size.unwrap_or(event.heap.saturating_sub(previous_heap));
+                let site = match (&event.function, event.address) {
+                    (Some(function), Some(address)) =>
+                        format!("{} @ {}", function, Address::from_usize(address)),
+                    (None, Some(address)) => format!("? @ {}", Address::from_usize(address)),
+                    (Some(function), None) => function.clone(),
+                    (None, None) => "unknown".to_owned(),
+                };
+                let summary = report.sites.entry(site).or_default();
+                summary.allocations += 1;
+                summary.bytes += size;
+                report.allocations += 1;
+                report.allocated += size;
+            }
+            if event.is_collection() {
+                if event.event == "N" {
+                    report.minor_collections += 1
+                } else {
+                    report.major_collections += 1
+                }
+                let pause = event.pause.unwrap_or(0);
+                report.freed += event.freed.unwrap_or(0);
+                report.total_pause += pause;
+                report.max_pause = std::cmp::max(report.max_pause, pause);
+            }
+            previous_heap = event.heap;
+        }
+        report.sites.sort_by(|_, a, _, b| b.bytes.cmp(&a.bytes).then(b.allocations.cmp(&a.allocations)));
+        report
+    }
+    pub fn allocation_rate(&self) -> f64 { /* in B/s */
+        if self.duration == 0 { return 0.0 }
+        self.allocated as f64 / (self.duration as f64 / 1e9)
+    }
+    pub fn collections(&self) -> usize {
+        self.minor_collections + self.major_collections
+    }
+}
+
+impl std::fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Duration:        {:.3} ms", self.duration as f64 / 1e6)?;
+        writeln!(f, "Allocations:     {} ({} B)", self.allocations, self.allocated)?;
+        writeln!(f, "Allocation rate: {:.0} B/s", self.allocation_rate())?;
+        writeln!(f, "Peak heap:       {} B", self.peak)?;
+        writeln!(f, "Collections:     {} ({} minor, {} major), {} B freed",
+                 self.collections(), self.minor_collections, self.major_collections, self.freed)?;
+        if self.collections() > 0 {
+            writeln!(f, "GC pauses:       {:.3} ms total, {:.3} ms mean, {:.3} ms max",
+                     self.total_pause as f64 / 1e6,
+                     self.total_pause as f64 / 1e6 / self.collections() as f64,
+                     self.max_pause as f64 / 1e6)?;
+        }
+        if !self.sites.is_empty() {
+            writeln!(f, "Allocation sites:")?;
+            for (site, summary) in self.sites.iter() {
+                writeln!(f, "  {:>8} B {:>6} × {}", summary.bytes, summary.allocations, site)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,10 +2,13 @@ use std::fmt::Write;
 
 use crate::bytecode::bytecode::OpCode;
 use crate::bytecode::heap::*;
+use crate::bytecode::heap_dump::{HeapSnapshot, HeapDumpConfig};
+use crate::bytecode::stack_trace::StackTrace;
 
 use anyhow::*;
 use anyhow::Context;
 use std::iter::repeat;
+use std::time::{Duration, Instant};
 
 use crate::bail_if;
 use crate::veccat;
@@ -13,17 +16,19 @@ use crate::veccat;
 use crate::bytecode::program::*;
 use crate::bytecode::state::*;
 use indexmap::map::IndexMap;
-use std::path::PathBuf;
 
 
 trait OpCodeEvaluationResult<T> {
-    fn attach(self, opcode: &OpCode) -> Result<T>;
+    fn attach(self, program: &Program, address: Option<Address>, opcode: &OpCode) -> Result<T>;
 }
 
 impl<T> OpCodeEvaluationResult<T> for Result<T> {
     #[inline(always)]
-    fn attach(self, opcode: &OpCode) -> Result<T> {
-        self.with_context(|| format!("Error evaluating {}:", opcode))
+    fn attach(self, program: &Program, address: Option<Address>, opcode: &OpCode) -> Result<T> {
+        self.with_context(|| match address {
+            Some(address) => format!("Error evaluating {}:", program.describe_instruction(address, opcode)),
+            None => format!("Error evaluating {}:", opcode),
+        })
     }
 }
 
@@ -34,22 +39,88 @@ pub fn evaluate(program: &Program) -> Result<()> {
     evaluate_with(program, &mut state, &mut output)
 }
 
-pub fn evaluate_with_memory_config(program: &Program, heap_size: usize, heap_log: Option<PathBuf>) -> Result<()> {
+pub fn evaluate_with_memory_config(program: &Program, config: HeapConfig, dumps: HeapDumpConfig, limits: StackLimits, budget: Budget) -> Result<()> {
     let mut state = State::from(program)?;
-    state.heap.set_size(heap_size);
-    if let Some(log) = heap_log {
-        state.heap.set_log(log);
-    }
+    state.heap.configure(config);
+    state.limits = limits;
     let mut output = Output::new();
-    evaluate_with(program, &mut state, &mut output)
+    let result = evaluate_with_budget(program, &mut state, &mut output, budget);
+
+    let on_error = dumps.on_error.filter(|_| result.is_err());
+    for path in dumps.at_exit.iter().chain(on_error.iter()) {
+        HeapSnapshot::from(&state).write_to(path)?;
+    }
+    result
 }
 
 pub fn evaluate_with<W>(program: &Program, state: &mut State, output: &mut W) -> Result<()> where W: Write {
-    // eprintln!("Program:");
-    // eprintln!("{}", program);
+    evaluate_with_budget(program, state, output, Budget::unlimited())
+}
+
+// A budget caps the work done by a single call to `evaluate_with_budget`. Running out of budget
+// stops before the next instruction with a `BudgetExhausted` error and leaves the state intact, so
+// calling `evaluate_with_budget` again with the same state resumes the program where it stopped.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Budget { pub instructions: Option<u64>, pub time: Option<Duration> }
+
+impl Budget {
+    pub fn unlimited() -> Self {
+        Budget { instructions: None, time: None }
+    }
+    #[allow(dead_code)]
+    pub fn instructions(instructions: u64) -> Self {
+        Budget { instructions: Some(instructions), time: None }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum BudgetExhausted {
+    Instructions { limit: u64 },
+    Time { limit: Duration },
+}
+
+impl std::fmt::Display for BudgetExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BudgetExhausted::Instructions { limit } =>
+                write!(f, "Instruction budget exhausted: executed {} instructions", limit),
+            BudgetExhausted::Time { limit } =>
+                write!(f, "Timeout: execution did not finish within {:.3} s", limit.as_secs_f64()),
+        }
+    }
+}
+
+impl std::error::Error for BudgetExhausted {}
+
+// The clock is only consulted every so many instructions, since reading it is comparatively slow.
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+pub fn evaluate_with_budget<W>(program: &Program, state: &mut State, output: &mut W, budget: Budget) -> Result<()> where W: Write {
+    let start = Instant::now();
+    let mut executed: u64 = 0;
     while let Some(address) = state.instruction_pointer.get() {
+        if let Some(limit) = budget.instructions {
+            if executed >= limit {
+                return Err(BudgetExhausted::Instructions { limit }.into())
+            }
+        }
+        if let Some(limit) = budget.time {
+            if executed % TIMEOUT_CHECK_INTERVAL == 0 && start.elapsed() >= limit {
+                return Err(BudgetExhausted::Time { limit }.into())
+            }
+        }
         let opcode = program.code.get(address)?;
-        eval_opcode(program, state, output, opcode)?;
+        if let Err(error) = eval_opcode(program, state, output, opcode) {
+            let location = program.debug.as_ref().and_then(|debug| debug.locate(address));
+            let error = match (location, program.code.span(address)) {
+                (Some(location), _) => error.context(location),
+                (None, Some(span)) => span.attach_to(error),
+                (None, None) => error,
+            };
+            return Err(error.context(StackTrace::from(program, state)))
+        }
+        executed += 1;
+        state.instruction_count += 1;
     }
     Ok(())
 }
@@ -62,6 +133,7 @@ pub fn step_with<W>(program: &Program, state: &mut State, output: &mut W) -> Res
 }
 
 pub fn eval_opcode<W>(program: &Program, state: &mut State, output: &mut W, opcode: &OpCode) -> Result<()> where W: Write {
+    let address = state.instruction_pointer.get();
     match opcode {
         OpCode::Literal { index } => eval_literal(program, state, index),
         OpCode::GetLocal { index } => eval_get_local(program, state, index),
@@ -73,20 +145,24 @@ pub fn eval_opcode<W>(program: &Program, state: &mut State, output: &mut W, opco
         OpCode::GetField { name } => eval_get_field(program, state, name),
         OpCode::SetField { name } => eval_set_field(program, state, name),
         OpCode::CallMethod { name, arguments } => eval_call_method(program, state, name, arguments),
-        OpCode::CallFunction { name, arguments } => eval_call_function(program, state, name, arguments),
+        OpCode::CallFunction { name, arguments } => eval_call_function(program, state, output, name, arguments),
         OpCode::Label { .. } => eval_label(program, state),
         OpCode::Print { format, arguments } => eval_print(program, state, output, format, arguments),
         OpCode::Jump { label } => eval_jump(program, state, label),
         OpCode::Branch { label } => eval_branch(program, state, label),
         OpCode::Return => eval_return(program, state),
         OpCode::Drop => eval_drop(program, state),
-    }.attach(opcode)
+        OpCode::Closure { function, captured } => eval_closure(program, state, function, captured),
+    }.and_then(|_| state.ensure_operand_capacity(program, address)).attach(program, address, opcode)
 }
 
 #[inline(always)]
 pub fn eval_literal(program: &Program, state: &mut State, index: &ConstantPoolIndex) -> Result<()> {
     let program_object = program.constant_pool.get(index)?;
-    let pointer = Pointer::from_literal(program_object)?;
+    let pointer = match program_object {
+        ProgramObject::String(string) => Pointer::from(state.allocate(program, HeapObject::String(string.clone()))?),
+        literal => Pointer::from_literal(literal)?,
+    };
     state.operand_stack.push(pointer);
     state.instruction_pointer.bump(program);
     Ok(())
@@ -106,6 +182,9 @@ pub fn eval_set_local(program: &Program, state: &mut State, index: &LocalFrameIn
     let pointer = *state.operand_stack.peek()?;
     let frame = state.frame_stack.get_locals_mut()?;
     frame.set(index, pointer)?;
+    if state.heap.is_young(&pointer) {
+        frame.remember();
+    }
     state.instruction_pointer.bump(program);
     Ok(())
 }
@@ -114,7 +193,17 @@ pub fn eval_set_local(program: &Program, state: &mut State, index: &LocalFrameIn
 pub fn eval_get_global(program: &Program, state: &mut State, index: &ConstantPoolIndex) -> Result<()> {
     let program_object = program.constant_pool.get(index)?;
     let name = program_object.as_str()?;
-    let pointer = *state.frame_stack.globals.get(name)?;
+    // A function name that no global shadows evaluates to a reference to the function.
+    let pointer = match state.frame_stack.globals.get(name) {
+        Ok(pointer) => *pointer,
+        Err(error) => match state.frame_stack.functions.get(name) {
+            Ok(function) => {
+                let reference = HeapObject::Closure(ClosureInstance { function: *function, captured: vec![] });
+                Pointer::from(state.allocate(program, reference)?)
+            }
+            Err(_) => return Err(error),
+        }
+    };
     state.operand_stack.push(pointer);
     state.instruction_pointer.bump(program);
     Ok(())
@@ -126,6 +215,9 @@ pub fn eval_set_global(program: &Program, state: &mut State, index: &ConstantPoo
     let name = program_object.as_str()?.to_owned();
     let pointer = *state.operand_stack.peek()?;
     state.frame_stack.globals.update(name, pointer)?;
+    if state.heap.is_young(&pointer) {
+        state.frame_stack.globals.remember();
+    }
     state.instruction_pointer.bump(program);
     Ok(())
 }
@@ -168,7 +260,7 @@ pub fn eval_object(program: &Program, state: &mut State, index: &ConstantPoolInd
 
     let parent = state.operand_stack.pop()?;
 
-    let heap_index = state.heap.allocate(HeapObject::new_object(parent, fields, methods)); // TODO simplify
+    let heap_index = state.allocate(program, HeapObject::new_object(parent, fields, methods))?; // TODO simplify
     state.operand_stack.push(Pointer::from(heap_index));
     state.instruction_pointer.bump(program);
     Ok(())
@@ -185,7 +277,7 @@ pub fn eval_array(program: &Program, state: &mut State) -> Result<()> {
     let elements = repeat(initializer).take(n as usize).collect();
     let array = HeapObject::from_pointers(elements);
 
-    let heap_index = state.heap.allocate(array);
+    let heap_index = state.allocate(program, array)?;
     state.operand_stack.push(Pointer::from(heap_index));
     state.instruction_pointer.bump(program);
     Ok(())
@@ -217,6 +309,7 @@ pub fn eval_set_field(program: &Program, state: &mut State, index: &ConstantPool
 
     let object_instance = object.as_object_instance_mut()?;
     object_instance.set_field(name, value_pointer.clone())?;
+    state.heap.write_barrier(&heap_pointer, &value_pointer);
     state.operand_stack.push(value_pointer);
     state.instruction_pointer.bump(program);
     Ok(())
@@ -255,15 +348,22 @@ fn dispatch_method(program: &Program, state: &mut State, receiver_pointer: Point
             state.instruction_pointer.bump(program);
         }
         Pointer::Reference(index) =>
-            match state.heap.dereference_mut(&index)? {
-                HeapObject::Array(array) => {
-                    dispatch_array_method(array, method_name, argument_pointers)?
+            match state.heap.dereference(&index)? {
+                HeapObject::Array(_) => {
+                    dispatch_array_method(&mut state.heap, &index, method_name, argument_pointers)?
                         .push_onto(&mut state.operand_stack);
                     state.instruction_pointer.bump(program);
                 }
                 HeapObject::Object(_) =>
                     dispatch_object_method(program, state, receiver_pointer,
                                            method_name, argument_pointers)?,
+                HeapObject::String(_) => {
+                    dispatch_string_method(program, state, &index, method_name, argument_pointers)?
+                        .push_onto(&mut state.operand_stack);
+                    state.instruction_pointer.bump(program);
+                }
+                HeapObject::Closure(_) =>
+                    dispatch_closure_method(program, state, index, method_name, argument_pointers)?,
             }
     }
     Ok(())
@@ -364,15 +464,15 @@ fn dispatch_boolean_method(receiver: &bool, method_name: &str, argument_pointers
     Ok(result)
 }
 
-fn dispatch_array_method(array: &mut ArrayInstance, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
+fn dispatch_array_method(heap: &mut Heap, index: &HeapIndex, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
     match method_name {
-        "get" => dispatch_array_get_method(array, method_name, argument_pointers),
-        "set" => dispatch_array_set_method(array, method_name, argument_pointers),
-        _ => bail!("Call method error: no method `{}` in array `{}`",  method_name, array),
+        "get" => dispatch_array_get_method(heap.dereference(index)?.as_array_instance()?, method_name, argument_pointers),
+        "set" => dispatch_array_set_method(heap, index, method_name, argument_pointers),
+        _ => bail!("Call method error: no method `{}` in array `{}`",  method_name, heap.dereference(index)?),
     }
 }
 
-fn dispatch_array_get_method(array: &mut ArrayInstance, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
+fn dispatch_array_get_method(array: &ArrayInstance, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
     bail_if!(argument_pointers.len() != 1,
              "Invalid number of arguments for method `{}` in array `{}`, expecting 1",
              method_name, array);
@@ -382,7 +482,8 @@ fn dispatch_array_get_method(array: &mut ArrayInstance, method_name: &str, argum
     array.get_element(index).map(|e| *e)
 }
 
-fn dispatch_array_set_method(array: &mut ArrayInstance, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
+fn dispatch_array_set_method(heap: &mut Heap, array_index: &HeapIndex, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
+    let array = heap.dereference_mut(array_index)?.as_array_instance_mut()?;
     bail_if!(argument_pointers.len() != 2,
              "Invalid number of arguments for method `{}` in array `{}`, expecting 2",
              method_name, array);
@@ -390,7 +491,130 @@ fn dispatch_array_set_method(array: &mut ArrayInstance, method_name: &str, argum
     let index_pointer = argument_pointers.first().unwrap();
     let value_pointer = argument_pointers.last().unwrap();
     let index = index_pointer.as_usize()?;
-    array.set_element(index, *value_pointer).map(|e| *e)
+    let result = array.set_element(index, *value_pointer).map(|e| *e)?;
+    heap.write_barrier(array_index, value_pointer);
+    Ok(result)
+}
+
+// Strings are immutable, so methods that produce text allocate a new string.
+fn dispatch_string_method(program: &Program, state: &mut State, index: &HeapIndex, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
+    let receiver = state.heap.dereference(index)?.as_str().unwrap();
+    let expect_arguments = |expected: usize| {
+        bail_if!(argument_pointers.len() != expected,
+                 "Invalid number of arguments for method `{}` in string {:?}, expecting {}",
+                 method_name, receiver, expected);
+        Ok(())
+    };
+
+    let result = match method_name {
+        "+" | "add" => {
+            expect_arguments(1)?;
+            let argument = argument_pointers[0].evaluate_as_string(&state.heap)?;
+            receiver.to_owned() + &argument
+        }
+        "get" => {
+            expect_arguments(1)?;
+            let position = argument_pointers[0].as_usize()?;
+            let character = receiver.chars().nth(position).with_context(|| {
+                format!("Index out of range {} for string {:?} with length {}", position, receiver, receiver.chars().count())
+            })?;
+            character.to_string()
+        }
+        "substring" => {
+            expect_arguments(2)?;
+            let start = argument_pointers[0].as_usize()?;
+            let end = argument_pointers[1].as_usize()?;
+            let length = receiver.chars().count();
+            bail_if!(start > end || end > length,
+                     "Range {}..{} out of bounds for string {:?} with length {}", start, end, receiver, length);
+            receiver.chars().skip(start).take(end - start).collect()
+        }
+        _ => return string_method(receiver, &state.heap, method_name, argument_pointers),
+    };
+    state.allocate(program, HeapObject::String(result)).map(Pointer::from)
+}
+
+fn string_method(receiver: &str, heap: &Heap, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<Pointer> {
+    let other = |pointer: &Pointer| pointer.as_heap_reference()
+        .and_then(|index| heap.dereference(index).ok())
+        .and_then(|object| object.as_str());
+
+    let result = match (method_name, argument_pointers.as_slice()) {
+        ("==", [argument]) | ("eq",  [argument]) => Pointer::from(other(argument) == Some(receiver)),
+        ("!=", [argument]) | ("neq", [argument]) => Pointer::from(other(argument) != Some(receiver)),
+        ("length", []) => Pointer::from(receiver.chars().count() as i32),
+        // Text that is not a number converts to null, so that input can be checked.
+        ("to_int", []) => receiver.trim().parse::<i32>().map_or(Pointer::Null, Pointer::from),
+        ("==", _) | ("eq", _) | ("!=", _) | ("neq", _) | ("length", _) | ("to_int", _) =>
+            bail!("Invalid number of arguments for method `{}` in string {:?}", method_name, receiver),
+        _ => bail!("Call method error: no method `{}` in string {:?}", method_name, receiver),
+    };
+    Ok(result)
+}
+
+fn dispatch_closure_method(program: &Program, state: &mut State, index: HeapIndex, method_name: &str, argument_pointers: Vec<Pointer>) -> Result<()> {
+    let result = match (method_name, argument_pointers.as_slice()) {
+        ("call", _) => return eval_call_closure(program, state, index, argument_pointers),
+        ("apply", [argument]) => {
+            let arguments = argument.as_heap_reference()
+                .and_then(|reference| state.heap.dereference(reference).ok())
+                .and_then(|object| object.as_array_instance().ok())
+                .map(|array| array.iter().copied().collect())
+                .with_context(|| format!("Function `apply` requires an array of arguments, but `{}` was supplied",
+                                         argument.evaluate_as_string(&state.heap).unwrap_or_default()))?;
+            return eval_call_closure(program, state, index, arguments)
+        }
+        ("==", [argument]) | ("eq",  [argument]) => Pointer::from(same_function(state, index, argument)?),
+        ("!=", [argument]) | ("neq", [argument]) => Pointer::from(!same_function(state, index, argument)?),
+        _ => bail!("Call method error: no method `{}` in function `{}`", method_name, state.heap.dereference(&index)?),
+    };
+    result.push_onto(&mut state.operand_stack);
+    state.instruction_pointer.bump(program);
+    Ok(())
+}
+
+// Each evaluation of a function name creates a new reference, so references to the same function
+// that capture nothing are equal; other closures are only equal to themselves.
+fn same_function(state: &State, index: HeapIndex, argument: &Pointer) -> Result<bool> {
+    if *argument == Pointer::from(index) {
+        return Ok(true)
+    }
+    let other = match argument.as_heap_reference() {
+        Some(other) => state.heap.dereference(other)?,
+        None => return Ok(false),
+    };
+    let closure = state.heap.dereference(&index)?.as_closure_instance()?;
+    Ok(match other.as_closure_instance() {
+        Ok(other) => closure.captured.is_empty() && other.captured.is_empty() && closure.function == other.function,
+        Err(_) => false,
+    })
+}
+
+// The frame of a closure holds the arguments, then the captured values, then the other locals.
+fn eval_call_closure(program: &Program, state: &mut State, index: HeapIndex, argument_pointers: Vec<Pointer>) -> Result<()> {
+    let closure = state.heap.dereference(&index)?.as_closure_instance()?;
+    let function = program.constant_pool.get(&closure.function)?;
+    let name = program.constant_pool.get(function.get_method_name()?)?.as_str()?;
+    let parameters = function.get_method_parameters()?;
+    let locals = function.get_method_locals()?;
+    let address = function.get_method_start_address()?;
+
+    bail_if!(argument_pointers.len() != parameters.to_usize(),
+             "Function `{}` requires {} arguments, but {} were supplied",
+             name, parameters, argument_pointers.len());
+    bail_if!(closure.captured.len() > locals.to_usize(),
+             "Function `{}` has {} locals, which cannot hold {} captured values",
+             name, locals, closure.captured.len());
+
+    state.ensure_frame_capacity(name)?;
+    let captured_pointers = closure.captured.clone();
+    let local_pointers = locals.make_vector(Pointer::Null).into_iter().skip(captured_pointers.len());
+
+    state.instruction_pointer.bump(program);
+    let frame = Frame::from(state.instruction_pointer.get(), veccat!(argument_pointers, captured_pointers, local_pointers));
+    state.frame_stack.push(frame);
+    state.instruction_pointer.set(Some(*address));
+    Ok(())
 }
 
 fn dispatch_object_method(program: &Program, state: &mut State,
@@ -428,6 +652,7 @@ fn eval_call_object_method(program: &Program, state: &mut State,
              "Method `{}` requires {} arguments, but {} were supplied",
              method_name, parameters, argument_pointers.len());
 
+    state.ensure_frame_capacity(method_name)?;
     let local_pointers = locals.make_vector(Pointer::Null);
 
     state.instruction_pointer.bump(program);
@@ -438,10 +663,24 @@ fn eval_call_object_method(program: &Program, state: &mut State,
 }
 
 #[inline(always)]
-pub fn eval_call_function(program: &Program, state: &mut State, index: &ConstantPoolIndex, arguments: &Arity) -> Result<()> {
+pub fn eval_call_function<W>(program: &Program, state: &mut State, output: &mut W, index: &ConstantPoolIndex, arguments: &Arity) -> Result<()> where W: Write {
     let program_object = program.constant_pool.get(index)?;
     let name = program_object.as_str()?;
-    let function_index = state.frame_stack.functions.get(name)?;
+    if name == "heap_dump" && state.frame_stack.functions.get(name).is_err() {
+        return eval_heap_dump(program, state, output, arguments)
+    }
+    // A global variable holding a closure can be called like a function.
+    let function_index = match state.frame_stack.functions.get(name) {
+        Ok(function_index) => function_index,
+        Err(error) => match state.frame_stack.globals.get(name).ok().and_then(|pointer| pointer.as_heap_reference()) {
+            Some(index) if state.heap.dereference(index)?.as_closure_instance().is_ok() => {
+                let index = *index;
+                let argument_pointers = state.operand_stack.pop_sequence(arguments.to_usize())?;
+                return eval_call_closure(program, state, index, argument_pointers)
+            }
+            _ => return Err(error),
+        }
+    };
     let function = program.constant_pool.get(function_index)?;
 
     let parameters = function.get_method_parameters()?;                                      // FIXME perhaps the thing to do here is to have a Method struct inside the ProgramObject::Method constructor
@@ -452,6 +691,7 @@ pub fn eval_call_function(program: &Program, state: &mut State, index: &Constant
              "Function `{}` requires {} arguments, but {} were supplied",
              name, parameters, arguments);
 
+    state.ensure_frame_capacity(name)?;
     let argument_pointers = state.operand_stack.pop_sequence(arguments.to_usize())?;
     let local_pointers = locals.make_vector(Pointer::Null);
 
@@ -462,6 +702,18 @@ pub fn eval_call_function(program: &Program, state: &mut State, index: &Constant
     Ok(())
 }
 
+// Builtin `heap_dump()`: writes a JSON snapshot of the heap and its roots to the output, unless the
+// program defines its own function of that name.
+fn eval_heap_dump<W>(program: &Program, state: &mut State, output: &mut W, arguments: &Arity) -> Result<()> where W: Write {
+    bail_if!(arguments.to_usize() != 0,
+             "Function `heap_dump` requires 0 arguments, but {} were supplied", arguments);
+    let snapshot = HeapSnapshot::from(state);
+    writeln!(output, "{}", snapshot.to_json())?;
+    state.operand_stack.push(Pointer::Null);
+    state.instruction_pointer.bump(program);
+    Ok(())
+}
+
 #[inline(always)]
 pub fn eval_print<W>(program: &Program, state: &mut State, output: &mut W, index: &ConstantPoolIndex, arguments: &Arity) -> Result<()> where W: Write {
     let program_object = program.constant_pool.get(index)?;
@@ -531,6 +783,18 @@ pub fn eval_return(_program: &Program, state: &mut State) -> Result<()> {
     Ok(())
 }
 
+#[inline(always)]
+pub fn eval_closure(program: &Program, state: &mut State, function: &ConstantPoolIndex, captured: &Arity) -> Result<()> {
+    let method = program.constant_pool.get(function)?;
+    bail_if!(!method.is_method(), "Expecting a method to create a closure from, but found `{}`.", method);
+    let captured = state.operand_stack.pop_sequence(captured.to_usize())?;
+    let closure = HeapObject::Closure(ClosureInstance { function: *function, captured });
+    let heap_index = state.allocate(program, closure)?;
+    state.operand_stack.push(Pointer::from(heap_index));
+    state.instruction_pointer.bump(program);
+    Ok(())
+}
+
 #[inline(always)]
 pub fn eval_drop(program: &Program, state: &mut State) -> Result<()> {
     state.operand_stack.pop()?;
@@ -1,17 +1,33 @@
 use crate::parser::AST;
+use crate::parser::diagnostics::SourceFile;
 
 pub(crate) mod bytecode;
 pub(crate) mod compiler;
+pub(crate) mod assembler;
 pub(crate) mod debug;
+pub mod debug_info;
 pub mod program;
 pub mod serializable;
 pub mod interpreter;
 #[macro_use] mod helpers;
 pub mod heap;
+pub mod heap_log;
+pub mod heap_dump;
+pub mod stack_trace;
 pub mod state;
+pub mod session;
+pub mod verifier;
 
 use anyhow::Result;
 
 pub fn compile(ast: &AST) -> Result<program::Program> {
     compiler::compile(ast)
-}
\ No newline at end of file
+}
+
+pub fn compile_with_debug_info(ast: &AST, source: &SourceFile) -> Result<program::Program> {
+    compiler::compile_with_debug_info(ast, source)
+}
+
+pub fn assemble(text: &str) -> Result<program::Program> {
+    assembler::assemble(text)
+}
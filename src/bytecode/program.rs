@@ -1,7 +1,10 @@
-use std::io::{Write, Read};
-use std::collections::HashMap;
+use std::io::{Write, Read, Cursor};
+use std::collections::{HashMap, BTreeMap};
 
 use super::bytecode::OpCode;
+use crate::parser::Span;
+use crate::parser::diagnostics::SourceFile;
+use super::debug_info::{DebugInfo, FLAG_DEBUG_LINES, FLAG_DEBUG_LOCALS};
 
 use super::serializable;
 use super::serializable::*;
@@ -20,6 +23,7 @@ pub struct Program {
     pub code: Code,
     pub globals: Globals,
     pub entry: Entry,
+    pub debug: Option<DebugInfo>,
 }
 
 impl Program {
@@ -30,24 +34,89 @@ impl Program {
         let label_addresses = code.label_addresses().into_iter();
         let labels = Labels::from(label_constants.zip(label_addresses)).unwrap();
 
-        Ok(Program { labels, constant_pool, code, globals, entry })
+        Ok(Program { labels, constant_pool, code, globals, entry, debug: None })
+    }
+
+    // The innermost function or method whose code contains the address.
+    pub fn enclosing_method(&self, address: &Address) -> Option<&str> {
+        self.enclosing_method_definition(address)
+            .and_then(|(_, method)| method.get_method_name().ok())
+            .and_then(|name| self.constant_pool.get(name).ok())
+            .and_then(|name| name.as_str().ok())
+    }
+
+    pub fn enclosing_method_definition(&self, address: &Address) -> Option<(ConstantPoolIndex, &ProgramObject)> {
+        self.constant_pool.iter().enumerate()
+            .filter(|(_, object)| match object {
+                ProgramObject::Method { code, .. } => code.contains(address),
+                _ => false,
+            })
+            .min_by_key(|(_, object)| object.get_method_length().unwrap())
+            .map(|(index, object)| (ConstantPoolIndex::from_usize(index), object))
+    }
+
+    // Methods are members of some class, functions are not.
+    pub fn is_class_member(&self, method: &ConstantPoolIndex) -> bool {
+        self.constant_pool.iter().any(|object| match object {
+            ProgramObject::Class(members) => members.contains(method),
+            _ => false,
+        })
     }
 }
 
-impl std::fmt::Display for Program {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Program {
+    // Local variable accesses are annotated with the name of the variable, if the debug info has it.
+    pub fn describe_instruction(&self, address: Address, opcode: &OpCode) -> String {
+        let name = match opcode {
+            OpCode::GetLocal { index } | OpCode::SetLocal { index } => self.debug.as_ref()
+                .and_then(|debug| debug.local_name(address, index)),
+            _ => None,
+        };
+        match name {
+            Some(name) => format!("{} ({})", opcode, name),
+            None => opcode.to_string(),
+        }
+    }
+
+    // Like Display, but instructions with debug info are annotated with their source location and,
+    // if the source file is among `sources`, the text of the line.
+    pub fn disassemble(&self, f: &mut dyn std::fmt::Write, sources: &[SourceFile]) -> std::fmt::Result {
         writeln!(f, "Constant Pool:")?;
         write!(f, "{}", self.constant_pool)?;
         writeln!(f, "Entry: {}", self.entry)?;
         writeln!(f, "Globals:")?;
         write!(f, "{}", self.globals)?;
         writeln!(f, "Code:")?;
-        write!(f, "{}", self.code)?;
+        let debug = match &self.debug {
+            Some(debug) => debug,
+            None => return write!(f, "{}", self.code),
+        };
+        for (i, opcode) in self.code.0.iter().enumerate() {
+            let instruction = format!("{}: {}", i, self.describe_instruction(Address::from_usize(i), opcode));
+            match debug.locate(Address::from_usize(i)) {
+                None => writeln!(f, "{}", instruction)?,
+                Some(location) => {
+                    let text = sources.iter()
+                        .find(|source| source.name == location.file)
+                        .and_then(|source| source.line(location.line));
+                    match text {
+                        Some(text) => writeln!(f, "{:<32} // {} | {}", instruction, location, text.trim())?,
+                        None => writeln!(f, "{:<32} // {}", instruction, location)?,
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.disassemble(f, &[])
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
 pub struct Globals(Vec<ConstantPoolIndex>);
 impl Globals {
     pub fn new() -> Self { Globals(Vec::new()) }
@@ -77,7 +146,7 @@ impl std::fmt::Display for Globals {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone)]
 pub struct Entry(Option<ConstantPoolIndex>);
 impl Entry {
     pub fn new() -> Self { Entry(None) }
@@ -124,7 +193,7 @@ impl Labels {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone)]
 pub struct ConstantPool(Vec<ProgramObject>);
 impl ConstantPool {
     pub fn new() -> Self { ConstantPool(Vec::new()) }
@@ -282,6 +351,7 @@ impl ProgramObject {
             ProgramObject::Null => true,
             ProgramObject::Boolean(_) => true,
             ProgramObject::Integer(_) => true,
+            ProgramObject::String(_) => true,
             _ => false,
         }
     }
@@ -411,21 +481,23 @@ impl SerializableWithContext for ProgramObject {
         }
     }
 
-    fn from_bytes<R: Read>(input: &mut R, code: &mut Code) -> Self {                                // TODO error handling
-        let tag = serializable::read_u8(input);
-        match tag {
-            0x00 => ProgramObject::Integer(serializable::read_i32(input)),
+    fn from_bytes<R: Input>(input: &mut R, code: &mut Code) -> Result<Self> {
+        let offset = input.offset();
+        let tag = serializable::read_u8(input)?;
+        let object = match tag {
+            0x00 => ProgramObject::Integer(serializable::read_i32(input)?),
             0x01 => ProgramObject::Null,
-            0x02 => ProgramObject::String(serializable::read_utf8(input)),
-            0x03 => ProgramObject::Method { name: ConstantPoolIndex::from_bytes(input),
-                parameters: Arity::from_bytes(input),
-                locals: Size::from_bytes(input),
-                code: code.append(OpCode::read_opcode_vector(input))},
-            0x04 => ProgramObject::Slot { name: ConstantPoolIndex::from_bytes(input) },
-            0x05 => ProgramObject::Class(ConstantPoolIndex::read_cpi_vector(input)),
-            0x06 => ProgramObject::Boolean(serializable::read_bool(input)),
-            _    => panic!("Cannot deserialize value: unrecognized value tag: {}", tag)
-        }
+            0x02 => ProgramObject::String(serializable::read_utf8(input)?),
+            0x03 => ProgramObject::Method { name: ConstantPoolIndex::from_bytes(input)?,
+                parameters: Arity::from_bytes(input)?,
+                locals: Size::from_bytes(input)?,
+                code: code.append(OpCode::read_opcode_vector(input, code.upcoming_address())?)},
+            0x04 => ProgramObject::Slot { name: ConstantPoolIndex::from_bytes(input)? },
+            0x05 => ProgramObject::Class(ConstantPoolIndex::read_cpi_vector(input)?),
+            0x06 => ProgramObject::Boolean(serializable::read_bool(input)?),
+            tag  => bail!("Expected a constant tag at byte {}, found 0x{:02X}", offset, tag),
+        };
+        Ok(object)
     }
 }
 // FIXME error message should include parameter list:
@@ -476,10 +548,15 @@ impl ProgramObject {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub struct Code(Vec<OpCode>);
+// Source spans of instructions compiled from located AST nodes, keyed by address. They are
+// not part of the serialized format.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct SourceSpans(BTreeMap<usize, Span>);
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Code(Vec<OpCode>, SourceSpans);
 impl Code {
-    pub fn new() -> Self { Code(Vec::new()) }
+    pub fn new() -> Self { Code(Vec::new(), SourceSpans::default()) }
     pub fn upcoming_address(&self) -> Address {
         Address::from_usize(self.0.len())
     }
@@ -488,8 +565,22 @@ impl Code {
         let length = code.length();
         // println!("code {} {} {:?}", first, length, code.0);
         self.0.extend(code.0.into_iter());
+        let offset = first.value_usize();
+        self.1.0.extend(code.1.0.into_iter().map(|(address, span)| (address + offset, span)));
         (first, length)
     }
+    // Inner nodes are compiled first, so instructions that already have a span keep it.
+    pub fn annotate(&mut self, from: Address, span: Span) {
+        for address in from.value_usize()..self.0.len() {
+            self.1.0.entry(address).or_insert(span);
+        }
+    }
+    pub fn span(&self, address: Address) -> Option<Span> {
+        self.1.0.get(&address.value_usize()).cloned()
+    }
+    pub fn spans<'a>(&'a self) -> impl Iterator<Item=(Address, Span)> + 'a {
+        self.1.0.iter().map(|(address, span)| (Address::from_usize(*address), *span))
+    }
     pub fn emit(&mut self, opcode: OpCode) {
         self.0.push(opcode)
     }
@@ -500,6 +591,9 @@ impl Code {
     pub fn emit_unless(&mut self, opcode: OpCode, condition: bool) {
         if !condition { self.emit(opcode) }
     }
+    pub fn iter(&self) -> impl Iterator<Item=&OpCode> {
+        self.0.iter()
+    }
     #[allow(dead_code)]
     pub fn length(&self) -> usize {
         self.0.len()
@@ -554,7 +648,7 @@ impl Code {
 
 impl From<Vec<OpCode>> for Code {
     fn from(vector: Vec<OpCode>) -> Self {
-        Code(vector)
+        Code(vector, SourceSpans::default())
     }
 }
 
@@ -678,26 +772,154 @@ impl std::fmt::Display for Code {
 //
 
 
-impl Serializable for Program {
-    fn serialize<W: Write>(&self, sink: &mut W) -> anyhow::Result<()> {
+// Files are in the headerless format of Feeny unless they are written with a header. The header is
+// the magic bytes, the format version, the feature flags, and a checksum of the body that follows. No Feeny file can start with the magic bytes, since they would be read
+// as 19782 constants, the first of which has the unknown tag 0x4C.
+pub const MAGIC: [u8; 4] = *b"FMLB";
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_LENGTH: usize = 11;
+// Set if the code uses opcodes that Feeny does not have. The other flags are in `debug_info`.
+pub const FLAG_EXTENDED_OPCODES: u8 = 0x04;
+const KNOWN_FLAGS: u8 = FLAG_DEBUG_LINES | FLAG_DEBUG_LOCALS | FLAG_EXTENDED_OPCODES;
+
+impl Program {
+    pub fn flags(&self) -> u8 {
+        let debug = self.debug.as_ref().map_or(0, |debug| debug.flags());
+        let extended = if self.code.iter().any(|opcode| opcode.is_extended()) { FLAG_EXTENDED_OPCODES } else { 0 };
+        debug | extended
+    }
+
+    // With a header, debug info follows the entry point directly, since the flags are in the header.
+    pub fn serialize_with_header<W: Write>(&self, sink: &mut W) -> anyhow::Result<()> {
+        let mut body = Vec::new();
+        self.serialize_body(&mut body)?;
+        if let Some(debug) = &self.debug {
+            debug.serialize(&mut body)?;
+        }
+
+        sink.write_all(&MAGIC)?;
+        serializable::write_u16(sink, FORMAT_VERSION)?;
+        serializable::write_u8(sink, self.flags())?;
+        serializable::write_u32(sink, adler32(&body))?;
+        sink.write_all(&body)?;
+        Ok(())
+    }
+
+    fn serialize_body<W: Write>(&self, sink: &mut W) -> anyhow::Result<()> {
         self.constant_pool.serialize(sink, &self.code)?;
         self.globals.serialize(sink)?;
         self.entry.serialize(sink)
     }
 
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
+    fn from_legacy_bytes(input: &mut Cursor<&[u8]>) -> Result<Self> {
         let mut code = Code::new();
+        let (constant_pool, globals, entry) = Self::body_from_bytes(input, &mut code)?;
+
+        // Anything after the entry point starts with a flags byte.
+        let offset = input.offset();
+        let mut flags = [0u8; 1];
+        let has_flags = input.read(&mut flags)
+            .with_context(|| format!("Cannot read flags at byte {}", offset))? == 1;
+        let debug = Self::debug_from_bytes(input, if has_flags { flags[0] } else { 0 })?;
+
+        Self::assemble(constant_pool, code, globals, entry, debug)
+    }
+
+    fn from_bytes_with_header(input: &mut Cursor<&[u8]>) -> Result<Self> {
+        let bytes = *input.get_ref();
+        bail_if!(bytes.len() < HEADER_LENGTH,
+                 "Expected a header of {} bytes, found the end of the input at byte {}", HEADER_LENGTH, bytes.len());
+        input.set_position(MAGIC.len() as u64);
+        let version = serializable::read_u16(input)?;
+        let flags = serializable::read_u8(input)?;
+        let checksum = serializable::read_u32(input)?;
+
+        bail_if!(version == 0, "Unknown bytecode format version {}", version);
+        bail_if!(version > FORMAT_VERSION,
+                 "Bytecode format version {} is newer than this version of fml can read, which is up to version {}",
+                 version, FORMAT_VERSION);
+        bail_if!(flags & !KNOWN_FLAGS != 0,
+                 "Bytecode uses features this version of fml does not know (flags 0x{:02X})", flags & !KNOWN_FLAGS);
+        let actual = adler32(&bytes[HEADER_LENGTH..]);
+        bail_if!(actual != checksum,
+                 "Bytecode is corrupt: the checksum in the header is 0x{:08X}, but the contents sum to 0x{:08X}",
+                 checksum, actual);
 
-        let constant_pool = ConstantPool::from_bytes(input, &mut code);
-        let globals = Globals::from_bytes(input);
-        let entry = Entry::from_bytes(input);
+        let mut code = Code::new();
+        let (constant_pool, globals, entry) = Self::body_from_bytes(input, &mut code)?;
+        let debug = Self::debug_from_bytes(input, flags)?;
+        bail_if!(input.offset() < bytes.len(),
+                 "Expected the end of the bytecode at byte {}, found {} more bytes", input.offset(), bytes.len() - input.offset());
 
+        Self::assemble(constant_pool, code, globals, entry, debug)
+    }
+
+    fn body_from_bytes(input: &mut Cursor<&[u8]>, code: &mut Code) -> Result<(ConstantPool, Globals, Entry)> {
+        let constant_pool = ConstantPool::from_bytes(input, code)?;
+        let offset = input.offset();
+        let globals = Globals::from_bytes(input)
+            .with_context(|| format!("Cannot read globals at byte {}", offset))?;
+        let offset = input.offset();
+        let entry = Entry::from_bytes(input)
+            .with_context(|| format!("Cannot read the entry point at byte {}", offset))?;
+        Ok((constant_pool, globals, entry))
+    }
+
+    fn debug_from_bytes(input: &mut Cursor<&[u8]>, flags: u8) -> Result<Option<DebugInfo>> {
+        if flags & (FLAG_DEBUG_LINES | FLAG_DEBUG_LOCALS) == 0 {
+            return Ok(None)
+        }
+        let offset = input.offset();
+        let debug = DebugInfo::from_bytes(input, flags)
+            .with_context(|| format!("Cannot read debug info at byte {}", offset))?;
+        Ok(Some(debug))
+    }
+
+    fn assemble(constant_pool: ConstantPool, code: Code, globals: Globals, entry: Entry, debug: Option<DebugInfo>) -> Result<Self> {
         let label_names = code.labels();
-        let label_constants = constant_pool.get_all(label_names).unwrap().into_iter();                // TODO error handling
+        let label_constants = constant_pool.get_all(label_names)
+            .context("Cannot find the names of labels")?.into_iter();
         let label_addresses = code.label_addresses().into_iter();
-        let labels = Labels::from(label_constants.zip(label_addresses)).unwrap();
+        let labels = Labels::from(label_constants.zip(label_addresses))
+            .context("Cannot find the names of labels")?;
 
-        Program { constant_pool, labels, code, globals, entry }
+        Ok(Program { constant_pool, labels, code, globals, entry, debug })
+    }
+}
+
+// Adler-32, as in zlib.
+fn adler32(bytes: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % MODULUS;
+        (a, (b + a) % MODULUS)
+    });
+    (b << 16) | a
+}
+
+impl Serializable for Program {
+    // The headerless Feeny format, where debug info, if any, follows a flags byte after the entry
+    // point.
+    fn serialize<W: Write>(&self, sink: &mut W) -> anyhow::Result<()> {
+        self.serialize_body(sink)?;
+        if let Some(debug) = &self.debug {
+            serializable::write_u8(sink, debug.flags())?;
+            debug.serialize(sink)?;
+        }
+        Ok(())
+    }
+
+    // Reads the whole input first, to check it against the checksum before decoding it.
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)
+            .context("Cannot read bytecode")?;
+        let mut cursor = Cursor::new(bytes.as_slice());
+        if bytes.starts_with(&MAGIC) {
+            Self::from_bytes_with_header(&mut cursor)
+        } else {
+            Self::from_legacy_bytes(&mut cursor)
+        }
     }
 }
 
@@ -709,12 +931,17 @@ impl SerializableWithContext for ConstantPool {
             .collect()
     }
 
-    fn from_bytes<R: Read>(input: &mut R, code: &mut Code) -> Self {
-        let size = serializable::read_u16_as_usize(input);
-        let constants: Vec<ProgramObject> =
-            (0..size).map(|_| ProgramObject::from_bytes(input, code)).collect();
+    fn from_bytes<R: Input>(input: &mut R, code: &mut Code) -> Result<Self> {
+        let size = serializable::read_u16_as_usize(input)?;
+        let constants = (0..size)
+            .map(|index| {
+                let offset = input.offset();
+                ProgramObject::from_bytes(input, code)
+                    .with_context(|| format!("Cannot read constant #{} at byte {}", index, offset))
+            })
+            .collect::<Result<Vec<ProgramObject>>>()?;
 
-        ConstantPool(constants)
+        Ok(ConstantPool(constants))
     }
 }
 
@@ -723,8 +950,8 @@ impl Serializable for Globals {
         ConstantPoolIndex::write_cpi_vector(sink, &self.0)
     }
 
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        Globals(ConstantPoolIndex::read_cpi_vector(input))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        ConstantPoolIndex::read_cpi_vector(input).map(Globals)
     }
 }
 
@@ -733,8 +960,8 @@ impl Serializable for Entry {
         self.0.expect("Cannot serialize an empty entry point.").serialize(sink)
     }
 
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        Entry(Some(ConstantPoolIndex::from_bytes(input)))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        ConstantPoolIndex::from_bytes(input).map(|index| Entry(Some(index)))
     }
 }
 
@@ -839,14 +1066,21 @@ impl AddressRange {
     pub fn length(&self) -> usize {
         self.length
     }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        let address = address.value_usize();
+        let start = self.start.value_usize();
+        address >= start && address < start + self.length
+    }
 }
 
 impl ConstantPoolIndex {
-    pub fn read_cpi_vector<R: Read>(input: &mut R) -> Vec<ConstantPoolIndex> {
-        serializable::read_u16_vector(input)
+    pub fn read_cpi_vector<R: Input>(input: &mut R) -> Result<Vec<ConstantPoolIndex>> {
+        let vector = serializable::read_u16_vector(input)?
             .into_iter()
             .map(ConstantPoolIndex::new)
-            .collect()
+            .collect();
+        Ok(vector)
     }
 
     pub fn write_cpi_vector<R: Write>(sink: &mut R, vector: &Vec<ConstantPoolIndex>) -> anyhow::Result<()> {
@@ -887,8 +1121,8 @@ impl Serializable for Arity {
         serializable::write_u8(sink, self.0)
     }
 
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        Arity(serializable::read_u8(input))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        serializable::read_u8(input).map(Arity)
     }
 }
 
@@ -901,10 +1135,11 @@ impl Arity {
     }
 
     #[allow(dead_code)]
-    pub fn from_bytes_minus_one<R: Read>(input: &mut R) -> Self {
-        let value = serializable::read_u8(input);
-        assert!(value > 0);
-        Arity(value - 1)
+    pub fn from_bytes_minus_one<R: Input>(input: &mut R) -> Result<Self> {
+        let offset = input.offset();
+        let value = serializable::read_u8(input)?;
+        bail_if!(value == 0, "Expected an arity plus one at byte {}, found 0", offset);
+        Ok(Arity(value - 1))
     }
 }
 
@@ -914,8 +1149,8 @@ impl Serializable for Size {
         serializable::write_u16(sink, self.0)
     }
 
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        Size(serializable::read_u16(input))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        serializable::read_u16(input).map(Size)
     }
 }
 
@@ -923,8 +1158,8 @@ impl Serializable for Address {
     fn serialize<W: Write> (&self, sink: &mut W) -> anyhow::Result<()> {
         serializable::write_u32(sink, self.0)
     }
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        Address(serializable::read_u32(input))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        serializable::read_u32(input).map(Address)
     }
 }
 
@@ -932,8 +1167,8 @@ impl Serializable for ConstantPoolIndex {
     fn serialize<W: Write> (&self, sink: &mut W) -> anyhow::Result<()> {
         serializable::write_u16(sink, self.0)
     }
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        ConstantPoolIndex(serializable::read_u16(input))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        serializable::read_u16(input).map(ConstantPoolIndex)
     }
 }
 
@@ -941,8 +1176,8 @@ impl Serializable for LocalFrameIndex {
     fn serialize<W: Write> (&self, sink: &mut W) -> anyhow::Result<()> {
         serializable::write_u16(sink, self.0)
     }
-    fn from_bytes<R: Read>(input: &mut R) -> Self {
-        LocalFrameIndex(serializable::read_u16(input))
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> {
+        serializable::read_u16(input).map(LocalFrameIndex)
     }
 }
 
@@ -1,88 +1,129 @@
-use std::io::{Read, Write};
+use std::io::{Read, Write, Cursor};
 
 use super::program::Code;
 
 use anyhow::*;
 
+use crate::bail_if;
+
 pub trait SerializableWithContext {
     fn serialize<W: Write> (&self, sink: &mut W, code: &Code) -> Result<()>;
-    fn from_bytes<R: Read>(input: &mut R, code: &mut Code) -> Self;
+    fn from_bytes<R: Input>(input: &mut R, code: &mut Code) -> Result<Self> where Self: Sized;
 }
 
 pub trait Serializable {
     fn serialize<W: Write> (&self, sink: &mut W) -> Result<()>;
-    fn from_bytes<R: Read>(input: &mut R) -> Self;
+    fn from_bytes<R: Input>(input: &mut R) -> Result<Self> where Self: Sized;
+}
+
+// A reader that knows how far into the data it is, so that errors can say where they happened.
+pub trait Input: Read {
+    fn offset(&self) -> usize;
+}
+
+impl<T> Input for Cursor<T> where T: AsRef<[u8]> {
+    fn offset(&self) -> usize {
+        self.position() as usize
+    }
+}
+
+// Counts the bytes read from any reader, like a file or standard input.
+pub struct OffsetReader<R> {
+    reader: R,
+    offset: usize,
+}
+
+impl<R> OffsetReader<R> where R: Read {
+    pub fn new(reader: R) -> Self {
+        OffsetReader { reader, offset: 0 }
+    }
 }
 
-pub fn read_u8<R: Read>(reader: &mut R) -> u8 {
+impl<R> Read for OffsetReader<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.reader.read(buf)?;
+        self.offset += length;
+        Ok(length)
+    }
+}
+
+impl<R> Input for OffsetReader<R> where R: Read {
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+fn read_exact<R: Input>(reader: &mut R, buf: &mut [u8], expected: &str) -> Result<()> {
+    let offset = reader.offset();
+    reader.read_exact(buf)
+        .map_err(|_| anyhow!("Expected {} at byte {}, found the end of the input", expected, offset))
+}
+
+pub fn read_u8<R: Input>(reader: &mut R) -> Result<u8> {
     let mut buf = [0u8; 1];
-    reader.read_exact(&mut buf).expect("Problem reading u8 from data stream");
-    u8::from_le_bytes(buf)
+    read_exact(reader, &mut buf, "a u8")?;
+    Ok(u8::from_le_bytes(buf))
 }
 
-pub fn read_bool<R: Read>(reader: &mut R) -> bool {
-    match read_u8(reader) {
-        0 => false,
-        1 => true,
-        n => panic!("Problem reading boolfrom data stream: unrecognized value: {}", n),
+pub fn read_bool<R: Input>(reader: &mut R) -> Result<bool> {
+    let offset = reader.offset();
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        n => bail!("Expected a boolean (0 or 1) at byte {}, found 0x{:02X}", offset, n),
     }
 }
 
-pub fn read_u16<R: Read>(reader: &mut R) -> u16 {
+pub fn read_u16<R: Input>(reader: &mut R) -> Result<u16> {
     let mut buf = [0u8; 2];
-    reader.read_exact(&mut buf).expect("Problem reading u16 from data stream");
-    u16::from_le_bytes(buf)
+    read_exact(reader, &mut buf, "a u16")?;
+    Ok(u16::from_le_bytes(buf))
 }
 
-pub fn read_u32<R: Read>(reader: &mut R) -> u32 {
+pub fn read_u32<R: Input>(reader: &mut R) -> Result<u32> {
     let mut buf = [0u8; 4];
-    reader.read_exact(&mut buf).expect("Problem reading u32 from data stream");
-    u32::from_le_bytes(buf)
+    read_exact(reader, &mut buf, "a u32")?;
+    Ok(u32::from_le_bytes(buf))
 }
 
-pub fn read_i32<R: Read>(reader: &mut R) -> i32 {
+pub fn read_i32<R: Input>(reader: &mut R) -> Result<i32> {
     let mut buf = [0u8; 4];
-    reader.read_exact(&mut buf).expect("Problem reading i32 from data stream");
-    i32::from_le_bytes(buf)
+    read_exact(reader, &mut buf, "an i32")?;
+    Ok(i32::from_le_bytes(buf))
 }
 
-pub fn read_utf8<R: Read>(reader: &mut R) -> String {
-    let length = read_u32_as_usize(reader);
-    let mut bytes = vec![0u8; length];
-    for i in 0..length {
-        bytes[i] = read_u8(reader);
-    }
-    String::from_utf8(bytes).expect(&format!("Problem reading UTF-8 string of size {} \
-                                              from data sink", length))
+pub fn read_utf8<R: Input>(reader: &mut R) -> Result<String> {
+    let length = read_u32_as_usize(reader)?;
+    let offset = reader.offset();
+    // The length comes from the input, so do not trust it with an allocation up front.
+    let mut bytes = Vec::new();
+    reader.by_ref().take(length as u64).read_to_end(&mut bytes)?;
+    bail_if!(bytes.len() < length,
+             "Expected a string of {} bytes at byte {}, found the end of the input after {}", length, offset, bytes.len());
+    String::from_utf8(bytes)
+        .map_err(|error| anyhow!("Expected a UTF-8 string at byte {}, found an invalid byte at byte {}",
+                                 offset, offset + error.utf8_error().valid_up_to()))
 }
 
-pub fn read_u16_vector<R: Read>(reader: &mut R) -> Vec<u16> {
-    let length = read_u16_as_usize(reader);
-    let mut ints = vec![0u16; length];
-    for i in 0..length {
-        ints[i] = read_u16(reader);
-    }
-    ints
+pub fn read_u16_vector<R: Input>(reader: &mut R) -> Result<Vec<u16>> {
+    let length = read_u16_as_usize(reader)?;
+    (0..length).map(|_| read_u16(reader)).collect()
 }
 
 #[allow(dead_code)]
-pub fn read_u32_vector<R: Read>(reader: &mut R) -> Vec<u32> {
-    let length = read_u16_as_usize(reader);
-    let mut ints = vec![0u32; length];
-    for i in 0..length {
-        ints[i] = read_u32(reader);
-    }
-    ints
+pub fn read_u32_vector<R: Input>(reader: &mut R) -> Result<Vec<u32>> {
+    let length = read_u16_as_usize(reader)?;
+    (0..length).map(|_| read_u32(reader)).collect()
 }
 
 // Reads u16 and converts it to usize, for compatibility between Rust types and Feeny bytecode.
-pub fn read_u16_as_usize<R: Read>(reader: &mut R) -> usize {
-    read_u16(reader) as usize
+pub fn read_u16_as_usize<R: Input>(reader: &mut R) -> Result<usize> {
+    read_u16(reader).map(|value| value as usize)
 }
 
 // Reads u32 and converts it to usize, for compatibility between Rust types and Feeny bytecode.
-pub fn read_u32_as_usize<R: Read>(reader: &mut R) -> usize {
-    read_u32(reader) as usize
+pub fn read_u32_as_usize<R: Input>(reader: &mut R) -> Result<usize> {
+    read_u32(reader).map(|value| value as usize)
 }
 
 pub fn write_u8<W: Write>(writer: &mut W, value: u8) -> Result<()> {
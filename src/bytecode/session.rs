@@ -0,0 +1,93 @@
+use std::fmt::Write;
+
+use anyhow::*;
+
+use crate::bytecode::compiler::{Compiled, Environment, Frame, ProgramGenerator};
+use crate::bytecode::debug_info::DebugInfo;
+use crate::bytecode::heap::Pointer;
+use crate::bytecode::interpreter::evaluate_with;
+use crate::bytecode::program::{Code, Program};
+use crate::bytecode::state::State;
+use crate::parser::AST;
+use crate::parser::diagnostics::{SourceFile, ParseDiagnostic};
+
+// A program that grows one top-level input at a time, as in the REPL. Each input is compiled into
+// a new entry method that extends the program compiled so far, and runs against the same state,
+// so globals, functions and heap objects survive from one input to the next.
+//
+// Inputs are appended to a transcript and parsed with spans offset by its length, so errors can
+// point into any earlier input.
+pub struct Session {
+    program: ProgramGenerator,
+    environment: Environment,
+    state: State,
+    pub transcript: SourceFile,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            program: ProgramGenerator::new(),
+            environment: Environment::new(),
+            state: State::new(),
+            transcript: SourceFile::new("<repl>", ""),
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // The transcript with `input` appended, which is what the spans of a parsed input refer to.
+    pub fn pending(&self, input: &str) -> SourceFile {
+        SourceFile::new(self.transcript.name.clone(), format!("{}{}", self.transcript.text, input))
+    }
+
+    pub fn parse(&self, input: &str) -> std::result::Result<AST, Vec<ParseDiagnostic>> {
+        let offset = self.transcript.text.len();
+        crate::parser::parse_with_spans(input)
+            .map(|ast| ast.shift_locations(offset))
+            .map_err(|diagnostics| diagnostics.into_iter()
+                .map(|diagnostic| diagnostic.shifted(offset))
+                .collect())
+    }
+
+    // Compiles an input without adding it to the session.
+    pub fn compile(&self, input: &str, ast: &AST) -> Result<(ProgramGenerator, Environment, Program)> {
+        let mut program = self.program.clone();
+        let mut environment = self.environment.clone();
+        ast.compile_into(&mut program, &mut Code::new(), &mut environment, &mut Frame::Top, true)?;
+
+        let mut materialized = program.clone().materialize()?;
+        let debug = DebugInfo::from_spans(&materialized.code, &self.pending(input))
+            .with_locals(program.local_names.clone());
+        materialized.debug = Some(debug);
+        Ok((program, environment, materialized))
+    }
+
+    // Compiles and runs an input, and renders the value of its last expression, unless it has none
+    // or it is null, like the result of `print` or of a function definition.
+    // An input that fails to compile leaves the session as it was. One that fails at runtime
+    // stays, along with whatever it did before the error.
+    pub fn evaluate<W>(&mut self, input: &str, ast: &AST, output: &mut W) -> Result<Option<String>> where W: Write {
+        let (program, environment, materialized) = self.compile(input, ast)?;
+        self.state.enter(&materialized)?;
+
+        self.program = program;
+        self.environment = environment;
+        self.transcript.text.push_str(input);
+
+        evaluate_with(&materialized, &mut self.state, output)?;
+
+        let heap = &self.state.heap;
+        self.state.operand_stack.pop().ok()
+            .filter(|pointer| *pointer != Pointer::Null)
+            .map(|pointer| pointer.evaluate_as_string(heap))
+            .transpose()
+    }
+
+    // The program as compiled so far.
+    pub fn program(&self) -> Result<Program> {
+        self.program.clone().materialize()
+    }
+}
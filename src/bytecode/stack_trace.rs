@@ -0,0 +1,129 @@
+use crate::bytecode::heap::{Heap, HeapObject, Pointer};
+use crate::bytecode::program::{Address, Program, ProgramObject};
+use crate::bytecode::state::State;
+
+// Attached as context to runtime errors, so that hosts and the CLI can find it in the error chain.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct StackTrace { pub entries: Vec<TraceEntry> }
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct TraceEntry {
+    pub function: Option<String>,
+    pub receiver: Option<String>,
+    pub arguments: Vec<String>,
+    pub address: Option<Address>,
+}
+
+// Arguments are rendered shallowly: printing whole object graphs could be huge or even cyclic.
+const MAX_RENDERED_ELEMENTS: usize = 5;
+
+// Deep recursion (e.g. a stack overflow) only shows both ends of the trace.
+const MAX_LEADING_ENTRIES: usize = 16;
+const MAX_TRAILING_ENTRIES: usize = 4;
+
+fn render(pointer: &Pointer, heap: &Heap, depth: usize) -> String {
+    let index = match pointer {
+        Pointer::Reference(index) => index,
+        pointer => return pointer.to_string(),
+    };
+    match heap.dereference(index) {
+        Err(_) => format!("<dangling {}>", index),
+        Ok(HeapObject::String(string)) => format!("{:?}", string),
+        Ok(HeapObject::Closure(_)) => "<function>".to_owned(),
+        Ok(HeapObject::Array(_)) if depth > 0 => "[..]".to_owned(),
+        Ok(HeapObject::Object(_)) if depth > 0 => "object(..)".to_owned(),
+        Ok(HeapObject::Array(array)) => {
+            let mut elements: Vec<String> = array.iter().take(MAX_RENDERED_ELEMENTS)
+                .map(|element| render(element, heap, depth + 1))
+                .collect();
+            if array.length() > MAX_RENDERED_ELEMENTS {
+                elements.push("..".to_owned())
+            }
+            format!("[{}]", elements.join(", "))
+        }
+        Ok(HeapObject::Object(object)) => {
+            let mut fields: Vec<String> = object.fields.iter().take(MAX_RENDERED_ELEMENTS)
+                .map(|(name, value)| format!("{}={}", name, render(value, heap, depth + 1)))
+                .collect();
+            if object.fields.len() > MAX_RENDERED_ELEMENTS {
+                fields.push("..".to_owned())
+            }
+            format!("object({})", fields.join(", "))
+        }
+    }
+}
+
+impl StackTrace {
+    // The innermost frame runs the method containing the instruction pointer, every other frame
+    // runs the method containing the call that pushed the frame above it.
+    pub fn from(program: &Program, state: &State) -> Self {
+        let frames: Vec<_> = state.frame_stack.iter().collect();
+        let mut address = state.instruction_pointer.get();
+        let mut entries = Vec::new();
+
+        for (depth, frame) in frames.iter().enumerate().rev() {
+            // A call that is the last instruction of the program has no return address, but the only
+            // such call is the one made by the entry function, which runs in the outermost frame.
+            let method = match address.as_ref() {
+                Some(address) => program.enclosing_method_definition(address),
+                None if depth == 0 => program.entry.get().ok()
+                    .and_then(|index| program.constant_pool.get(&index).ok().map(|method| (index, method))),
+                None => None,
+            };
+            let (function, parameters, is_method) = match method {
+                Some((index, ProgramObject::Method { name, parameters, .. })) => {
+                    let name = program.constant_pool.get(name).ok()
+                        .and_then(|name| name.as_str().ok())
+                        .map(|name| name.to_owned());
+                    (name, parameters.to_usize(), program.is_class_member(&index))
+                }
+                _ => (None, 0, false),
+            };
+
+            let mut arguments: Vec<String> = frame.iter().take(parameters)
+                .map(|pointer| render(pointer, &state.heap, 0))
+                .collect();
+            let receiver = if is_method && !arguments.is_empty() {
+                Some(arguments.remove(0))
+            } else {
+                None
+            };
+
+            entries.push(TraceEntry { function, receiver, arguments, address });
+            address = frame.return_address.map(|address| Address::from_usize(address.value_usize() - 1));
+        }
+        StackTrace { entries }
+    }
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.function, &self.receiver) {
+            (Some(name), _) if name == "λ:" => write!(f, "<top level>")?,
+            (Some(name), Some(receiver)) => write!(f, "{}.{}({})", receiver, name, self.arguments.join(", "))?,
+            (Some(name), None) => write!(f, "{}({})", name, self.arguments.join(", "))?,
+            (None, _) => write!(f, "<unknown>")?,
+        }
+        if let Some(address) = self.address {
+            write!(f, " at {}", address)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Stack trace (most recent call first):")?;
+        let omitted = self.entries.len().saturating_sub(MAX_LEADING_ENTRIES + MAX_TRAILING_ENTRIES);
+        for (position, entry) in self.entries.iter().enumerate() {
+            if omitted > 0 && position >= MAX_LEADING_ENTRIES && position < MAX_LEADING_ENTRIES + omitted {
+                if position == MAX_LEADING_ENTRIES {
+                    writeln!(f, "  ... {} frames omitted ...", omitted)?;
+                }
+                continue
+            }
+            writeln!(f, "  in {}", entry)?;
+        }
+        Ok(())
+    }
+}
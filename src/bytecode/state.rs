@@ -1,5 +1,6 @@
 use crate::bytecode::program::*;
 use crate::bytecode::heap::*;
+use crate::bytecode::heap_log::AllocationSite;
 use std::collections::{HashMap, HashSet};
 
 use anyhow::*;
@@ -57,6 +58,15 @@ impl OperandStack {
     pub fn pop_reverse_sequence(&mut self, n: usize) -> Result<Vec<Pointer>> {
         (0..n).map(|_| self.pop()).collect::<Result<Vec<Pointer>>>()
     }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn iter(&self) -> impl Iterator<Item=&Pointer> {
+        self.0.iter()
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut Pointer> {
+        self.0.iter_mut()
+    }
 }
 
 impl From<Vec<Pointer>> for OperandStack {
@@ -65,17 +75,19 @@ impl From<Vec<Pointer>> for OperandStack {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub struct Frame { pub(crate) return_address: Option<Address>, locals: Vec<Pointer> }
+// A frame is remembered when it may hold pointers into the nursery, so minor collections have to
+// scan it. New frames start out remembered, since their arguments can be young.
+#[derive(Debug)]
+pub struct Frame { pub(crate) return_address: Option<Address>, locals: Vec<Pointer>, remembered: bool }
 impl Frame {
     pub fn new() -> Self {
-        Frame { locals: Vec::new(), return_address: None }
+        Frame { locals: Vec::new(), return_address: None, remembered: true }
     }
     pub fn with_capacity(return_address: Option<Address>, size: usize, initial: Pointer) -> Self {
-        Frame { locals: (0..size).map(|_| initial.clone()).collect(), return_address }
+        Frame { locals: (0..size).map(|_| initial.clone()).collect(), return_address, remembered: true }
     }
     pub fn from(return_address: Option<Address>, locals: Vec<Pointer>) -> Self {
-        Frame { locals, return_address }
+        Frame { locals, return_address, remembered: true }
     }
     pub fn get(&self, index: &LocalFrameIndex) -> Result<&Pointer> {
         let index = index.value() as usize;
@@ -92,6 +104,22 @@ impl Frame {
         self.locals[index] = pointer;
         Ok(())
     }
+    pub fn remember(&mut self) {
+        self.remembered = true
+    }
+    pub fn iter(&self) -> impl Iterator<Item=&Pointer> {
+        self.locals.iter()
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut Pointer> {
+        self.locals.iter_mut()
+    }
+}
+
+impl Eq for Frame {}
+impl PartialEq for Frame {
+    fn eq(&self, other: &Self) -> bool {
+        self.return_address == other.return_address && self.locals == other.locals
+    }
 }
 
 #[derive(Eq, PartialEq, Debug)]
@@ -109,6 +137,9 @@ impl FrameStack {
     pub fn push(&mut self, frame: Frame) {
         self.frames.push(frame)
     }
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
     pub fn get_locals(&self) -> Result<&Frame> {
         self.frames.last()
             .with_context(|| format!("Attempting to access frame from empty stack."))
@@ -117,6 +148,33 @@ impl FrameStack {
         self.frames.last_mut()
             .with_context(|| format!("Attempting to access frame from empty stack."))
     }
+    pub fn iter(&self) -> impl Iterator<Item=&Frame> {
+        self.frames.iter()
+    }
+    pub fn clear(&mut self) {
+        self.frames.clear()
+    }
+    pub fn pointers(&self) -> impl Iterator<Item=&Pointer> {
+        let locals = self.frames.iter().flat_map(|frame| frame.iter());
+        locals.chain(self.globals.iter())
+    }
+    pub fn pointers_mut(&mut self, collection: Collection) -> impl Iterator<Item=&mut Pointer> {
+        let all = collection == Collection::Major;
+        let locals = self.frames.iter_mut()
+            .filter(move |frame| all || frame.remembered)
+            .flat_map(|frame| frame.iter_mut());
+        let globals = Some(&mut self.globals)
+            .filter(|globals| all || globals.remembered)
+            .into_iter()
+            .flat_map(|globals| globals.iter_mut());
+        locals.chain(globals)
+    }
+    pub fn update_remembered<F>(&mut self, is_young: F) where F: Fn(&Pointer) -> bool {
+        for frame in self.frames.iter_mut() {
+            frame.remembered = frame.locals.iter().any(&is_young);
+        }
+        self.globals.remembered = self.globals.globals.values().any(&is_young);
+    }
 }
 
 impl From<(GlobalFrame, GlobalFunctions)> for FrameStack {
@@ -155,6 +213,9 @@ impl GlobalFunctions {
         bail_if!(result.is_some(), "Cannot define function `{}`: already defined.", name);
         Ok(())
     }
+    pub fn redefine(&mut self, name: String, index: ConstantPoolIndex) {
+        self.0.insert(name, index);
+    }
     pub fn from(methods: Vec<(String, ConstantPoolIndex)>) -> Result<Self> {
         let mut unique = HashSet::new();
         let functions = methods.into_iter()
@@ -170,23 +231,23 @@ impl GlobalFunctions {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
-pub struct GlobalFrame(HashMap<String, Pointer>);
+#[derive(Debug)]
+pub struct GlobalFrame { globals: HashMap<String, Pointer>, remembered: bool }
 impl GlobalFrame {
-    pub fn new() -> Self { GlobalFrame(HashMap::new()) }
+    pub fn new() -> Self { GlobalFrame { globals: HashMap::new(), remembered: true } }
     pub fn get(&self, name: &str) -> Result<&Pointer> {
-        self.0.get(name)
+        self.globals.get(name)
             .with_context(|| format!("No such global `{}`.", name))
     }
     #[allow(dead_code)]
     pub fn update(&mut self, name: String, pointer: Pointer) -> Result<()> {
-        let result = self.0.insert(name.clone(), pointer);
+        let result = self.globals.insert(name.clone(), pointer);
         bail_if!(result.is_none(), "No such global `{}`.", name);
         Ok(())
     }
     #[allow(dead_code)]
     pub fn define(&mut self, name: String, pointer: Pointer) -> Result<()> {
-        let result = self.0.insert(name.clone(), pointer);
+        let result = self.globals.insert(name.clone(), pointer);
         bail_if!(result.is_some(), "Cannot define global `{}`: already defined.", name);
         Ok(())
     }
@@ -201,16 +262,68 @@ impl GlobalFrame {
                 }
             })
             .collect::<Result<HashMap<String, Pointer>>>()?;
-        Ok(GlobalFrame(globals))
+        Ok(GlobalFrame { globals, remembered: true })
+    }
+    pub fn remember(&mut self) {
+        self.remembered = true
+    }
+    pub fn iter(&self) -> impl Iterator<Item=&Pointer> {
+        self.globals.values()
+    }
+    pub fn iter_named(&self) -> impl Iterator<Item=(&String, &Pointer)> {
+        self.globals.iter()
     }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut Pointer> {
+        self.globals.values_mut()
+    }
+}
+
+impl Eq for GlobalFrame {}
+impl PartialEq for GlobalFrame {
+    fn eq(&self, other: &Self) -> bool {
+        self.globals == other.globals
+    }
+}
+
+// Zero means unlimited.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct StackLimits { pub max_frame_depth: usize, pub max_operands: usize }
+
+impl Default for StackLimits {
+    fn default() -> Self {
+        StackLimits { max_frame_depth: 10_000, max_operands: 100_000 }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum StackOverflow {
+    Frames { function: String, depth: usize, limit: usize },
+    Operands { function: String, size: usize, limit: usize },
 }
 
+impl std::fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StackOverflow::Frames { function, depth, limit } =>
+                write!(f, "Stack overflow: cannot call `{}` at frame depth {}, the maximum frame \
+                           depth is {}", function, depth, limit),
+            StackOverflow::Operands { function, size, limit } =>
+                write!(f, "Operand stack overflow in `{}`: {} operands exceed the maximum operand \
+                           stack size of {}", function, size, limit),
+        }
+    }
+}
+
+impl std::error::Error for StackOverflow {}
+
 #[derive(Debug)]
 pub struct State {
     pub operand_stack: OperandStack,
     pub frame_stack: FrameStack,
     pub instruction_pointer: InstructionPointer,
-    pub heap: Heap
+    pub heap: Heap,
+    pub limits: StackLimits,
+    pub instruction_count: u64,
 }
 
 // pub struct State {
@@ -222,6 +335,61 @@ pub struct State {
 //     pub memory: Heap,
 // }
 
+fn entry_point(program: &Program) -> Result<(InstructionPointer, Frame)> {
+    let entry_index = program.entry.get()
+        .with_context(|| format!("Cannot find entry method."))?;
+    let entry_method = program.constant_pool.get(&entry_index)
+        .with_context(|| format!("Cannot find entry method."))?;
+    let entry_address = entry_method.get_method_start_address()?;
+    let entry_length = entry_method.get_method_length()?;
+    let entry_locals = entry_method.get_method_locals()?;
+
+    let instruction_pointer = if entry_length > 0 {
+        InstructionPointer::from(*entry_address)
+    } else {
+        InstructionPointer::new()
+    };
+    Ok((instruction_pointer, Frame::with_capacity(None, entry_locals.to_usize(), Pointer::Null)))
+}
+
+// The names of global variables and of global functions with their methods, in definition order.
+fn global_definitions(program: &Program) -> Result<(Vec<String>, Vec<(String, ConstantPoolIndex)>)> {
+    let global_objects = program.globals.iter()
+        .map(|index| {
+            program.constant_pool.get(&index).map(|object| (index, object))
+        })
+        .collect::<Result<Vec<(ConstantPoolIndex, &ProgramObject)>>>()?;
+
+    ensure!(global_objects.iter().all(|(_, object)| object.is_slot() || object.is_method()),
+            "Illegal global constant: expecting Method or Slot.");
+
+    fn extract_slot(program: &Program, slot: &ProgramObject) -> Result<String> {
+        let name_index = slot.as_slot_index()?;
+        let name_object = program.constant_pool.get(name_index)?;
+        let name = name_object.as_str()?;
+        Ok(name.to_owned())
+    }
+
+    let globals = global_objects.iter()
+        .filter(|(_, program_object)| program_object.is_slot())
+        .map(|(_, slot)| extract_slot(program, slot))
+        .collect::<Result<Vec<String>>>()?;
+
+    fn extract_function(program: &Program, index: &ConstantPoolIndex, method: &ProgramObject) -> Result<(String, ConstantPoolIndex)> {
+        let name_index = method.get_method_name()?;
+        let name_object = program.constant_pool.get(name_index)?;
+        let name = name_object.as_str()?;
+        Ok((name.to_owned(), index.clone()))
+    }
+
+    let functions = global_objects.iter()
+        .filter(|(_, program_object)| program_object.is_method())
+        .map(|(index, method)| extract_function(program, index, method))
+        .collect::<Result<Vec<(String, ConstantPoolIndex)>>>()?;
+
+    Ok((globals, functions))
+}
+
 impl State {
     // pub fn set_heap_size (mut self, heap_size: usize) -> Self {
     //     self.heap.set_size(heap_size);
@@ -232,63 +400,41 @@ impl State {
     //     self
     // }
     pub fn from(program: &Program) -> Result<Self> {                                                // TODO error handling is a right mess here.
-
-        let entry_index = program.entry.get()
-            .with_context(|| format!("Cannot find entry method."))?;
-        let entry_method = program.constant_pool.get(&entry_index)
-            .with_context(|| format!("Cannot find entry method."))?;
-        let entry_address = entry_method.get_method_start_address()?;
-        let entry_length = entry_method.get_method_length()?;
-        let entry_locals = entry_method.get_method_locals()?;
-
-        let instruction_pointer = if entry_length > 0 { 
-            InstructionPointer::from(*entry_address) 
-        } else { 
-            InstructionPointer::new()
-        };
-
-        let global_objects = program.globals.iter()
-            .map(|index| {
-                program.constant_pool.get(&index).map(|object| (index, object))
-            })
-            .collect::<Result<Vec<(ConstantPoolIndex, &ProgramObject)>>>()?;
-
-        ensure!(global_objects.iter().all(|(_, object)| object.is_slot() || object.is_method()),
-                "Illegal global constant: expecting Method or Slot.");
-
-        fn extract_slot(program: &Program, slot: &ProgramObject) -> Result<String> {
-            let name_index = slot.as_slot_index()?;
-            let name_object = program.constant_pool.get(name_index)?;
-            let name = name_object.as_str()?;
-            Ok(name.to_owned())
-        }
-
-        let globals = global_objects.iter()
-            .filter(|(_, program_object)| program_object.is_slot())
-            .map(|(_, slot)| extract_slot(program, slot))
-            .collect::<Result<Vec<String>>>()?;
-
-        fn extract_function(program: &Program, index: &ConstantPoolIndex, method: &ProgramObject) -> Result<(String, ConstantPoolIndex)> {
-            let name_index = method.get_method_name()?;
-            let name_object = program.constant_pool.get(name_index)?;
-            let name = name_object.as_str()?;
-            Ok((name.to_owned(), index.clone()))
-        }
-
-        let functions = global_objects.iter()
-            .filter(|(_, program_object)| program_object.is_method())
-            .map(|(index, method)| extract_function(program, index, method))
-            .collect::<Result<Vec<(String, ConstantPoolIndex)>>>()?;
+        let (instruction_pointer, entry_frame) = entry_point(program)?;
+        let (globals, functions) = global_definitions(program)?;
 
         let global_frame = GlobalFrame::from(globals, Pointer::Null)?;
         let global_functions = GlobalFunctions::from(functions)?;
         let mut frame_stack = FrameStack::from((global_frame, global_functions));
-        frame_stack.push(Frame::with_capacity(None, entry_locals.to_usize(), Pointer::Null));
+        frame_stack.push(entry_frame);
 
         let operand_stack = OperandStack::new();
         let heap: Heap = Heap::new();
 
-        Ok(State { operand_stack, frame_stack, instruction_pointer, heap })
+        Ok(State { operand_stack, frame_stack, instruction_pointer, heap, limits: StackLimits::default(), instruction_count: 0 })
+    }
+
+    // Prepares to run the entry method of a program that extends the one this state ran before, as
+    // the REPL does after compiling each input. Globals and the heap are kept, globals that are new
+    // start out as null, and functions that are defined again are replaced.
+    pub fn enter(&mut self, program: &Program) -> Result<()> {
+        let (instruction_pointer, entry_frame) = entry_point(program)?;
+        let (globals, functions) = global_definitions(program)?;
+
+        for name in globals {
+            if self.frame_stack.globals.get(&name).is_err() {
+                self.frame_stack.globals.define(name, Pointer::Null)?;
+            }
+        }
+        for (name, index) in functions {
+            self.frame_stack.functions.redefine(name, index);
+        }
+
+        self.frame_stack.clear();
+        self.frame_stack.push(entry_frame);
+        self.operand_stack = OperandStack::new();
+        self.instruction_pointer = instruction_pointer;
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -297,7 +443,9 @@ impl State {
             operand_stack: OperandStack::new(),
             frame_stack: FrameStack::new(),
             instruction_pointer: InstructionPointer::new(),
-            heap: Heap::new()
+            heap: Heap::new(),
+            limits: StackLimits::default(),
+            instruction_count: 0,
         }
     }
 
@@ -307,8 +455,70 @@ impl State {
             operand_stack: OperandStack::new(),
             frame_stack: FrameStack::from(Frame::new()),
             instruction_pointer: InstructionPointer::from(Address::from_usize(0)),
-            heap: Heap::new()
+            heap: Heap::new(),
+            limits: StackLimits::default(),
+            instruction_count: 0,
+        }
+    }
+
+    pub fn ensure_frame_capacity(&self, function: &str) -> Result<()> {
+        let (depth, limit) = (self.frame_stack.depth(), self.limits.max_frame_depth);
+        if limit != 0 && depth >= limit {
+            return Err(StackOverflow::Frames { function: function.to_owned(), depth, limit }.into())
+        }
+        Ok(())
+    }
+
+    pub fn ensure_operand_capacity(&self, program: &Program, address: Option<Address>) -> Result<()> {
+        let (size, limit) = (self.operand_stack.len(), self.limits.max_operands);
+        if limit != 0 && size > limit {
+            let function = address.as_ref()
+                .and_then(|address| program.enclosing_method(address))
+                .unwrap_or("?")
+                .to_owned();
+            return Err(StackOverflow::Operands { function, size, limit }.into())
         }
+        Ok(())
+    }
+
+    pub fn allocate(&mut self, program: &Program, mut object: HeapObject) -> Result<HeapIndex> {
+        if let Some(collection) = self.heap.next_collection(object.size()) {
+            // The object being allocated is not reachable from the state yet, but it may hold the
+            // only references to objects that were just popped off the operand stack.
+            let roots = self.operand_stack.iter_mut()
+                .chain(self.frame_stack.pointers_mut(collection))
+                .chain(object.pointers_mut());
+            self.heap.collect_garbage(roots, collection);
+            let heap = &self.heap;
+            self.frame_stack.update_remembered(|pointer| heap.is_young(pointer));
+            if self.heap.is_stressed() {
+                self.verify_heap(&object)?;
+            }
+        }
+        self.heap.ensure_capacity(object.size())?;
+        if self.heap.is_logging() {
+            let site = AllocationSite::new(program, self.instruction_pointer.get());
+            Ok(self.heap.allocate_at(object, &site))
+        } else {
+            Ok(self.heap.allocate(object))
+        }
+    }
+
+    pub fn verify_heap(&self, allocating: &HeapObject) -> Result<()> {
+        let roots = self.operand_stack.iter()
+            .chain(self.frame_stack.pointers())
+            .flat_map(|pointer| pointer.as_heap_reference().copied())
+            .chain(allocating.references());
+        self.heap.verify(roots)
+    }
+
+    #[allow(dead_code)]
+    pub fn collect_garbage(&mut self) {
+        let roots = self.operand_stack.iter_mut()
+            .chain(self.frame_stack.pointers_mut(Collection::Major));
+        self.heap.collect_garbage(roots, Collection::Major);
+        let heap = &self.heap;
+        self.frame_stack.update_remembered(|pointer| heap.is_young(pointer));
     }
 
     // pub fn bump_instruction_pointer(&mut self, program: &Program) -> &Option<Address> {
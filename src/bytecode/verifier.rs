@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::*;
+
+use super::bytecode::OpCode;
+use super::program::*;
+
+// Checks a program before it runs, so that hand-written or corrupt bytecode is rejected up front
+// instead of failing deep inside the interpreter:
+//  - constants refer to constants of the right kind, and method code lies within the program,
+//  - instructions refer to constants of the right kind, and jumps to labels in the same method,
+//  - locals fit the frame of the method they are used in,
+//  - the operand stack never goes below the bottom of the method's frame, has the same depth on
+//    every path into an instruction, and holds exactly the result when the method returns.
+//
+// The operand stack is shared between frames, so each method is analyzed from a depth of zero.
+pub fn verify(program: &Program) -> Result<()> {
+    let mut verifier = Verifier { program, violations: Vec::new() };
+    verifier.constants();
+    verifier.globals();
+    verifier.entry();
+    for (index, object) in program.constant_pool.iter().enumerate() {
+        if object.is_method() {
+            verifier.method(ConstantPoolIndex::from_usize(index), object);
+        }
+    }
+    if verifier.violations.is_empty() {
+        Ok(())
+    } else {
+        Err(VerificationFailed(verifier.violations).into())
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Location {
+    Constant(ConstantPoolIndex),
+    Instruction { method: String, address: Address },
+    Globals,
+    Entry,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Violation {
+    pub location: Location,
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.location {
+            Location::Constant(index) => write!(f, "constant {}: {}", index, self.message),
+            Location::Instruction { method, address } => write!(f, "method `{}` at {}: {}", method, address, self.message),
+            Location::Globals => write!(f, "globals: {}", self.message),
+            Location::Entry => write!(f, "entry: {}", self.message),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct VerificationFailed(pub Vec<Violation>);
+
+impl std::fmt::Display for VerificationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Bytecode verification failed:")?;
+        for violation in self.0.iter() {
+            write!(f, "\n  {}", violation)?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+impl std::error::Error for VerificationFailed {}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Kind { String, Literal, Class, Method, Member }
+
+impl Kind {
+    fn matches(&self, object: &ProgramObject) -> bool {
+        match (self, object) {
+            (Kind::String, ProgramObject::String(_)) => true,
+            (Kind::Literal, object) => object.is_literal(),
+            (Kind::Class, ProgramObject::Class(_)) => true,
+            (Kind::Method, ProgramObject::Method { .. }) => true,
+            (Kind::Member, object) => object.is_slot() || object.is_method(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Kind::String => write!(f, "a string"),
+            Kind::Literal => write!(f, "a null, an integer, a boolean, or a string"),
+            Kind::Class => write!(f, "a class"),
+            Kind::Method => write!(f, "a method"),
+            Kind::Member => write!(f, "a slot or a method"),
+        }
+    }
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Verifier<'a> {
+    fn report<S>(&mut self, location: Location, message: S) where S: Into<String> {
+        self.violations.push(Violation { location, message: message.into() })
+    }
+
+    // Reports and returns None unless the index points to a constant of the given kind.
+    fn expect(&mut self, location: &Location, index: &ConstantPoolIndex, kind: Kind) -> Option<&'a ProgramObject> {
+        match self.program.constant_pool.get(index).ok() {
+            None => {
+                self.report(location.clone(), format!("{} is not in the constant pool", index));
+                None
+            }
+            Some(object) if !kind.matches(object) => {
+                self.report(location.clone(), format!("{} should be {}, but it is `{}`", index, kind, object));
+                None
+            }
+            Some(object) => Some(object),
+        }
+    }
+
+    fn constants(&mut self) {
+        for (index, object) in self.program.constant_pool.iter().enumerate() {
+            let location = Location::Constant(ConstantPoolIndex::from_usize(index));
+            match object {
+                ProgramObject::Slot { name } => { self.expect(&location, name, Kind::String); }
+                ProgramObject::Class(members) => {
+                    for member in members {
+                        self.expect(&location, member, Kind::Member);
+                    }
+                }
+                ProgramObject::Method { name, code, .. } => {
+                    self.expect(&location, name, Kind::String);
+                    if code.start().value_usize() + code.length() > self.program.code.length() {
+                        self.report(location, format!("code {} is outside the program, which has {} instructions",
+                                                      code, self.program.code.length()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn globals(&mut self) {
+        for global in self.program.globals.iter() {
+            self.expect(&Location::Globals, &global, Kind::Member);
+        }
+    }
+
+    fn entry(&mut self) {
+        let entry = match self.program.entry.get() {
+            std::result::Result::Ok(entry) => entry,
+            Err(_) => return self.report(Location::Entry, "there is no entry point"),
+        };
+        if let Some(method) = self.expect(&Location::Entry, &entry, Kind::Method) {
+            if method.get_method_parameters().map_or(false, |parameters| parameters.to_usize() != 0) {
+                self.report(Location::Entry, format!("the entry method {} should not take arguments", entry));
+            }
+        }
+    }
+
+    fn method(&mut self, index: ConstantPoolIndex, method: &'a ProgramObject) {
+        let (name, parameters, locals, range) = match method {
+            ProgramObject::Method { name, parameters, locals, code } => (name, parameters, locals, code),
+            _ => return,
+        };
+        let end = range.start().value_usize() + range.length();
+        if range.length() == 0 || end > self.program.code.length() {
+            return
+        }
+        let name = self.program.constant_pool.get(name).ok()
+            .and_then(|name| name.as_str().ok())
+            .map_or(name.to_string(), |name| name.to_owned());
+        let frame_size = parameters.to_usize() + locals.to_usize();
+        // Functions, object methods and closures must return. The entry method may run off its end if its
+        // code ends the program, which stops it. Other methods cannot be called, like the entry
+        // methods of earlier REPL inputs, so they never run again.
+        let callable = self.program.globals.iter().any(|global| global == index)
+            || self.program.constant_pool.iter()
+                .filter_map(|object| object.as_class_definition().ok())
+                .any(|members| members.contains(&index))
+            || self.program.code.iter()
+                .any(|opcode| matches!(opcode, OpCode::Closure { function, .. } if *function == index));
+        let is_entry = self.program.entry.get().ok() == Some(index);
+        let may_fall_off = if is_entry { end == self.program.code.length() } else { !callable };
+
+        let addresses = range.start().value_usize()..end;
+        let mut labels: HashMap<&str, Address> = HashMap::new();
+        for address in addresses.clone().map(Address::from_usize) {
+            if let std::result::Result::Ok(OpCode::Label { name: label }) = self.program.code.get(address) {
+                let location = Location::Instruction { method: name.clone(), address };
+                if let Some(label) = self.expect(&location, label, Kind::String).and_then(|label| label.as_str().ok()) {
+                    if labels.insert(label, address).is_some() {
+                        self.report(location, format!("label `{}` is defined more than once", label));
+                    }
+                }
+            }
+        }
+
+        let reported = self.violations.len();
+        // Depths of the operand stack before each instruction, found by following every path.
+        let mut depths: BTreeMap<Address, usize> = BTreeMap::new();
+        let mut pending = vec![(*range.start(), 0usize)];
+        while let Some((address, depth)) = pending.pop() {
+            let location = Location::Instruction { method: name.clone(), address };
+            match depths.get(&address) {
+                Some(known) if *known == depth => continue,
+                Some(known) => {
+                    let message = format!("the operand stack depth is {} on one path here and {} on another", known, depth);
+                    self.report(location, message);
+                    continue
+                }
+                None => { depths.insert(address, depth); }
+            }
+
+            let opcode = self.program.code.get(address).expect("The method's code is in the program");
+            let (pops, pushes) = self.instruction(&location, opcode, frame_size);
+            if pops > depth {
+                let message = format!("`{}` pops {} operands, but the operand stack depth is {}", opcode, pops, depth);
+                self.report(location.clone(), message);
+            }
+            // Carry on as if the stack had been deep enough, to find more problems on this path.
+            let depth = depth.saturating_sub(pops) + pushes;
+
+            let target = match opcode {
+                OpCode::Jump { label } | OpCode::Branch { label } => {
+                    let target = self.program.constant_pool.get(label).ok()
+                        .and_then(|label| label.as_str().ok())
+                        .map(|label| (label, labels.get(label)));
+                    match target {
+                        Some((_, Some(target))) => Some(*target),
+                        Some((label, None)) => {
+                            self.report(location.clone(), format!("label `{}` is not defined in this method", label));
+                            None
+                        }
+                        None => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(target) = target {
+                pending.push((target, depth));
+            }
+
+            match opcode {
+                OpCode::Return if depth != 1 => {
+                    let message = format!("the operand stack depth is {} when the method returns, but it should be 1", depth);
+                    self.report(location, message);
+                }
+                OpCode::Return | OpCode::Jump { .. } => {}
+                _ if address.value_usize() + 1 < end => pending.push((Address::from_usize(address.value_usize() + 1), depth)),
+                _ if !may_fall_off => self.report(location, "the method ends without returning"),
+                _ => {}
+            }
+        }
+        // Paths are followed in no particular order, so report in address order.
+        self.violations[reported..].sort_by_key(|violation| match violation.location {
+            Location::Instruction { address, .. } => Some(address),
+            _ => None,
+        });
+    }
+
+    // Checks the operands of an instruction, and returns how many values it pops and pushes.
+    fn instruction(&mut self, location: &Location, opcode: &OpCode, frame_size: usize) -> (usize, usize) {
+        match opcode {
+            OpCode::Literal { index } => { self.expect(location, index, Kind::Literal); (0, 1) }
+            OpCode::GetLocal { index } | OpCode::SetLocal { index } => {
+                if index.value() as usize >= frame_size {
+                    self.report(location.clone(), format!("local {} is outside the frame, which has {} slots",
+                                                          index.value(), frame_size));
+                }
+                match opcode { OpCode::GetLocal { .. } => (0, 1), _ => (1, 1) }
+            }
+            OpCode::GetGlobal { name } => { self.expect(location, name, Kind::String); (0, 1) }
+            OpCode::SetGlobal { name } => { self.expect(location, name, Kind::String); (1, 1) }
+            OpCode::Object { class } => {
+                let slots = self.expect(location, class, Kind::Class)
+                    .and_then(|class| class.as_class_definition().ok())
+                    .map_or(0, |members| {
+                        members.iter()
+                            .filter(|member| self.program.constant_pool.get(member).map_or(false, |member| member.is_slot()))
+                            .count()
+                    });
+                (slots + 1, 1)
+            }
+            OpCode::Array => (2, 1),
+            OpCode::GetField { name } => { self.expect(location, name, Kind::String); (1, 1) }
+            OpCode::SetField { name } => { self.expect(location, name, Kind::String); (2, 1) }
+            OpCode::CallMethod { name, arguments } => {
+                self.expect(location, name, Kind::String);
+                if arguments.to_usize() == 0 {
+                    self.report(location.clone(), "a method call needs at least 1 argument, the receiver");
+                }
+                (arguments.to_usize(), 1)
+            }
+            OpCode::CallFunction { name, arguments } => { self.expect(location, name, Kind::String); (arguments.to_usize(), 1) }
+            OpCode::Print { format, arguments } => { self.expect(location, format, Kind::String); (arguments.to_usize(), 1) }
+            OpCode::Label { .. } => (0, 0),
+            OpCode::Jump { label } => { self.expect(location, label, Kind::String); (0, 0) }
+            OpCode::Branch { label } => { self.expect(location, label, Kind::String); (1, 0) }
+            OpCode::Return => (0, 0),
+            OpCode::Drop => (1, 0),
+            OpCode::Closure { function, captured } => {
+                let locals = self.expect(location, function, Kind::Method)
+                    .and_then(|method| method.get_method_locals().ok());
+                if let Some(locals) = locals {
+                    if captured.to_usize() > locals.to_usize() {
+                        self.report(location.clone(), format!("{} captures {} values, but its method has {} locals",
+                                                              opcode, captured.to_usize(), locals.to_usize()));
+                    }
+                }
+                (captured.to_usize(), 1)
+            }
+        }
+    }
+}
@@ -8,29 +8,42 @@ mod bytecode;
 #[cfg(test)] mod tests;
 
 use std::path::PathBuf;
+use std::time::Duration;
 use std::fs::File;
-use std::io::{Read, BufReader, BufRead, Write, BufWriter};
+use std::io::{Read, BufReader, BufRead, Write, BufWriter, IsTerminal};
 
 use clap::Clap;
 use clap::crate_version;
 use clap::crate_authors;
 use anyhow::*;
 
-use crate::parser::AST;
-use crate::fml::TopLevelParser;
+use crate::parser::{AST, Span};
+use crate::parser::diagnostics::{SourceFile, ParseDiagnostic};
 
 use crate::bytecode::program::Program;
-use crate::bytecode::serializable::Serializable;
-use crate::bytecode::interpreter::evaluate_with_memory_config;
+use crate::bytecode::serializable::{Serializable, OffsetReader};
+use crate::bytecode::debug::PrettyPrint;
+use crate::bytecode::interpreter::{evaluate_with_memory_config, Budget, BudgetExhausted};
+use crate::bytecode::heap::{HeapExhausted, GarbageCollector, GenerationConfig, HeapConfig};
+use crate::bytecode::heap_log::{HeapLogFormat, HeapReport, read_heap_log};
+use crate::bytecode::heap_dump::{HeapDumpConfig, HeapSnapshot};
+use crate::bytecode::state::{StackLimits, StackOverflow, Output};
+use crate::bytecode::stack_trace::StackTrace;
+use crate::bytecode::debug_info::SourceLocation;
+use crate::bytecode::session::Session;
+use crate::bytecode::verifier::{verify, VerificationFailed};
 
 #[derive(Clap, Debug)]
 #[clap(version = crate_version!(), author = crate_authors!())]
 enum Action {
     Parse(ParserAction),
     Compile(CompilerAction),
+    Assemble(AssemblerAction),
     Execute(BytecodeInterpreterAction),
     Disassemble(BytecodeDisassemblyAction),
     Run(RunAction),
+    Repl(ReplAction),
+    HeapReport(HeapReportAction),
 }
 
 impl Action {
@@ -38,9 +51,12 @@ impl Action {
         match self {
             Self::Parse(action) => action.parse(),
             Self::Compile(action) => action.compile(),
+            Self::Assemble(action) => action.assemble(),
             Self::Execute(action) => action.interpret(),
             Self::Run(action) => action.run(),
+            Self::Repl(action) => action.repl(),
             Self::Disassemble(action) => action.debug(),
+            Self::HeapReport(action) => action.report(),
         }
     }
 }
@@ -50,12 +66,47 @@ impl Action {
 struct RunAction {
     #[clap(name="FILE", parse(from_os_str))]
     pub input: Option<PathBuf>,
-    #[clap(long="heap-size", name="MBs", about = "Maximum heap size in megabytes", default_value = "0")]
+    #[clap(flatten)]
+    pub runtime: RuntimeOptions,
+}
+
+// Heap, garbage collection, and resource limit options shared by the commands that run programs.
+#[derive(Clap, Debug)]
+struct RuntimeOptions {
+    #[clap(long="heap-size", name="MBs", about = "Maximum heap size in megabytes, 0 means unlimited", default_value = "0")]
     pub heap_size: usize,
-    #[clap(long="heap-log", name="LOG_FILE", about = "Path to heap log, if none, the log is not produced", parse(from_os_str), parse(from_os_str))]
+    #[clap(long="heap-log", name="LOG_FILE", about = "Path to heap log, if none, the log is not produced", parse(from_os_str))]
     pub heap_log: Option<PathBuf>,
+    #[clap(long="heap-log-format", name="LOG_FORMAT", about = "Format of the heap log: csv or jsonl", default_value = "csv")]
+    pub heap_log_format: HeapLogFormat,
+    #[clap(long="gc", name="GC", about = "Garbage collection strategy: copying, generational, mark-sweep, or none", default_value = "mark-sweep")]
+    pub gc: GarbageCollector,
+    #[clap(long="nursery-size", name="NURSERY_KBs", about = "Size of the nursery in kilobytes (generational GC only)", default_value = "256")]
+    pub nursery_size: usize,
+    #[clap(long="old-generation-size", name="OLD_MBs", about = "Size of the old generation in megabytes before a major collection (generational GC only)", default_value = "1")]
+    pub old_generation_size: usize,
+    #[clap(long="promotion-age", name="COLLECTIONS", about = "Number of minor collections an object survives before promotion (generational GC only)", default_value = "2")]
+    pub promotion_age: usize,
+    #[clap(long="gc-stress", about = "Collect garbage and verify heap integrity before every allocation")]
+    pub gc_stress: bool,
+    #[clap(long="heap-dump", name="DUMP_FILE", about = "Dump the heap and its roots when the program exits, as DOT if the file ends in .dot, otherwise as JSON", parse(from_os_str))]
+    pub heap_dump: Option<PathBuf>,
+    #[clap(long="heap-dump-on-error", name="ERROR_DUMP_FILE", about = "Dump the heap and its roots only if the program fails with a runtime error", parse(from_os_str))]
+    pub heap_dump_on_error: Option<PathBuf>,
+    #[clap(long="max-frame-depth", name="FRAMES", about = "Maximum depth of nested function and method calls, 0 means unlimited", default_value = "10000")]
+    pub max_frame_depth: usize,
+    #[clap(long="max-operands", name="OPERANDS", about = "Maximum size of the operand stack, 0 means unlimited", default_value = "100000")]
+    pub max_operands: usize,
+    #[clap(long="max-instructions", name="INSTRUCTIONS", about = "Stop after executing this many instructions")]
+    pub max_instructions: Option<u64>,
+    #[clap(long="timeout", name="SECONDS", about = "Stop after running for this many seconds of wall-clock time")]
+    pub timeout: Option<f64>,
 }
 
+#[derive(Clap, Debug)]
+#[clap(about = "Evaluate FML interactively, one top-level expression at a time")]
+struct ReplAction {}
+
 #[derive(Clap, Debug)]
 #[clap(about = "Print FML bytecode in human-readable form")]
 struct BytecodeDisassemblyAction {
@@ -68,28 +119,58 @@ struct BytecodeDisassemblyAction {
 struct BytecodeInterpreterAction {
     #[clap(name="FILE", parse(from_os_str))]
     pub input: Option<PathBuf>,
-    #[clap(long="heap-size", name="MBs", about = "Maximum heap size in megabytes", default_value = "0")]
-    pub heap_size: usize,
-    #[clap(long="heap-log", name="LOG_FILE", about = "Path to heap log, if none, the log is not produced", parse(from_os_str))]
-    pub heap_log: Option<PathBuf>,
+    #[clap(long="no-verify", about = "Run the bytecode without checking it first, so malformed bytecode fails while it runs, if at all")]
+    pub no_verify: bool,
+    #[clap(flatten)]
+    pub runtime: RuntimeOptions,
+}
+
+#[derive(Clap, Debug)]
+#[clap(about = "Summarize a heap log produced with --heap-log (CSV or JSON lines)")]
+struct HeapReportAction {
+    #[clap(name="LOG_FILE", parse(from_os_str))]
+    pub input: PathBuf,
 }
 
 #[derive(Clap, Debug)]
-#[clap(about = "Compiles an FML AST into bytecode")]
+#[clap(about = "Compiles FML source code or an FML AST into bytecode")]
 struct CompilerAction {
-    #[clap(short = 'o', long = "output-path", alias = "output-dir", parse(from_os_str))]
+    #[clap(short = 'o', long = "output-path", alias = "output-dir", parse(from_os_str),
+    about = "The output file, or with several inputs, the output directory [default: next to each input]")]
     pub output: Option<PathBuf>,
 
-    #[clap(name="FILE", parse(from_os_str))]
-    pub input: Option<PathBuf>,
+    #[clap(name="FILE", parse(from_os_str),
+    about = "FML source files (.fml) or serialized ASTs, standard input is read as FML source if there are none")]
+    pub inputs: Vec<PathBuf>,
 
     #[clap(long = "output-format", alias = "bc", name = "AST_FORMAT",
-    about = "The output format for the bytecode: bytes or string")]
+    about = "The output format for the bytecode: bytes, header (bytes with a versioned header), or string")]
     pub output_format: Option<BCSerializer>,
 
     #[clap(long = "input-format", alias = "ast", name = "BC_FORMAT",
-    about = "The output format of the AST: JSON, LISP, YAML")]
+    about = "The format of AST inputs: JSON, LISP, YAML [default: from the file extension]")]
     pub input_format: Option<ASTSerializer>,
+
+    #[clap(long = "debug-info", about = "Include a line table that maps instructions to the FML source, and local variable names")]
+    pub debug_info: bool,
+
+    #[clap(long = "source", name = "SOURCE_FILE", parse(from_os_str),
+    about = "The FML source an AST input was parsed with --spans from, used by --debug-info [default: the input with a .fml extension]")]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Clap, Debug)]
+#[clap(about = "Assembles FML bytecode written as text, the format of compile --output-format string")]
+struct AssemblerAction {
+    #[clap(short = 'o', long = "output-path", parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    #[clap(name="FILE", parse(from_os_str))]
+    pub input: Option<PathBuf>,
+
+    #[clap(long = "output-format", alias = "bc", name = "BC_FORMAT",
+    about = "The output format for the bytecode: bytes, header (bytes with a versioned header), or string")]
+    pub output_format: Option<BCSerializer>,
 }
 
 #[derive(Clap, Debug)]
@@ -104,6 +185,14 @@ struct ParserAction {
     #[clap(long = "format", alias = "ast", name = "FORMAT",
     about = "The output format of the AST: JSON, LISP, YAML, or Rust")]
     pub format: Option<ASTSerializer>,
+
+    #[clap(long = "partial",
+    about = "Output the AST of everything that parsed even if there are syntax errors, which leave out the statements and members they occur in")]
+    pub partial: bool,
+
+    #[clap(long = "spans",
+    about = "Record the source span of each node, for compile --debug-info on the AST")]
+    pub spans: bool,
 }
 
 macro_rules! prepare_file_path_from_input_and_serializer {
@@ -132,21 +221,58 @@ macro_rules! prepare_file_path_from_input_and_serializer {
 }
 
 
+impl RuntimeOptions {
+    pub fn evaluate(&self, program: &Program) -> Result<()> {
+        evaluate_with_memory_config(program, self.heap_config(), self.heap_dumps(), self.stack_limits(), self.budget())
+    }
+
+    pub fn budget(&self) -> Budget {
+        Budget {
+            instructions: self.max_instructions,
+            time: self.timeout.map(Duration::from_secs_f64),
+        }
+    }
+
+    pub fn stack_limits(&self) -> StackLimits {
+        StackLimits { max_frame_depth: self.max_frame_depth, max_operands: self.max_operands }
+    }
+
+    pub fn heap_dumps(&self) -> HeapDumpConfig {
+        HeapDumpConfig { at_exit: self.heap_dump.clone(), on_error: self.heap_dump_on_error.clone() }
+    }
+
+    pub fn heap_config(&self) -> HeapConfig {
+        HeapConfig {
+            size: self.heap_size,
+            log: self.heap_log.clone(),
+            log_format: self.heap_log_format,
+            collector: self.gc,
+            generations: GenerationConfig {
+                nursery_size: self.nursery_size,
+                old_size: self.old_generation_size,
+                promotion_age: self.promotion_age,
+            },
+            stress: self.gc_stress,
+        }
+    }
+}
+
 impl RunAction {
     pub fn run(&self) {
         let source = self.selected_input()
             .expect("Cannot open FML program.");
+        let source = source.into_source_file()
+            .expect("Error reading input");
 
-        let ast: AST = TopLevelParser::new()
-            .parse(&source.into_string()
-            .expect("Error reading input"))
-            .expect("Parse error");
+        let ast: AST = parse_or_exit(&source);
 
-        let program = bytecode::compile(&ast)
-            .expect("Compiler error");
+        let program = bytecode::compile_with_debug_info(&ast, &source).unwrap_or_else(|error| {
+            report_error(&error, Some(&source));
+            std::process::exit(1);
+        });
 
-        evaluate_with_memory_config(&program, self.heap_size, self.heap_log.clone())
-            .expect("Interpreter error")
+        let result = self.runtime.evaluate(&program);
+        report_interpreter_result(result, Some(&source))
     }
 
     pub fn selected_input(&self) -> Result<NamedSource> {
@@ -154,85 +280,433 @@ impl RunAction {
     }
 }
 
+fn report_interpreter_result(result: Result<()>, source: Option<&SourceFile>) {
+    if let Err(error) = result {
+        report_error(&error, source);
+        std::process::exit(1);
+    }
+}
+
+// Prints the error chain, with a source excerpt if the error has a span and the source is known,
+// followed by the FML stack trace, if any.
+fn report_error(error: &anyhow::Error, source: Option<&SourceFile>) {
+    let fatal = error.chain().find_map(|cause| {
+        cause.downcast_ref::<HeapExhausted>().map(|error| error.to_string())
+            .or_else(|| cause.downcast_ref::<StackOverflow>().map(|error| error.to_string()))
+            .or_else(|| cause.downcast_ref::<BudgetExhausted>().map(|error| error.to_string()))
+    });
+    let trace = error.downcast_ref::<StackTrace>();
+    // Runtime errors are located by the program's debug info, compiler errors by AST spans.
+    let location = error.downcast_ref::<SourceLocation>();
+    let span = source.and(error.downcast_ref::<Span>());
+
+    let message = match fatal {
+        Some(message) => message,
+        None => {
+            // The stack trace is the outermost context, the rest of the chain says what failed.
+            let location_context = location.map(|location| location.to_string())
+                .or_else(|| span.map(|span| span.to_string()));
+            let causes: Vec<String> = error.chain()
+                .skip(if trace.is_some() { 1 } else { 0 })
+                .map(|cause| cause.to_string())
+                .filter(|cause| Some(cause) != location_context.as_ref())
+                .collect();
+            causes.iter().enumerate()
+                .map(|(depth, cause)| format!("{:indent$}{}", "", cause, indent = depth * 2))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    };
+    match (location, span) {
+        (Some(location), _) => {
+            let loaded;
+            let file = match source {
+                Some(source) if source.name == location.file => Some(source),
+                _ => {
+                    loaded = NamedSource::from_file(&PathBuf::from(&location.file))
+                        .and_then(|source| source.into_source_file()).ok();
+                    loaded.as_ref()
+                }
+            };
+            match file {
+                Some(file) => eprint!("{}", file.render(&location.span, &message)),
+                None => eprintln!("{}: error: {}", location, message),
+            }
+        }
+        (None, Some(span)) => eprint!("{}", source.unwrap().render(span, &message)),
+        (None, None) => eprintln!("{}", message),
+    }
+    if let Some(trace) = trace {
+        eprint!("{}", trace);
+    }
+}
+
+fn report_parse_diagnostics(source: &SourceFile, diagnostics: &[ParseDiagnostic]) {
+    for diagnostic in diagnostics {
+        eprint!("{}", source.render(&diagnostic.span, &diagnostic.message));
+    }
+    if diagnostics.len() > 1 {
+        eprintln!("{} syntax errors", diagnostics.len());
+    }
+}
+
+fn parse_or_exit(source: &SourceFile) -> AST {
+    parser::parse_with_spans(&source.text).unwrap_or_else(|diagnostics| {
+        report_parse_diagnostics(source, &diagnostics);
+        std::process::exit(1);
+    })
+}
+
+const REPL_HELP: &str = "\
+Enter FML top-level expressions. Unfinished input, like an open `begin`, continues on the next
+line, and an empty line ends it.
+  :ast CODE    print the AST of CODE without running it
+  :bc [CODE]   print the bytecode compiled so far, including CODE if given, without running it
+  :heap        print the globals, the objects on the heap, and the heap size
+  :help        print this message
+  :quit        exit the REPL
+";
+
+impl ReplAction {
+    pub fn repl(&self) {
+        let mut session = Session::new();
+        let interactive = std::io::stdin().is_terminal();
+        let prompt = |continued: bool| {
+            if interactive {
+                print!("{}", if continued { "...> " } else { "fml> " });
+                std::io::stdout().flush().expect("Cannot write to output");
+            }
+        };
+
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        let mut input = String::new();
+        loop {
+            prompt(!input.is_empty());
+            // The end of the input ends whatever input is still unfinished, like an empty line.
+            let line = match lines.next() {
+                Some(line) => line.expect("Cannot read from input"),
+                None if input.is_empty() => break,
+                None => String::new(),
+            };
+
+            if input.is_empty() && line.trim_start().starts_with(':') {
+                match self.meta_command(&session, line.trim()) {
+                    ControlFlow::Continue => continue,
+                    ControlFlow::Quit => break,
+                }
+            }
+
+            input.push_str(&line);
+            input.push('\n');
+            if !line.trim().is_empty() && parser::is_incomplete(&input) {
+                continue
+            }
+            let input = std::mem::take(&mut input);
+            if input.trim().is_empty() {
+                continue
+            }
+
+            let source = session.pending(&input);
+            match session.parse(&input) {
+                Err(diagnostics) => report_parse_diagnostics(&source, &diagnostics),
+                Ok(ast) => match session.evaluate(&input, &ast, &mut Output::new()) {
+                    Ok(Some(value)) => println!("{}", value),
+                    Ok(None) => {},
+                    Err(error) => report_error(&error, Some(&source)),
+                },
+            }
+        }
+    }
+
+    fn meta_command(&self, session: &Session, line: &str) -> ControlFlow {
+        let (command, argument) = match line.find(char::is_whitespace) {
+            Some(position) => (&line[..position], line[position..].trim()),
+            None => (line, ""),
+        };
+        match (command, argument) {
+            (":quit", _) | (":q", _) => return ControlFlow::Quit,
+            (":help", _) => print!("{}", REPL_HELP),
+            (":heap", _) => {
+                print!("{}", HeapSnapshot::from(session.state()).to_text());
+                println!("{} B in use", session.state().heap.size());
+            }
+            (":ast", "") => eprintln!(":ast needs some code to parse"),
+            (":ast", code) => match session.parse(code) {
+                Err(diagnostics) => report_parse_diagnostics(&session.pending(code), &diagnostics),
+                Ok(ast) => print!("{}", ASTSerializer::LISP.serialize(&ast.strip_locations())
+                    .expect("Cannot serialize AST")),
+            },
+            (":bc", "") => match session.program() {
+                Ok(program) => print!("{}", program),
+                Err(error) => report_error(&error, None),
+            },
+            (":bc", code) => match session.parse(code) {
+                Err(diagnostics) => report_parse_diagnostics(&session.pending(code), &diagnostics),
+                Ok(ast) => match session.compile(code, &ast) {
+                    Ok((_, _, program)) => print!("{}", program),
+                    Err(error) => report_error(&error, Some(&session.pending(code))),
+                },
+            },
+            (command, _) => eprintln!("Unknown command {}, try :help", command),
+        }
+        ControlFlow::Continue
+    }
+}
+
+enum ControlFlow { Continue, Quit }
+
 impl BytecodeInterpreterAction {
     pub fn interpret(&self) {
-        let mut source = self.selected_input()
-            .expect("Cannot open an input for the bytecode interpreter.");
-
-        let program = BCSerializer::BYTES.deserialize(&mut source)
-            .expect("Cannot parse bytecode from input.");
+        let program = load_bytecode(self.input.as_ref());
+        if !self.no_verify {
+            verify_or_exit(self.input.as_ref(), &program);
+        }
 
-        evaluate_with_memory_config(&program, self.heap_size, self.heap_log.clone())
-            .expect("Interpreter error")
+        let result = self.runtime.evaluate(&program);
+        report_interpreter_result(result, None)
     }
+}
 
-    pub fn selected_input(&self) -> Result<NamedSource> {
-        NamedSource::from(self.input.as_ref())
+impl HeapReportAction {
+    pub fn report(&self) {
+        let file = File::open(&self.input)
+            .expect("Cannot open heap log.");
+
+        let events = read_heap_log(BufReader::new(file))
+            .expect("Cannot parse heap log.");
+
+        print!("{}", HeapReport::from(&events));
     }
 }
 
 impl BytecodeDisassemblyAction {
     pub fn debug(&self) {
-        let mut source = self.selected_input()
-            .expect("Cannot open an input for the bytecode interpreter.");
-
-        let program = BCSerializer::BYTES.deserialize(&mut source)
-            .expect("Cannot parse bytecode from input.");
+        let program = load_bytecode(self.input.as_ref());
 
-        println!("{}", program);
-    }
+        // Sources named in the debug info are shown beside the instructions, if they can be read.
+        let sources: Vec<SourceFile> = program.debug.iter()
+            .flat_map(|debug| debug.files.iter())
+            .filter_map(|file| NamedSource::from_file(&PathBuf::from(file)).ok())
+            .filter_map(|source| source.into_source_file().ok())
+            .collect();
 
-    pub fn selected_input(&self) -> Result<NamedSource> {
-        NamedSource::from(self.input.as_ref())
+        let mut disassembly = String::new();
+        program.disassemble(&mut disassembly, &sources)
+            .expect("Cannot disassemble program.");
+        println!("{}", disassembly);
     }
 }
 
 impl CompilerAction {
     pub fn compile(&self) {
-        let source = self.selected_input()
-            .expect("Cannot open an input for the compiler.");
-        let mut sink = self.selected_output()
-            .expect("Cannot open an output for the compiler.");
-        let input_serializer = self.selected_input_format()
-            .expect("Cannot derive input format from file path. Consider setting it explicitly.");
-        let output_serializer = self.selected_output_format();
+        let inputs: Vec<Option<&PathBuf>> = if self.inputs.is_empty() {
+            vec![None]
+        } else {
+            self.inputs.iter().map(Some).collect()
+        };
+        if self.source.is_some() && inputs.len() > 1 {
+            eprintln!("--source can only be used with a single input.");
+            std::process::exit(1);
+        }
+        if self.debug_info && self.selected_output_format() == BCSerializer::STRING {
+            eprintln!("--debug-info cannot be written in the string format, use bytes or header.");
+            std::process::exit(1);
+        }
 
-        let source = source.into_string()
-            .expect("Error reading input file");
-        let ast = input_serializer.deserialize(&source)
-            .expect("Error parsing AST from input file");
+        // A bad file does not stop the others from being compiled.
+        let failed = inputs.iter()
+            .filter(|input| !self.compile_input(**input))
+            .count();
+        if failed > 0 {
+            if inputs.len() > 1 {
+                eprintln!("{} of {} inputs failed to compile", failed, inputs.len());
+            }
+            std::process::exit(1);
+        }
+    }
 
-        let program = bytecode::compile(&ast)
-            .expect("Compiler Error");
+    // Reports its own errors, returns whether the input compiled.
+    fn compile_input(&self, input: Option<&PathBuf>) -> bool {
+        let name = input_name(input);
+        let source = match NamedSource::from(input).and_then(|source| source.into_source_file()) {
+            Ok(source) => source,
+            Err(error) => { eprintln!("{}: error: {}", name, error); return false }
+        };
 
-        output_serializer.serialize(&program, &mut sink)
-            .expect("Cannot serialize program to output.");
-    }
+        let (ast, debug_source) = match self.selected_input_format(input) {
+            None => match parser::parse_with_spans(&source.text) {
+                Ok(ast) => (ast, source),
+                Err(diagnostics) => { report_parse_diagnostics(&source, &diagnostics); return false }
+            },
+            Some(format) => {
+                let ast = match format.deserialize(&source.text) {
+                    Ok(ast) => ast,
+                    Err(error) => { eprintln!("{}: error: Cannot parse AST: {}", name, error); return false }
+                };
+                if !self.debug_info {
+                    (ast, source)
+                } else {
+                    match self.selected_source(input) {
+                        Ok(source) => (ast, source),
+                        Err(error) => { eprintln!("{}: error: Cannot read the FML source for debug info: {}", name, error); return false }
+                    }
+                }
+            }
+        };
 
-    pub fn selected_input(&self) -> Result<NamedSource> {
-        NamedSource::from(self.input.as_ref())
+        let compiled = if self.debug_info {
+            bytecode::compile_with_debug_info(&ast, &debug_source)
+        } else {
+            bytecode::compile(&ast)
+        };
+        let program = match compiled {
+            Ok(program) => program,
+            Err(error) => { report_error(&error, Some(&debug_source)); return false }
+        };
+
+        let written = self.selected_output(input)
+            .and_then(|mut sink| {
+                self.selected_output_format().serialize(&program, &mut sink)?;
+                sink.flush().map_err(|error| error.into())
+            });
+        if let Err(error) = written {
+            eprintln!("{}: error: Cannot write bytecode: {}", name, error);
+            return false
+        }
+        true
     }
 
     pub fn selected_output_format(&self) -> BCSerializer {
         self.output_format.unwrap_or(BCSerializer::BYTES)
     }
 
-    pub fn selected_input_format(&self) -> Option<ASTSerializer> {
-        if self.input_format.is_some() {
-            self.input_format
-        } else {
-            self.selected_input().unwrap().extension().map(|s| {
-                ASTSerializer::from_extension(s.as_str())
-            }).flatten()
+    pub fn selected_source(&self, input: Option<&PathBuf>) -> Result<SourceFile> {
+        let path = match (&self.source, input) {
+            (Some(source), _) => source.clone(),
+            (None, Some(input)) => input.with_extension("fml"),
+            (None, None) => bail!("Cannot derive the FML source from standard input, use --source."),
+        };
+        NamedSource::from_file(&path)?.into_source_file()
+    }
+
+    // None means the input is FML source: files ending in .fml, and standard input unless a
+    // format is given.
+    pub fn selected_input_format(&self, input: Option<&PathBuf>) -> Option<ASTSerializer> {
+        let extension = input.and_then(|path| path.extension()).and_then(|extension| extension.to_str());
+        match (self.input_format, extension) {
+            (_, Some("fml")) => None,
+            (Some(format), _) => Some(format),
+            (None, Some(extension)) => ASTSerializer::from_extension(extension),
+            (None, None) => None,
         }
     }
 
-    pub fn selected_output(&self) -> Result<NamedSink> {
-        let maybe_file = prepare_file_path_from_input_and_serializer!(self);
-        NamedSink::from(maybe_file)
+    // A single input goes to the output path, or into it if it is a directory. Several inputs go
+    // into the output directory, keeping their paths below the directory they have in common, so
+    // that a whole tree of programs can be compiled at once. Without an output path, a single
+    // input goes to standard output and several inputs go next to their sources.
+    pub fn selected_output(&self, input: Option<&PathBuf>) -> Result<NamedSink> {
+        let extension = self.selected_output_format().extension();
+        let several = self.inputs.len() > 1;
+        let path = match (&self.output, input) {
+            (None, Some(input)) if several => Some(input.with_extension(extension)),
+            (None, _) => None,
+            (Some(output), _) if !several && !output.is_dir() => Some(output.clone()),
+            (Some(output), None) => Some(output.join("program").with_extension(extension)),
+            (Some(output), Some(input)) => {
+                let common = common_directory(&self.inputs);
+                let relative = input.strip_prefix(&common).unwrap_or(input.as_path());
+                let relative = if relative.is_absolute() {
+                    PathBuf::from(relative.file_name().unwrap_or_default())
+                } else {
+                    relative.to_path_buf()
+                };
+                Some(output.join(relative).with_extension(extension))
+            }
+        };
+        if let Some(directory) = path.as_ref().and_then(|path| path.parent()) {
+            if !directory.as_os_str().is_empty() {
+                std::fs::create_dir_all(directory)
+                    .with_context(|| format!("Cannot create output directory {}", directory.display()))?;
+            }
+        }
+        NamedSink::from(path)
+    }
+}
+
+impl AssemblerAction {
+    pub fn assemble(&self) {
+        let mut source = NamedSource::from(self.input.as_ref())
+            .expect("Cannot open an input for the assembler.");
+
+        let program = match BCSerializer::STRING.deserialize(&mut source) {
+            Ok(program) => program,
+            Err(error) => {
+                eprintln!("{}: error: {}", input_name(self.input.as_ref()), error);
+                std::process::exit(1)
+            }
+        };
+
+        let mut sink = NamedSink::from(self.output.clone())
+            .expect("Cannot open an output for the assembler.");
+        self.output_format.unwrap_or(BCSerializer::BYTES).serialize(&program, &mut sink)
+            .and_then(|_| sink.flush().map_err(|error| error.into()))
+            .expect("Cannot write bytecode.");
+    }
+}
+
+fn input_name(input: Option<&PathBuf>) -> String {
+    input.map_or("<stdin>".to_owned(), |path| path.display().to_string())
+}
+
+// Reports why the bytecode cannot be loaded instead of panicking, since a bad file is not a bug.
+fn load_bytecode(input: Option<&PathBuf>) -> Program {
+    let loaded = NamedSource::from(input)
+        .and_then(|mut source| BCSerializer::BYTES.deserialize(&mut source));
+    match loaded {
+        Ok(program) => program,
+        Err(error) => {
+            eprintln!("{}: error: Cannot load bytecode: {:#}", input_name(input), error);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn verify_or_exit(input: Option<&PathBuf>, program: &Program) {
+    if let Err(error) = verify(program) {
+        match error.downcast_ref::<VerificationFailed>() {
+            Some(VerificationFailed(violations)) => {
+                for violation in violations {
+                    eprintln!("{}: error: {}", input_name(input), violation);
+                }
+                if violations.len() > 1 {
+                    eprintln!("{} verification errors", violations.len());
+                }
+            }
+            None => eprintln!("{}: error: {}", input_name(input), error),
+        }
+        std::process::exit(1);
     }
 }
 
+// The longest directory that contains all the paths.
+fn common_directory(paths: &[PathBuf]) -> PathBuf {
+    let mut common: Option<Vec<std::path::Component>> = None;
+    for path in paths {
+        let directory: Vec<_> = path.parent().map_or(Vec::new(), |parent| parent.components().collect());
+        common = Some(match common {
+            None => directory,
+            Some(common) => common.into_iter()
+                .zip(directory)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    common.unwrap_or_default().iter().collect()
+}
+
 impl ParserAction {
     pub fn parse(&self) {
         let source = self.selected_input()
@@ -241,16 +715,26 @@ impl ParserAction {
             .expect("Cannot open an output for the parser.");
         let serializer = self.selected_output_format();
 
-        let ast: AST = TopLevelParser::new()
-            .parse(&source.into_string()
-            .expect("Error reading input"))
-            .expect("Parse error");
+        let source = source.into_source_file()
+            .expect("Error reading input");
+
+        let (ast, diagnostics) = parser::parse_with_recovery(&source.text);
+        report_parse_diagnostics(&source, &diagnostics);
+
+        if diagnostics.is_empty() || self.partial {
+            if let Some(ast) = ast {
+                let ast = if self.spans { ast } else { ast.strip_locations() };
+                let result = serializer.serialize(&ast)
+                    .expect("Cannot serialize AST");
 
-        let result = serializer.serialize(&ast)
-            .expect("Cannot serialize AST");
+                write!(sink, "{}", result)
+                    .expect("Cannot write to output");
+            }
+        }
 
-        write!(sink, "{}", result)
-            .expect("Cannot write to output");
+        if !diagnostics.is_empty() {
+            std::process::exit(1);
+        }
     }
 
     pub fn selected_input(&self) -> Result<NamedSource> {
@@ -278,24 +762,36 @@ impl ParserAction {
 
 #[derive(Debug, PartialOrd, PartialEq, Ord, Eq, Copy, Clone, Hash)]
 enum BCSerializer {
-    BYTES, STRING
+    BYTES, HEADER, STRING
 }
 
 impl BCSerializer {
     pub fn serialize(&self, program: &Program, sink: &mut NamedSink) -> Result<()> {
         match self {
             BCSerializer::BYTES  => program.serialize(sink),
-            BCSerializer::STRING => unimplemented!(),
+            BCSerializer::HEADER => program.serialize_with_header(sink),
+            BCSerializer::STRING => {
+                program.pretty_print(sink);
+                writeln!(sink).map_err(|error| error.into())
+            }
         }
     }
 
     pub fn deserialize(&self, source: &mut NamedSource) -> Result<Program> {
-        Ok(Program::from_bytes(source))
+        match self {
+            BCSerializer::BYTES | BCSerializer::HEADER => Program::from_bytes(&mut OffsetReader::new(source)),
+            BCSerializer::STRING => {
+                let mut text = String::new();
+                source.read_to_string(&mut text)?;
+                bytecode::assemble(&text)
+            }
+        }
     }
 
     pub fn extension(&self) -> &'static str {
         match self {
             BCSerializer::BYTES  => "bc",
+            BCSerializer::HEADER => "bc",
             BCSerializer::STRING => "bc.txt",
         }
     }
@@ -306,7 +802,8 @@ impl std::str::FromStr for BCSerializer {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
-            "bytes" | "bc" | "bytecode"                  => Ok(Self::BYTES),
+            "bytes" | "bc" | "bytecode" | "feeny"        => Ok(Self::BYTES),
+            "header" | "versioned"                       => Ok(Self::HEADER),
             "string" | "str" | "pp" | "pretty" | "print" => Ok(Self::STRING),
             format => Err(anyhow::anyhow!("Unknown BC serialization format: {}", format))
         }
@@ -422,14 +919,12 @@ impl NamedSource {
         self.source.read_to_string(&mut string)?;
         Ok(string)
     }
-    fn extension(&self) -> Option<String> {
-        match &self.name {
-            Stream::File(file) => {
-                PathBuf::from(file).extension()
-                    .map(|s| s.to_str().unwrap().to_owned())
-            }
-            Stream::Console => None
-        }
+    fn into_source_file(self) -> Result<SourceFile> {
+        let name = match &self.name {
+            Stream::File(file) => file.clone(),
+            Stream::Console => "<stdin>".to_owned(),
+        };
+        self.into_string().map(|text| SourceFile::new(name, text))
     }
 }
 
@@ -0,0 +1,103 @@
+use lalrpop_util::ParseError;
+
+use crate::parser::Span;
+
+// The text of a source file, used to turn byte spans into lines, columns and excerpts.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct SourceFile { pub name: String, pub text: String }
+
+impl SourceFile {
+    pub fn new<S: Into<String>, T: Into<String>>(name: S, text: T) -> Self {
+        SourceFile { name: name.into(), text: text.into() }
+    }
+
+    fn clamp(&self, offset: usize) -> usize {
+        let mut offset = std::cmp::min(offset, self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    fn line_bounds(&self, offset: usize) -> (usize, usize) {
+        let start = self.text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+        let end = self.text[offset..].find('\n').map_or(self.text.len(), |newline| offset + newline);
+        (start, end)
+    }
+
+    // Lines and columns count from 1, columns are counted in characters.
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let offset = self.clamp(offset);
+        let (line_start, _) = self.line_bounds(offset);
+        let line = self.text[..offset].matches('\n').count() + 1;
+        let column = self.text[line_start..offset].chars().count() + 1;
+        (line, column)
+    }
+
+    pub fn line(&self, line: usize) -> Option<&str> {
+        self.text.lines().nth(line.checked_sub(1)?)
+    }
+
+    // Renders `file:line:col: error: message` followed by the first line of the span, underlined.
+    pub fn render(&self, span: &Span, message: &str) -> String {
+        let start = self.clamp(span.start);
+        let (line, column) = self.location(start);
+        let (line_start, line_end) = self.line_bounds(start);
+        let end = std::cmp::max(start, std::cmp::min(self.clamp(span.end), line_end));
+
+        let excerpt = self.text[line_start..line_end].trim_end_matches('\r');
+        let indent = self.text[line_start..start].chars().count();
+        let width = std::cmp::max(1, self.text[start..end].chars().count());
+        let gutter = " ".repeat(line.to_string().len());
+
+        format!("{}:{}:{}: error: {}\n{} |\n{} | {}\n{} | {}{}\n",
+                self.name, line, column, message,
+                gutter,
+                line, excerpt,
+                gutter, " ".repeat(indent), "^".repeat(width))
+    }
+}
+
+// A syntax error, with the tokens the parser would have accepted in its place. Errors at the end
+// of the input mean it is unfinished rather than wrong.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct ParseDiagnostic { pub span: Span, pub message: String, pub expected: Vec<String>, pub unexpected_end: bool }
+
+impl ParseDiagnostic {
+    pub fn from_parse_error<T, E>(error: &ParseError<usize, T, E>) -> Self
+        where T: std::fmt::Display, E: std::fmt::Display {
+
+        fn expected(tokens: &[String]) -> String {
+            match tokens.len() {
+                0 => String::new(),
+                1 => format!(", expected {}", tokens[0]),
+                _ => format!(", expected one of {}", tokens.join(", ")),
+            }
+        }
+
+        let (span, message, expected_tokens) = match error {
+            ParseError::InvalidToken { location } =>
+                (Span::new(*location, *location + 1), "invalid token".to_owned(), vec![]),
+            ParseError::UnrecognizedEOF { location, expected: tokens } =>
+                (Span::new(*location, *location), format!("unexpected end of file{}", expected(tokens)), tokens.clone()),
+            ParseError::UnrecognizedToken { token: (start, token, end), expected: tokens } =>
+                (Span::new(*start, *end), format!("unexpected `{}`{}", token, expected(tokens)), tokens.clone()),
+            ParseError::ExtraToken { token: (start, token, end) } =>
+                (Span::new(*start, *end), format!("extra token `{}`", token), vec![]),
+            ParseError::User { error } =>
+                (Span::new(0, 0), error.to_string(), vec![]),
+        };
+        let unexpected_end = matches!(error, ParseError::UnrecognizedEOF { .. });
+        ParseDiagnostic { span, message, expected: expected_tokens, unexpected_end }
+    }
+
+    pub fn shifted(self, offset: usize) -> Self {
+        ParseDiagnostic { span: Span::new(self.span.start + offset, self.span.end + offset), ..self }
+    }
+}
+
+impl std::fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.message, self.span)
+    }
+}
@@ -1,39 +1,88 @@
+pub mod diagnostics;
+
 use std::fmt::Debug;
 use std::cmp::PartialEq;
 use serde::{Serialize, Deserialize};
 
+use crate::fml::TopLevelParser;
+use diagnostics::ParseDiagnostic;
+
+// Parses a whole FML file. Syntax errors at statement and member boundaries are recovered from,
+// so every error in the file is reported, along with the AST, with spans, of everything that did parse.
+pub fn parse_with_recovery(text: &str) -> (Option<AST>, Vec<ParseDiagnostic>) {
+    let mut errors = Vec::new();
+    let result = TopLevelParser::new().parse(&mut errors, text);
+    let mut diagnostics: Vec<ParseDiagnostic> = errors.iter()
+        .map(|recovery| ParseDiagnostic::from_parse_error(&recovery.error))
+        .collect();
+    match result {
+        Ok(ast) => (Some(ast), diagnostics),
+        Err(error) => {
+            diagnostics.push(ParseDiagnostic::from_parse_error(&error));
+            (None, diagnostics)
+        }
+    }
+}
+
+// Whether the text is the beginning of a longer input, like an unfinished `begin ... end` block.
+pub fn is_incomplete(text: &str) -> bool {
+    parse_with_recovery(text).1.iter().any(|diagnostic| diagnostic.unexpected_end)
+}
+
+// The AST without spans, which is equal to the same AST built by hand.
+#[allow(dead_code)]
+pub fn parse(text: &str) -> Result<AST, Vec<ParseDiagnostic>> {
+    parse_with_spans(text).map(AST::strip_locations)
+}
+
+// Like `parse`, but each node keeps the span it was parsed from, so compiler and runtime errors can
+// point into the source.
+pub fn parse_with_spans(text: &str) -> Result<AST, Vec<ParseDiagnostic>> {
+    match parse_with_recovery(text) {
+        (Some(ast), diagnostics) if diagnostics.is_empty() => Ok(ast),
+        (_, diagnostics) => Err(diagnostics),
+    }
+}
+
+// Nodes that can fail to compile record the span of the source they were parsed from. Spans are
+// optional, so ASTs built by hand or serialized without them still compile.
 #[derive(PartialEq,Debug,Serialize,Deserialize,Clone)]
 pub enum AST {
     Integer(i32),
     Boolean(bool),
+    String(String),
     Null,
 
-    Variable { name: Identifier, value: Box<AST> },
-    Array { size: Box<AST>, value: Box<AST> },
-    Object { extends: Box<AST>, members: Vec<Box<AST>> },
+    Variable { name: Identifier, value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Array { size: Box<AST>, value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Object { extends: Box<AST>, members: Vec<Box<AST>>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
 
-    AccessVariable { name: Identifier },
-    AccessField { object: Box<AST>, field: Identifier },
-    AccessArray { array: Box<AST>, index: Box<AST> },
+    AccessVariable { name: Identifier, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    AccessField { object: Box<AST>, field: Identifier, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    AccessArray { array: Box<AST>, index: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
 
-    AssignVariable { name: Identifier, value: Box<AST> },
-    AssignField { object: Box<AST>, field: Identifier, value: Box<AST> },
-    AssignArray { array: Box<AST>, index: Box<AST>, value: Box<AST> },
+    AssignVariable { name: Identifier, value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    AssignField { object: Box<AST>, field: Identifier, value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    AssignArray { array: Box<AST>, index: Box<AST>, value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
 
-    Function { name: Identifier, parameters: Vec<Identifier>, body: Box<AST> },
+    Function { name: Identifier, parameters: Vec<Identifier>, body: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Lambda { parameters: Vec<Identifier>, body: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
     //Operator { operator: Operator, parameters: Vec<Identifier>, body: Box<AST> },    // TODO Consider merging with function
 
-    CallFunction { name: Identifier, arguments: Vec<Box<AST>> },
-    CallMethod { object: Box<AST>, name: Identifier, arguments: Vec<Box<AST>> },
+    CallFunction { name: Identifier, arguments: Vec<Box<AST>>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    CallMethod { object: Box<AST>, name: Identifier, arguments: Vec<Box<AST>>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
     //CallOperator { object: Box<AST>, operator: Operator, arguments: Vec<Box<AST>> }, // TODO Consider removing
     //Operation { operator: Operator, left: Box<AST>, right: Box<AST> },               // TODO Consider removing
 
     Top (Vec<Box<AST>>),
     Block (Vec<Box<AST>>),
-    Loop { condition: Box<AST>, body: Box<AST> },
-    Conditional { condition: Box<AST>, consequent: Box<AST>, alternative: Box<AST> },
+    Loop { condition: Box<AST>, body: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Break { value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Continue { #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Return { value: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
+    Conditional { condition: Box<AST>, consequent: Box<AST>, alternative: Box<AST>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
 
-    Print { format: String, arguments: Vec<Box<AST>> },
+    Print { format: String, arguments: Vec<Box<AST>>, #[serde(default, skip_serializing_if = "Option::is_none")] span: Option<Span> },
 }
 
 impl AST {
@@ -45,43 +94,69 @@ impl AST {
         Self::Boolean(b)
     }
 
+    pub fn string(s: String) -> Self {
+        Self::String(s)
+    }
+
+    // The literal as written in the source, without its quotes. Escape sequences are the same as
+    // in print formats, which the lexer has already checked.
+    pub fn string_literal(literal: &str) -> Self {
+        let mut string = String::new();
+        let mut characters = literal.chars();
+        while let Some(character) = characters.next() {
+            let character = match character {
+                '\\' => match characters.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(escaped) => escaped,
+                    None => '\\',
+                },
+                character => character,
+            };
+            string.push(character);
+        }
+        Self::String(string)
+    }
+
     pub fn null() -> Self {
         Self::Null
     }
 
     pub fn variable(name: Identifier, value: AST) -> Self {
-        Self::Variable { name, value: value.into_boxed() }
+        Self::Variable { name, value: value.into_boxed(), span: None }
     }
 
     pub fn array(size: AST, value: AST) -> Self {
-        Self::Array { size: size.into_boxed(), value: value.into_boxed() }
+        Self::Array { size: size.into_boxed(), value: value.into_boxed(), span: None }
     }
 
     pub fn object(extends: AST, members: Vec<AST>) -> Self {
-        Self::Object { extends: extends.into_boxed(), members: members.into_boxed() }
+        Self::Object { extends: extends.into_boxed(), members: members.into_boxed(), span: None }
     }
 
     pub fn access_variable(name: Identifier) -> Self {
-        Self::AccessVariable { name }
+        Self::AccessVariable { name, span: None }
     }
 
     pub fn access_field(object: AST, field: Identifier) -> Self {
-        Self::AccessField { object: object.into_boxed(), field }
+        Self::AccessField { object: object.into_boxed(), field, span: None }
     }
 
     pub fn access_array(array: AST, index: AST) -> Self {
-        Self::AccessArray { array: array.into_boxed(), index: index.into_boxed() }
+        Self::AccessArray { array: array.into_boxed(), index: index.into_boxed(), span: None }
     }
 
     pub fn assign_variable(name: Identifier, value: AST) -> Self {
-        Self::AssignVariable { name, value: value.into_boxed() }
+        Self::AssignVariable { name, value: value.into_boxed(), span: None }
     }
 
     pub fn assign_field(object: AST, field: Identifier, value: AST) -> Self {
         Self::AssignField {
             object: object.into_boxed(),
             field,
-            value: value.into_boxed()
+            value: value.into_boxed(),
+            span: None
         }
     }
 
@@ -89,34 +164,41 @@ impl AST {
         Self::AssignArray {
             array: array.into_boxed(),
             index: index.into_boxed(),
-            value: value.into_boxed()
+            value: value.into_boxed(),
+            span: None
         }
     }
 
     pub fn function(name: Identifier, parameters: Vec<Identifier>, body: AST) -> Self {
-        Self::Function { name, parameters, body: body.into_boxed() }
+        Self::Function { name, parameters, body: body.into_boxed(), span: None }
+    }
+
+    pub fn lambda(parameters: Vec<Identifier>, body: AST) -> Self {
+        Self::Lambda { parameters, body: body.into_boxed(), span: None }
     }
 
     pub fn operator(operator: Operator, parameters: Vec<Identifier>, body: AST) -> Self {
-        Self::Function { name: Identifier::from(operator), parameters, body: body.into_boxed() }
+        Self::Function { name: Identifier::from(operator), parameters, body: body.into_boxed(), span: None }
     }
 
     pub fn call_function(name: Identifier, arguments: Vec<AST>) -> Self {
-        Self::CallFunction { name, arguments: arguments.into_boxed() }
+        Self::CallFunction { name, arguments: arguments.into_boxed(), span: None }
     }
 
     pub fn call_method(object: AST, name: Identifier, arguments: Vec<AST>) -> Self {
         Self::CallMethod {
             object: object.into_boxed(),
             name,
-            arguments: arguments.into_boxed() }
+            arguments: arguments.into_boxed(),
+            span: None }
     }
 
     pub fn call_operator(object: AST, operator: Operator, arguments: Vec<AST>) -> Self {
         Self::CallMethod {
             object: object.into_boxed(),
             name: Identifier::from(operator),
-            arguments: arguments.into_boxed()
+            arguments: arguments.into_boxed(),
+            span: None
         }
     }
 
@@ -125,7 +207,8 @@ impl AST {
         Self::CallMethod {
             object: left.into_boxed(),
             name: Identifier::from(operator),
-            arguments: vec![right.into_boxed()]
+            arguments: vec![right.into_boxed()],
+            span: None
         }
     }
 
@@ -138,19 +221,136 @@ impl AST {
     }
 
     pub fn loop_de_loop(condition: AST, body: AST) -> Self {
-        Self::Loop { condition: condition.into_boxed(), body: body.into_boxed() }
+        Self::Loop { condition: condition.into_boxed(), body: body.into_boxed(), span: None }
+    }
+
+    pub fn break_loop(value: AST) -> Self {
+        Self::Break { value: value.into_boxed(), span: None }
+    }
+
+    pub fn continue_loop() -> Self {
+        Self::Continue { span: None }
+    }
+
+    pub fn return_value(value: AST) -> Self {
+        Self::Return { value: value.into_boxed(), span: None }
     }
 
     pub fn conditional(condition: AST, consequent: AST, alternative: AST) -> Self {
         Self::Conditional {
             condition: condition.into_boxed(),
             consequent: consequent.into_boxed(),
-            alternative: alternative.into_boxed()
+            alternative: alternative.into_boxed(),
+            span: None
         }
     }
 
     pub fn print(format: String, arguments: Vec<AST>) -> Self {
-        Self::Print { format, arguments: arguments.into_boxed() }
+        Self::Print { format, arguments: arguments.into_boxed(), span: None }
+    }
+
+    pub fn located(self, start: usize, end: usize) -> Self {
+        self.with_span(Span::new(start, end))
+    }
+
+    // Literals, blocks and the top level cannot fail to compile, so they have no span to set.
+    pub fn with_span(mut self, span: Span) -> Self {
+        if let Some(location) = self.location_mut() {
+            *location = Some(span);
+        }
+        self
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            AST::Variable { span, .. } | AST::Array { span, .. } | AST::Object { span, .. }
+            | AST::AccessVariable { span, .. } | AST::AccessField { span, .. } | AST::AccessArray { span, .. }
+            | AST::AssignVariable { span, .. } | AST::AssignField { span, .. } | AST::AssignArray { span, .. }
+            | AST::Function { span, .. } | AST::Lambda { span, .. }
+            | AST::CallFunction { span, .. } | AST::CallMethod { span, .. }
+            | AST::Loop { span, .. } | AST::Break { span, .. } | AST::Continue { span } | AST::Return { span, .. }
+            | AST::Conditional { span, .. } | AST::Print { span, .. } => *span,
+            AST::Integer(_) | AST::Boolean(_) | AST::String(_) | AST::Null | AST::Top(_) | AST::Block(_) => None,
+        }
+    }
+
+    fn location_mut(&mut self) -> Option<&mut Option<Span>> {
+        match self {
+            AST::Variable { span, .. } | AST::Array { span, .. } | AST::Object { span, .. }
+            | AST::AccessVariable { span, .. } | AST::AccessField { span, .. } | AST::AccessArray { span, .. }
+            | AST::AssignVariable { span, .. } | AST::AssignField { span, .. } | AST::AssignArray { span, .. }
+            | AST::Function { span, .. } | AST::Lambda { span, .. }
+            | AST::CallFunction { span, .. } | AST::CallMethod { span, .. }
+            | AST::Loop { span, .. } | AST::Break { span, .. } | AST::Continue { span } | AST::Return { span, .. }
+            | AST::Conditional { span, .. } | AST::Print { span, .. } => Some(span),
+            AST::Integer(_) | AST::Boolean(_) | AST::String(_) | AST::Null | AST::Top(_) | AST::Block(_) => None,
+        }
+    }
+
+    pub fn strip_locations(mut self) -> AST {
+        self.map_locations(&|_| None);
+        self
+    }
+
+    // Moves every span by `offset` bytes, for ASTs parsed from a part of a larger source.
+    pub fn shift_locations(mut self, offset: usize) -> AST {
+        self.map_locations(&|span| Some(Span::new(span.start + offset, span.end + offset)));
+        self
+    }
+
+    // Replaces each span in the tree with `f(span)`, or removes it if `None`.
+    fn map_locations(&mut self, f: &dyn Fn(Span) -> Option<Span>) {
+        if let Some(location) = self.location_mut() {
+            *location = location.and_then(f);
+        }
+        let children: Vec<&mut Box<AST>> = match self {
+            AST::Integer(_) | AST::Boolean(_) | AST::String(_) | AST::Null
+            | AST::AccessVariable { .. } | AST::Continue { .. } => vec![],
+            AST::Variable { value, .. } | AST::AssignVariable { value, .. }
+            | AST::Break { value, .. } | AST::Return { value, .. } => vec![value],
+            AST::Array { size, value, .. } => vec![size, value],
+            AST::Object { extends, members, .. } => std::iter::once(extends).chain(members.iter_mut()).collect(),
+            AST::AccessField { object, .. } => vec![object],
+            AST::AccessArray { array, index, .. } => vec![array, index],
+            AST::AssignField { object, value, .. } => vec![object, value],
+            AST::AssignArray { array, index, value, .. } => vec![array, index, value],
+            AST::Function { body, .. } | AST::Lambda { body, .. } => vec![body],
+            AST::CallFunction { arguments, .. } | AST::Print { arguments, .. } => arguments.iter_mut().collect(),
+            AST::CallMethod { object, arguments, .. } => std::iter::once(object).chain(arguments.iter_mut()).collect(),
+            AST::Top(children) | AST::Block(children) => children.iter_mut().collect(),
+            AST::Loop { condition, body, .. } => vec![condition, body],
+            AST::Conditional { condition, consequent, alternative, .. } => vec![condition, consequent, alternative],
+        };
+        children.into_iter().for_each(|child| child.map_locations(f));
+    }
+}
+
+// Byte offsets into the source, `end` is exclusive.
+#[derive(PartialEq,Eq,Debug,Copy,Clone,Serialize,Deserialize)]
+pub struct Span { pub start: usize, pub end: usize }
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    pub fn join(&self, other: &Span) -> Span {
+        Span::new(std::cmp::min(self.start, other.start), std::cmp::max(self.end, other.end))
+    }
+
+    // Errors keep the innermost span they were raised in.
+    pub fn attach_to(self, error: anyhow::Error) -> anyhow::Error {
+        if error.downcast_ref::<Span>().is_some() {
+            error
+        } else {
+            error.context(self)
+        }
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "at bytes {}..{}", self.start, self.end)
     }
 }
 
@@ -270,12 +470,19 @@ macro_rules! make_operator_ast {
 }
 
 impl AST {
-    pub fn from_binary_expression(first_operand: AST, other_operators_and_operands: Vec<(Operator, AST)>) -> Self {
+    // Folds `a + b - c` into nested operations, each spanning from `start` to its right operand.
+    pub fn from_binary_expression(start: usize, first_operand: AST, other_operators_and_operands: Vec<(Operator, AST, usize)>) -> Self {
         other_operators_and_operands.into_iter()
-            .fold(first_operand, |left, (operator, right)| {
-                AST::operation(operator, left, right)
+            .fold(first_operand, |left, (operator, right, end)| {
+                AST::operation(operator, left, right).located(start, end)
             })
     }
+
+    // Folds `object.a.b.c` into nested field accesses, each spanning from the object to its field.
+    pub fn from_field_chain(start: usize, object: AST, fields: Vec<(Identifier, usize)>) -> Self {
+        fields.into_iter()
+            .fold(object, |left, (field, end)| AST::access_field(left, field).located(start, end))
+    }
 }
 
 pub trait IntoBoxed {
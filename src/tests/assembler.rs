@@ -0,0 +1,156 @@
+use std::io::Cursor;
+
+use crate::bytecode::assemble;
+use crate::bytecode::debug::PrettyPrint;
+use crate::bytecode::interpreter::evaluate_with;
+use crate::bytecode::program::Program;
+use crate::bytecode::serializable::Serializable;
+use crate::bytecode::state::State;
+
+fn run(program: &Program) -> String {
+    let mut state = State::from(program).unwrap();
+    let mut output = String::new();
+    evaluate_with(program, &mut state, &mut output).unwrap();
+    output
+}
+
+fn text(program: &Program) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    program.pretty_print(&mut bytes);
+    String::from_utf8(bytes).unwrap()
+}
+
+fn bytes(program: &Program) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    program.serialize(&mut bytes).unwrap();
+    bytes
+}
+
+fn error(text: &str) -> String {
+    assemble(text).unwrap_err().to_string()
+}
+
+#[test] fn round_trip_through_bytes() {
+    let source = r#"
+        let a = array(3, 0);
+        let o = object begin let v = 1; function m(x) -> this.v + x; end;
+        function f(n) -> if n == 0 then 0 else n + f(n - 1);
+        let i = 0;
+        while i < 3 do begin a[i] <- o.m(i); i <- i + 1; end;
+        print("~\t~ \"~\"\n", f(4), a, i);
+    "#;
+    let ast = crate::parser::parse(source).unwrap();
+    let compiled = crate::bytecode::compile(&ast).unwrap();
+    let written = text(&compiled);
+
+    let assembled = assemble(&written).unwrap();
+    let deserialized = Program::from_bytes(&mut Cursor::new(bytes(&assembled))).unwrap();
+    assert_eq!(text(&deserialized), written);
+    assert_eq!(bytes(&deserialized), bytes(&compiled));
+    assert_eq!(run(&deserialized), run(&compiled));
+}
+
+#[test] fn round_trip_examples_through_text() {
+    let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths: Vec<_> = vec![root.join("examples"), root.join("tests").join("misc")].into_iter()
+        .flat_map(|directory| std::fs::read_dir(directory).unwrap())
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |extension| extension == "fml"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    // The brainfuck example nests deeper than the default test thread stack allows.
+    std::thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(move || {
+        for path in paths {
+            let source = std::fs::read_to_string(&path).unwrap();
+            let ast = crate::parser::parse(&source).unwrap();
+            let compiled = crate::bytecode::compile(&ast).unwrap();
+            let written = text(&compiled);
+
+            let assembled = assemble(&written)
+                .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            assert_eq!(text(&assembled), written, "{}", path.display());
+            assert_eq!(bytes(&assembled), bytes(&compiled), "{}", path.display());
+        }
+    }).unwrap().join().unwrap();
+}
+
+#[test] fn empty_class() {
+    let ast = crate::parser::parse("let o = object begin end; print(\"~\", o);").unwrap();
+    let compiled = crate::bytecode::compile(&ast).unwrap();
+    let written = text(&compiled);
+    assert!(written.contains("Class()"));
+
+    let assembled = assemble(&written).unwrap();
+    assert_eq!(bytes(&assembled), bytes(&compiled));
+    assert_eq!(run(&assembled), "object()");
+}
+
+#[test] fn named_constants_and_symbolic_labels() {
+    let program = assemble(r#"
+        Constants :
+            #format: String("~")   ; printed on every iteration
+            #zero: Int(0)
+            #one: Int(1)
+            #three: Int(3)
+            #less: String("<")
+            #plus: String("+")
+            #null: Null
+            #name: String("λ:")
+            #entry: Method(#name, nargs:0, nlocals:1) :
+                  lit #zero
+                  set local 0
+                  drop
+                  goto test
+               label body
+                  get local 0
+                  printf #format 1
+                  drop
+                  get local 0
+                  lit #one
+                  call slot #plus 2
+                  set local 0
+                  drop
+               label test
+                  get local 0
+                  lit #three
+                  call slot #less 2
+                  branch body
+                  lit #null
+                  return
+        Globals :
+        Entry : #entry
+    "#).unwrap();
+
+    assert_eq!(run(&program), "012");
+    assert_eq!(program.constant_pool.size(), 11);
+    assert!(text(&program).contains("#9: String(\"test\")\n    #10: String(\"body\")"));
+}
+
+#[test] fn numbered_constants_must_be_in_order() {
+    assert_eq!(error("Constants :\n #0: Null\n #2: Null\nGlobals :\nEntry : #0"),
+               "Line 3: Constant #2 is defined at position 1.");
+}
+
+#[test] fn references_must_be_defined() {
+    assert_eq!(error("Constants :\n #0: Slot(#1)\nGlobals :\nEntry : #0"),
+               "Line 2: There is no constant #1.");
+    assert_eq!(error("Constants :\n #0: Slot(#x)\nGlobals :\nEntry : #0"),
+               "Line 2: There is no constant named #x.");
+    assert_eq!(error("Constants :\n #x: Null\n #x: Null\nGlobals :\nEntry : #x"),
+               "Line 3: Constant #x is already defined.");
+}
+
+#[test] fn labels_must_be_defined_once() {
+    let method = |body: &str| format!("Constants :\n #n: String(\"f\")\n #f: Method(#n, nargs:0, nlocals:0) :\n{}\nGlobals :\nEntry : #f", body);
+    assert_eq!(error(&method("goto nowhere\nreturn")), "Line 4: Label `nowhere` is never defined.");
+    assert_eq!(error(&method("label here\nlabel here\nreturn")), "Line 5: Label `here` is already defined on line 4.");
+}
+
+#[test] fn malformed_instructions() {
+    let method = |body: &str| format!("Constants :\n #n: String(\"f\")\n #f: Method(#n, nargs:0, nlocals:0) :\n{}\nGlobals :\nEntry : #f", body);
+    assert_eq!(error(&method("get field #n")), "Line 4: Unknown instruction `get field`.");
+    assert_eq!(error(&method("call #n 256")), "Line 4: 256 is not between 0 and 255.");
+    assert_eq!(error(&method("printf 1 #n")), "Line 4: Expected a constant, found `1`.");
+}
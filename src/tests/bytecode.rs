@@ -5,12 +5,12 @@ use crate::bytecode::serializable::*;
 use crate::bytecode::program::*;
 
 fn deserialize_test(expected: OpCode, input: Vec<u8>) {
-    assert_eq!(OpCode::from_bytes(&mut Cursor::new(input)), expected);
+    assert_eq!(OpCode::from_bytes(&mut Cursor::new(input)).unwrap(), expected);
 }
 
 fn deserialize_with_context_test(expected_object: ProgramObject, expected_code: Code, input: Vec<u8>) {
     let mut code = Code::new();
-    let object = ProgramObject::from_bytes(&mut Cursor::new(input), &mut code);
+    let object = ProgramObject::from_bytes(&mut Cursor::new(input), &mut code).unwrap();
     assert_eq!(object, expected_object);
     assert_eq!(code, expected_code);
 }
@@ -129,6 +129,12 @@ fn serialize_with_context_test<S>(expected: Vec<u8>, object: S, code: Code) wher
     deserialize_test(expected, bytes);
 }
 
+#[test] fn deserialize_closure () {
+    let expected = OpCode::Closure { function: ConstantPoolIndex::new(1), captured: Arity::new(2) };
+    let bytes = vec!(0x11, 0x01, 0x00, 0x02);
+    deserialize_test(expected, bytes);
+}
+
 #[test] fn serialize_label () {
     let expected = vec!(0x00, 0x01, 0x00);
     let object = OpCode::Label { name: ConstantPoolIndex::new(1) };
@@ -231,6 +237,12 @@ fn serialize_with_context_test<S>(expected: Vec<u8>, object: S, code: Code) wher
     serialize_test(expected, object);
 }
 
+#[test] fn serialize_closure () {
+    let expected = vec!(0x11, 0x01, 0x00, 0x02);
+    let object = OpCode::Closure { function: ConstantPoolIndex::new(1), captured: Arity::new(2) };
+    serialize_test(expected, object);
+}
+
 #[test] fn serialize_null () {
     let expected = vec!(0x01);
     let object = ProgramObject::Null;
@@ -360,4 +372,136 @@ fn serialize_with_context_test<S>(expected: Vec<u8>, object: S, code: Code) wher
                      0x0F);
 
     deserialize_with_context_test(object, code, bytes);
-}
\ No newline at end of file
+}
+// String("f"), Method(#0, nargs:0, nlocals:0) with `lit #0` and `return`, no globals, entry #1.
+fn small_program_bytes() -> Vec<u8> {
+    vec!(0x02, 0x00,
+         0x02, 0x01, 0x00, 0x00, 0x00, 0x66,
+         0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+         0x01, 0x00, 0x00,
+         0x0F,
+         0x00, 0x00,
+         0x01, 0x00)
+}
+
+fn deserialize_error_test(expected: &str, bytes: Vec<u8>) {
+    let error = Program::from_bytes(&mut Cursor::new(bytes)).unwrap_err();
+    assert_eq!(format!("{:#}", error), expected);
+}
+
+#[test] fn deserialize_small_program () {
+    let program = Program::from_bytes(&mut Cursor::new(small_program_bytes())).unwrap();
+    assert_eq!(program.code.length(), 2);
+}
+
+#[test] fn deserialize_unknown_opcode () {
+    let mut bytes = small_program_bytes();
+    bytes[21] = 0x42;
+    deserialize_error_test("Cannot read constant #1 at byte 8: Cannot read opcode at address 0001 (byte 21): \
+                            Expected an opcode tag at byte 21, found 0x42", bytes);
+}
+
+#[test] fn deserialize_truncated_opcode () {
+    let mut bytes = small_program_bytes();
+    bytes.truncate(20);
+    deserialize_error_test("Cannot read constant #1 at byte 8: Cannot read opcode at address 0000 (byte 18): \
+                            Expected a u16 at byte 19, found the end of the input", bytes);
+}
+
+#[test] fn deserialize_unknown_constant () {
+    let mut bytes = small_program_bytes();
+    bytes[2] = 0x09;
+    deserialize_error_test("Cannot read constant #0 at byte 2: Expected a constant tag at byte 2, found 0x09", bytes);
+}
+
+#[test] fn deserialize_invalid_utf8 () {
+    let mut bytes = small_program_bytes();
+    bytes[7] = 0xFF;
+    deserialize_error_test("Cannot read constant #0 at byte 2: \
+                            Expected a UTF-8 string at byte 7, found an invalid byte at byte 7", bytes);
+}
+
+#[test] fn deserialize_truncated_string () {
+    let mut bytes = small_program_bytes();
+    bytes[4] = 0x10;
+    deserialize_error_test("Cannot read constant #0 at byte 2: \
+                            Expected a string of 4097 bytes at byte 7, found the end of the input after 19", bytes);
+}
+
+#[test] fn deserialize_truncated_entry () {
+    let mut bytes = small_program_bytes();
+    bytes.truncate(25);
+    deserialize_error_test("Cannot read the entry point at byte 24: Expected a u16 at byte 24, found the end of the input", bytes);
+}
+
+fn with_header(version: u16, flags: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = b"FMLB".to_vec();
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.push(flags);
+    bytes.extend_from_slice(&adler32(&body).to_le_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+// Only for the small bodies used in these tests.
+fn adler32(bytes: &[u8]) -> u32 {
+    let a = 1 + bytes.iter().map(|byte| *byte as u32).sum::<u32>();
+    let b = bytes.len() as u32 + bytes.iter().rev().enumerate().map(|(i, byte)| (i as u32 + 1) * *byte as u32).sum::<u32>();
+    (b << 16) | a
+}
+
+#[test] fn serialize_writes_no_header () {
+    let program = Program::from_bytes(&mut Cursor::new(small_program_bytes())).unwrap();
+    let mut bytes = Vec::new();
+    program.serialize(&mut bytes).unwrap();
+    assert_eq!(bytes, small_program_bytes());
+}
+
+#[test] fn serialize_with_header () {
+    let program = Program::from_bytes(&mut Cursor::new(small_program_bytes())).unwrap();
+    let mut bytes = Vec::new();
+    program.serialize_with_header(&mut bytes).unwrap();
+    assert_eq!(bytes, with_header(FORMAT_VERSION, 0x00, small_program_bytes()));
+}
+
+#[test] fn deserialize_with_header () {
+    let bytes = with_header(FORMAT_VERSION, 0x00, small_program_bytes());
+    let program = Program::from_bytes(&mut Cursor::new(bytes)).unwrap();
+    assert_eq!(program.code.length(), 2);
+}
+
+#[test] fn deserialize_with_header_reports_file_offsets () {
+    let mut body = small_program_bytes();
+    body[21] = 0x42;
+    deserialize_error_test("Cannot read constant #1 at byte 19: Cannot read opcode at address 0001 (byte 32): \
+                            Expected an opcode tag at byte 32, found 0x42", with_header(FORMAT_VERSION, 0x00, body));
+}
+
+#[test] fn deserialize_newer_version () {
+    deserialize_error_test("Bytecode format version 2 is newer than this version of fml can read, which is up to version 1",
+                           with_header(2, 0x00, small_program_bytes()));
+}
+
+#[test] fn deserialize_unknown_flags () {
+    deserialize_error_test("Bytecode uses features this version of fml does not know (flags 0x80)",
+                           with_header(FORMAT_VERSION, 0x80, small_program_bytes()));
+}
+
+#[test] fn deserialize_bad_checksum () {
+    let mut bytes = with_header(FORMAT_VERSION, 0x00, small_program_bytes());
+    let last = bytes.len() - 1;
+    bytes[last] = 0x01;
+    deserialize_error_test("Bytecode is corrupt: the checksum in the header is 0x08CA0082, \
+                            but the contents sum to 0x08CB0083", bytes);
+}
+
+#[test] fn deserialize_truncated_header () {
+    deserialize_error_test("Expected a header of 11 bytes, found the end of the input at byte 6", b"FMLB\x01\x00".to_vec());
+}
+
+#[test] fn deserialize_trailing_bytes () {
+    let mut body = small_program_bytes();
+    body.push(0x00);
+    deserialize_error_test("Expected the end of the bytecode at byte 37, found 1 more bytes",
+                           with_header(FORMAT_VERSION, 0x00, body));
+}
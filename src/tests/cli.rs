@@ -0,0 +1,20 @@
+use std::path::PathBuf;
+
+use crate::common_directory;
+
+fn paths(paths: &[&str]) -> Vec<PathBuf> {
+    paths.iter().map(PathBuf::from).collect()
+}
+
+#[test] fn common_directory_of_a_tree() {
+    assert_eq!(common_directory(&paths(&["src/a/x.fml", "src/b/y.fml", "src/a/c/z.fml"])), PathBuf::from("src"));
+}
+
+#[test] fn common_directory_of_siblings() {
+    assert_eq!(common_directory(&paths(&["src/a/x.fml", "src/a/y.fml"])), PathBuf::from("src/a"));
+}
+
+#[test] fn common_directory_of_unrelated_paths() {
+    assert_eq!(common_directory(&paths(&["x.fml", "src/y.fml"])), PathBuf::from(""));
+    assert_eq!(common_directory(&paths(&["/tmp/x.fml", "/home/y.fml"])), PathBuf::from("/"));
+}
@@ -0,0 +1,237 @@
+use crate::bytecode::heap::*;
+use super::{compile, execute, run, error, compile_error, Setup};
+
+#[test] fn call_by_name_and_by_method() {
+    let source = r#"
+        let square = function (x) -> x * x;
+        print("~ ~", square(3), square.call(4))
+    "#;
+    assert_eq!(run(source).unwrap(), "9 16");
+}
+
+#[test] fn closures_capture_enclosing_locals() {
+    let source = r#"
+        function make_adder(n) -> function (x) -> x + n;
+        function main() -> begin
+            let add2 = make_adder(2);
+            let add5 = make_adder(5);
+            print("~ ~", add2(1), add5(1));
+        end;
+        main()
+    "#;
+    assert_eq!(run(source).unwrap(), "3 6");
+}
+
+#[test] fn assigning_a_variable_after_capturing_it_is_an_error() {
+    let source = r#"
+        function main() -> begin
+            let n = 0;
+            let inc = function () -> n + 1;
+            n <- 5;
+            inc()
+        end;
+        main()
+    "#;
+    assert!(compile_error(source).contains("Cannot assign to `n`: a function captures the value of a variable, not the variable itself"));
+
+    let source = "begin let n = 0; let inc = function () -> n + 1; n <- 5; inc() end";
+    assert!(compile_error(source).contains("Cannot assign to `n`: a function captures the value of a variable, not the variable itself"));
+}
+
+#[test] fn capturing_an_assigned_variable_is_an_error() {
+    let source = r#"
+        function main() -> begin
+            let n = 0;
+            let fs = array(3, null);
+            let i = 0;
+            while i < 3 do begin
+                fs[i] <- function () -> n;
+                n <- n + 1;
+                i <- i + 1;
+            end;
+        end;
+        main()
+    "#;
+    assert!(compile_error(source).contains("Cannot assign to `n`: a function captures the value of a variable, not the variable itself"));
+
+    let source = r#"
+        function main() -> begin
+            let n = 0;
+            n <- 5;
+            let f = function () -> n;
+            f()
+        end;
+        main()
+    "#;
+    assert!(compile_error(source).contains("Cannot assign to `n`: a function captures the value of a variable, not the variable itself"));
+}
+
+#[test] fn shadowing_variables_are_separate_from_captured_ones() {
+    let source = r#"
+        function main() -> begin
+            let n = 1;
+            let f = function () -> n;
+            begin let n = 2; n <- 3; print("~ ", n) end;
+            print("~", f());
+        end;
+        main()
+    "#;
+    assert_eq!(run(source).unwrap(), "3 1");
+}
+
+#[test] fn assigning_a_captured_variable_is_an_error() {
+    let source = r#"
+        function counter() -> begin
+            let n = 0;
+            function () -> n <- n + 1;
+        end;
+        counter()
+    "#;
+    assert!(compile_error(source).contains("Cannot assign to `n`: a function captures the value of a variable, not the variable itself"));
+}
+
+#[test] fn shadowing_a_captured_variable() {
+    let source = r#"
+        function main() -> begin
+            let x = 1;
+            let f = function () -> begin let y = x; let x = x + 1; x * 10 + y end;
+            print("~ ~", f(), x);
+        end;
+        main()
+    "#;
+    assert_eq!(run(source).unwrap(), "21 1");
+}
+
+#[test] fn capturing_the_variable_a_function_replaces() {
+    let source = r#"
+        function main() -> begin
+            let f = function (n) -> n + 1;
+            begin
+                let f = function (n) -> f(n) * 2;
+                print("~", f(3));
+            end;
+        end;
+        main()
+    "#;
+    assert_eq!(run(source).unwrap(), "8");
+}
+
+#[test] fn local_functions_cannot_refer_to_themselves() {
+    let source = r#"
+        function main() -> begin
+            let fact = function (n) -> if n == 0 then 1 else n * fact(n - 1);
+            fact(5)
+        end;
+        main()
+    "#;
+    assert!(compile_error(source).contains("Cannot use `fact` in the function assigned to it: a local function cannot refer to itself"));
+
+    let source = "let fact = function (n) -> if n == 0 then 1 else n * fact(n - 1); print(\"~\", fact(5))";
+    assert_eq!(run(source).unwrap(), "120");
+}
+
+#[test] fn shared_state_through_objects() {
+    let source = r#"
+        function counter() -> begin
+            let state = object begin let n = 0; end;
+            function () -> state.n <- state.n + 1;
+        end;
+        function main() -> begin
+            let next = counter();
+            next(); next();
+            print("~", next());
+        end;
+        main()
+    "#;
+    assert_eq!(run(source).unwrap(), "3");
+}
+
+#[test] fn nested_closures() {
+    let source = r#"
+        function main() -> begin
+            let a = 1;
+            let f = function (b) -> function (c) -> a + b + c;
+            let g = f(10);
+            print("~", g(100));
+        end;
+        main()
+    "#;
+    assert_eq!(run(source).unwrap(), "111");
+}
+
+#[test] fn closures_as_arguments_and_values() {
+    let source = r#"
+        function twice(f, x) -> f(f(x));
+        let inc = function (x) -> x + 1;
+        let fs = array(2, inc);
+        let o = object begin let f = inc; end;
+        print("~ ~ ~ ~ ~ ~", twice(inc, 0), fs[1].call(1), o.f.call(2), fs[0] == inc, inc == (function (x) -> x + 1), inc)
+    "#;
+    assert_eq!(run(source).unwrap(), "2 2 3 true false <function>");
+}
+
+#[test] fn arity_is_checked() {
+    assert!(error("let f = function (x, y) -> x; f(1)").contains("requires 2 arguments, but 1 were supplied"));
+    assert!(error("let f = function () -> 1; f.invoke()").contains("no method `invoke`"));
+}
+
+#[test] fn function_names_are_values() {
+    let source = r#"
+        function less(a, b) -> a < b;
+        function greater(a, b) -> a > b;
+        function first(xs, compare) -> if compare(xs[0], xs[1]) then xs[0] else xs[1];
+        let xs = array(2, 1);
+        xs[1] <- 2;
+        let fs = array(1, greater);
+        let o = object begin let compare = less; end;
+        print("~ ~ ~ ~ ~", first(xs, less), first(xs, fs[0]), o.compare.call(1, 2), less.apply(xs), less)
+    "#;
+    assert_eq!(run(source).unwrap(), "1 2 true true <function>");
+}
+
+#[test] fn function_references_compare_by_function() {
+    let source = r#"
+        function f() -> 1;
+        function g() -> 1;
+        let h = f;
+        print("~ ~ ~ ~", f == f, h == f, f == g, f != g)
+    "#;
+    assert_eq!(run(source).unwrap(), "true true false true");
+}
+
+#[test] fn variables_shadow_function_names() {
+    let source = r#"
+        function f() -> 1;
+        function g(f) -> f;
+        let r = g(2);
+        let f = 3;
+        print("~ ~ ~", r, f, f())
+    "#;
+    assert_eq!(run(source).unwrap(), "2 3 1");
+}
+
+#[test] fn function_references_check_arity() {
+    assert!(error("function f(x) -> x; let g = f; g(1, 2)").contains("Function `f` requires 1 arguments, but 2 were supplied"));
+    assert!(error("function f(x) -> x; f.apply(array(2, 0))").contains("Function `f` requires 1 arguments, but 2 were supplied"));
+    assert!(error("function f(x) -> x; f.apply(1)").contains("Function `apply` requires an array of arguments, but `1` was supplied"));
+    assert!(error("g").contains("No such global `g`."));
+}
+
+#[test] fn closures_survive_collection() {
+    let source = r#"
+        function make(i) -> begin let o = object begin let v = i; end; function () -> o.v; end;
+        let fs = array(10, null);
+        let i = 0;
+        while i < 10 do begin fs[i] <- make(i); i <- i + 1; end;
+        let sum = 0;
+        i <- 0;
+        while i < 10 do begin sum <- sum + fs[i].call(); i <- i + 1; end;
+        print("~", sum)
+    "#;
+    let program = compile(source).unwrap();
+    for collector in vec![GarbageCollector::MarkAndSweep, GarbageCollector::Copying, GarbageCollector::Generational] {
+        let (result, _, output) = execute(&program, Setup::heap(HeapConfig { collector, stress: true, ..HeapConfig::default() }));
+        result.unwrap();
+        assert_eq!(output, "45");
+    }
+}
@@ -136,7 +136,8 @@ use crate::bytecode::compiler::*;
 
 #[test] fn local_definition () {
     let ast = AST::Variable { name: Identifier::from("x"),
-        value: Box::new(AST::Integer(1)) };
+        value: Box::new(AST::Integer(1)),
+        span: None };
 
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::new();
@@ -168,7 +169,8 @@ use crate::bytecode::compiler::*;
 
 #[test] fn global_definition () {
     let ast = AST::Variable { name: Identifier::from("x"),
-        value: Box::new(AST::Integer(1)) };
+        value: Box::new(AST::Integer(1)),
+        span: None };
 
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::Top;
@@ -201,7 +203,7 @@ use crate::bytecode::compiler::*;
 }
 
 #[test] fn local_access_x () {
-    let ast = AST::AccessVariable { name: Identifier::from("x") };
+    let ast = AST::AccessVariable { name: Identifier::from("x"), span: None };
 
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::from_locals(vec!["x".to_string(), "y".to_string()]);
@@ -229,7 +231,7 @@ use crate::bytecode::compiler::*;
 }
 
 #[test] fn local_access_y () {
-    let ast = AST::AccessVariable { name: Identifier::from("y") };
+    let ast = AST::AccessVariable { name: Identifier::from("y"), span: None };
 
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::from_locals(vec!["x".to_string(), "y".to_string()]);
@@ -257,7 +259,7 @@ use crate::bytecode::compiler::*;
 }
 
 #[test] fn global_access () {
-    let ast = AST::AccessVariable { name: Identifier::from("x") };
+    let ast = AST::AccessVariable { name: Identifier::from("x"), span: None };
 
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::new();
@@ -287,7 +289,7 @@ use crate::bytecode::compiler::*;
 }
 
 #[test] fn global_access_from_elsewhere () {
-    let ast = AST::AccessVariable { name: Identifier::from("z") };
+    let ast = AST::AccessVariable { name: Identifier::from("z"), span: None };
 
     let mut global_environment = Environment::from_locals(vec!["x".to_string()]);
     let mut current_frame = Frame::new();
@@ -316,8 +318,67 @@ use crate::bytecode::compiler::*;
     assert_eq!(current_frame, expected_current_frame);
 }
 
+#[test] fn unused_local_access_is_dropped () {
+    let ast = AST::Block(vec![
+        Box::new(AST::AccessVariable { name: Identifier::from("x"), span: None }),
+        Box::new(AST::AccessVariable { name: Identifier::from("y"), span: None }),
+    ]);
+
+    let mut global_environment = Environment::new();
+    let mut current_frame = Frame::from_locals(vec!["x".to_string(), "y".to_string()]);
+
+    let program = ast.compile(&mut global_environment, &mut current_frame).unwrap();
+
+    let expected_code = Code::from(vec!(
+        /* 0 */ OpCode::GetLocal { index: LocalFrameIndex::new(0) },
+        /* 1 */ OpCode::Drop,
+        /* 2 */ OpCode::GetLocal { index: LocalFrameIndex::new(1) },
+    ));
+
+    let expected_constants = <ConstantPool as From<Vec<ProgramObject>>>::from(vec![]);
+
+    let expected_globals = Globals::from(vec![]);
+    let expected_entry = Entry::new();
+
+    let expected_program =
+        Program::from(expected_code, expected_constants, expected_globals, expected_entry).unwrap();
+
+    assert_eq!(program, expected_program);
+}
+
+#[test] fn unused_global_access_is_dropped () {
+    let ast = AST::Block(vec![
+        Box::new(AST::AccessVariable { name: Identifier::from("x"), span: None }),
+        Box::new(AST::Null),
+    ]);
+
+    let mut global_environment = Environment::new();
+    let mut current_frame = Frame::new();
+
+    let program = ast.compile(&mut global_environment, &mut current_frame).unwrap();
+
+    let expected_code = Code::from(vec!(
+        /* 0 */ OpCode::GetGlobal { name: ConstantPoolIndex::new(0) },
+        /* 1 */ OpCode::Drop,
+        /* 2 */ OpCode::Literal { index: ConstantPoolIndex::new(1) },
+    ));
+
+    let expected_constants = <ConstantPool as From<Vec<ProgramObject>>>::from(vec![
+        /* 0 */ ProgramObject::from_str("x"),
+        /* 1 */ ProgramObject::Null,
+    ]);
+
+    let expected_globals = Globals::from(vec![]);
+    let expected_entry = Entry::new();
+
+    let expected_program =
+        Program::from(expected_code, expected_constants, expected_globals, expected_entry).unwrap();
+
+    assert_eq!(program, expected_program);
+}
+
 #[test] fn loop_de_loop () {
-    let ast = AST::Loop { condition: Box::new(AST::Boolean(false)), body: Box::new(AST::Null) };
+    let ast = AST::Loop { condition: Box::new(AST::Boolean(false)), body: Box::new(AST::Null), span: None };
 
     let mut global_environment = Environment::new();
     let mut current_frame = Frame::new();
@@ -356,11 +417,95 @@ use crate::bytecode::compiler::*;
     assert_eq!(current_frame, expected_current_frame);
 }
 
+#[test] fn loop_with_break () {
+    let ast = AST::Loop { condition: Box::new(AST::Boolean(true)), body: Box::new(AST::break_loop(AST::Integer(1))), span: None };
+
+    let mut global_environment = Environment::new();
+    let mut current_frame = Frame::new();
+
+    let program = ast.compile(&mut global_environment, &mut current_frame).unwrap();
+
+    let expected_global_environment = Environment::new();
+    let expected_current_frame = Frame::new();
+
+    let expected_code = Code::from(vec!(
+        /* 0 */ OpCode::Jump { label: ConstantPoolIndex::new(1) },
+        /* 1 */ OpCode::Label { name: ConstantPoolIndex::new(0) },
+        /* 2 */ OpCode::Literal { index: ConstantPoolIndex::new(2) },
+        /* 3 */ OpCode::Jump { label: ConstantPoolIndex::new(3) },
+        /* 4 */ OpCode::Label { name: ConstantPoolIndex::new(1) },
+        /* 5 */ OpCode::Literal { index: ConstantPoolIndex::new(4) },
+        /* 6 */ OpCode::Branch { label: ConstantPoolIndex::new(0) },
+        /* 7 */ OpCode::Literal { index: ConstantPoolIndex::new(5) },
+        /* 8 */ OpCode::Label { name: ConstantPoolIndex::new(3) },
+    ));
+
+    let expected_constants = <ConstantPool as From<Vec<ProgramObject>>>::from(vec![
+        /* 0 */ ProgramObject::String("loop:body:0".to_string()),
+        /* 1 */ ProgramObject::String("loop:condition:0".to_string()),
+        /* 2 */ ProgramObject::Integer(1),
+        /* 3 */ ProgramObject::String("loop:end:0".to_string()),
+        /* 4 */ ProgramObject::Boolean(true),
+        /* 5 */ ProgramObject::Null,
+    ]);
+
+    let expected_globals = Globals::from(vec![]);
+    let expected_entry = Entry::new();
+
+    let expected_program =
+        Program::from(expected_code, expected_constants, expected_globals, expected_entry).unwrap();
+
+    assert_eq!(program, expected_program);
+    assert_eq!(global_environment, expected_global_environment);
+    assert_eq!(current_frame, expected_current_frame);
+}
+
+#[test] fn return_drops_pending_operands () {
+    let ast = AST::print("~ ~".to_string(), vec!(AST::Integer(1), AST::return_value(AST::Integer(2))));
+
+    let mut global_environment = Environment::new();
+    let mut current_frame = Frame::new();
+
+    let program = ast.compile(&mut global_environment, &mut current_frame).unwrap();
+
+    let mut expected_global_environment = Environment::new();
+    expected_global_environment.generate_unique_number();
+    let expected_current_frame = Frame::from_locals(vec!("::result_0".to_string()));
+
+    let expected_code = Code::from(vec!(
+        /* 0 */ OpCode::Literal { index: ConstantPoolIndex::new(1) },
+        /* 1 */ OpCode::Literal { index: ConstantPoolIndex::new(2) },
+        /* 2 */ OpCode::SetLocal { index: LocalFrameIndex::new(0) },
+        /* 3 */ OpCode::Drop,
+        /* 4 */ OpCode::Drop,
+        /* 5 */ OpCode::GetLocal { index: LocalFrameIndex::new(0) },
+        /* 6 */ OpCode::Return,
+        /* 7 */ OpCode::Print { format: ConstantPoolIndex::new(0), arguments: Arity::new(2) },
+    ));
+
+    let expected_constants = <ConstantPool as From<Vec<ProgramObject>>>::from(vec![
+        /* 0 */ ProgramObject::String("~ ~".to_string()),
+        /* 1 */ ProgramObject::Integer(1),
+        /* 2 */ ProgramObject::Integer(2),
+    ]);
+
+    let expected_globals = Globals::from(vec![]);
+    let expected_entry = Entry::new();
+
+    let expected_program =
+        Program::from(expected_code, expected_constants, expected_globals, expected_entry).unwrap();
+
+    assert_eq!(program, expected_program);
+    assert_eq!(global_environment, expected_global_environment);
+    assert_eq!(current_frame, expected_current_frame);
+}
+
 #[test] fn conditional () {
     let ast = AST::Conditional {
         condition: Box::new(AST::Boolean(true)),
         consequent: Box::new(AST::Integer(1)),
-        alternative: Box::new(AST::Integer(-1))
+        alternative: Box::new(AST::Integer(-1)),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -404,6 +549,7 @@ use crate::bytecode::compiler::*;
     let ast = AST::Array {
         value: Box::new(AST::Null),
         size: Box::new(AST::Integer(10)),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -441,8 +587,10 @@ use crate::bytecode::compiler::*;
         size: Box::new(AST::Integer(10)),
         value: Box::new(AST::CallFunction {
             name: Identifier::from("f"),
-            arguments: vec!()
+            arguments: vec!(),
+            span: None
         }),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -525,8 +673,9 @@ use crate::bytecode::compiler::*;
 
 #[test] fn array_access_test() {
     let ast = AST::AccessArray {
-        array: Box::new(AST::AccessVariable { name: Identifier("x".to_string()) }),
+        array: Box::new(AST::AccessVariable { name: Identifier("x".to_string()), span: None }),
         index: Box::new(AST::Integer(1)),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -561,9 +710,10 @@ use crate::bytecode::compiler::*;
 
 #[test] fn array_mutation_test() {
     let ast = AST::AssignArray {
-        array: Box::new(AST::AccessVariable { name: Identifier("x".to_string()) }),
+        array: Box::new(AST::AccessVariable { name: Identifier("x".to_string()), span: None }),
         index: Box::new(AST::Integer(1)),
         value: Box::new(AST::Integer(42)),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -605,6 +755,7 @@ use crate::bytecode::compiler::*;
             Box::new(AST::Integer(2)),
             Box::new(AST::Integer(5)),
         ),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -646,6 +797,7 @@ use crate::bytecode::compiler::*;
             Box::new(AST::Integer(0)),
             Box::new(AST::Boolean(true)),
         ),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -685,6 +837,7 @@ use crate::bytecode::compiler::*;
     let ast = AST::CallFunction {
         name: Identifier("f".to_string()),
         arguments: vec!(Box::new(AST::Integer(42))),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -719,7 +872,8 @@ use crate::bytecode::compiler::*;
 #[test] fn function_application_test_zero () {
     let ast = AST::CallFunction {
         name: Identifier("f".to_string()),
-        arguments: vec!()
+        arguments: vec!(),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -755,7 +909,8 @@ use crate::bytecode::compiler::*;
         parameters: vec!(Identifier::from("left"),
                          Identifier::from("middle"),
                          Identifier::from("right")),
-        body: Box::new(AST::AccessVariable { name: Identifier::from("left") })
+        body: Box::new(AST::AccessVariable { name: Identifier::from("left"), span: None }),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -801,46 +956,56 @@ use crate::bytecode::compiler::*;
             Box::new(AST::Function {
                 name: Identifier::from("implies"),
                 parameters: vec!(Identifier::from("x")),
-                body: Box::new(AST::Boolean(true))}),
+                body: Box::new(AST::Boolean(true)),
+                span: None}),
 
             Box::new(AST::Variable {
                 name: Identifier::from("id"),
-                value: Box::new(AST::Integer(1))}),
+                value: Box::new(AST::Integer(1)),
+                span: None}),
 
             Box::new(AST::Function {
                 name: Identifier::from("identity"),
                 parameters: vec!(),
-                body: Box::new(AST::Boolean(true))}),
+                body: Box::new(AST::Boolean(true)),
+                span: None}),
 
             Box::new(AST::Function {
                 name: Identifier::from("or"),
                 parameters: vec!(Identifier::from("x")),
-                body: Box::new(AST::Boolean(true))}),
+                body: Box::new(AST::Boolean(true)),
+                span: None}),
 
             Box::new(AST::Function {
                 name: Identifier::from("and"),
                 parameters: vec!(Identifier::from("x")),
-                body: Box::new(AST::AccessVariable { name: Identifier::from("x") })}),
+                body: Box::new(AST::AccessVariable { name: Identifier::from("x"), span: None }),
+                span: None}),
 
             Box::new(AST::Variable {
                 name: Identifier::from("hash"),
-                value: Box::new(AST::Integer(1))}),
+                value: Box::new(AST::Integer(1)),
+                span: None}),
 
             Box::new(AST::Function {
                 name: Identifier::from(Operator::Addition),
                 parameters: vec!(Identifier::from("x")),
-                body: Box::new(AST::Boolean(true))}),
+                body: Box::new(AST::Boolean(true)),
+                span: None}),
 
             Box::new(AST::Function {
                 name: Identifier::from(Operator::Multiplication),
                 parameters: vec!(Identifier::from("x")),
-                body: Box::new(AST::AccessVariable { name: Identifier::from("x") })}),
+                body: Box::new(AST::AccessVariable { name: Identifier::from("x"), span: None }),
+                span: None}),
 
             Box::new(AST::Function {
                 name: Identifier::from("me"),
                 parameters: vec!(),
-                body: Box::new(AST::AccessVariable { name: Identifier::from("this") })}),
-        )
+                body: Box::new(AST::AccessVariable { name: Identifier::from("this"), span: None }),
+                span: None}),
+        ),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -1098,8 +1263,9 @@ use crate::bytecode::compiler::*;
 
 #[test] fn field_access_test () {
     let ast = AST::AccessField {
-        object: Box::new(AST::AccessVariable { name: Identifier::from("obj") }),
+        object: Box::new(AST::AccessVariable { name: Identifier::from("obj"), span: None }),
         field: Identifier::from("x"),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -1132,9 +1298,10 @@ use crate::bytecode::compiler::*;
 
 #[test] fn field_mutation_test () {
     let ast = AST::AssignField {
-        object: Box::new(AST::AccessVariable { name: Identifier::from("obj") }),
+        object: Box::new(AST::AccessVariable { name: Identifier::from("obj"), span: None }),
         field: Identifier::from("x"),
         value: Box::new(AST::Integer(42)),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -1173,7 +1340,8 @@ use crate::bytecode::compiler::*;
         arguments: vec!(Box::new(AST::Integer(1)),
                         Box::new(AST::Integer(2)),
                         Box::new(AST::Integer(3))),
-        object: Box::new(AST::AccessVariable { name: Identifier::from("obj") })
+        object: Box::new(AST::AccessVariable { name: Identifier::from("obj"), span: None }),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -1216,7 +1384,8 @@ use crate::bytecode::compiler::*;
     let ast = AST::CallMethod {
         name: Identifier::from("f"),
         arguments: vec!(Box::new(AST::Integer(42))),
-        object: Box::new(AST::AccessVariable { name: Identifier::from("obj") })
+        object: Box::new(AST::AccessVariable { name: Identifier::from("obj"), span: None }),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -1253,7 +1422,8 @@ use crate::bytecode::compiler::*;
     let ast = AST::CallMethod {
         name: Identifier::from("f"),
         arguments: vec!(),
-        object: Box::new(AST::AccessVariable { name: Identifier::from("obj") })
+        object: Box::new(AST::AccessVariable { name: Identifier::from("obj"), span: None }),
+        span: None
     };
 
     let mut global_environment = Environment::new();
@@ -1289,6 +1459,7 @@ use crate::bytecode::compiler::*;
         name: Identifier::from(Operator::Subtraction),
         arguments: vec!(Box::new(AST::Integer(1))),
         object: Box::new(AST::Integer(7)),
+        span: None,
     };
 
     let mut global_environment = Environment::new();
@@ -1327,6 +1498,7 @@ use crate::bytecode::compiler::*;
         name: Identifier::from(Operator::Subtraction),
         object: Box::new(AST::Integer(1)),
         arguments: vec![Box::new(AST::Integer(7))],
+        span: None,
     };
 
     let mut global_environment = Environment::new();
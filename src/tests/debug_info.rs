@@ -0,0 +1,156 @@
+use std::io::Cursor;
+
+use crate::bytecode::bytecode::OpCode;
+use crate::bytecode::debug_info::*;
+use crate::bytecode::program::*;
+use crate::bytecode::serializable::Serializable;
+use crate::parser::Span;
+use crate::parser::diagnostics::SourceFile;
+use super::{compile_with_debug_info, execute, Setup};
+
+#[test] fn line_table_maps_instructions_to_lines() {
+    let source = SourceFile::new("test.fml", "let x = 1;\nprint(\"~\", x + 2);\n");
+
+    let program = compile_with_debug_info(&source).unwrap();
+    let debug = program.debug.as_ref().unwrap();
+
+    assert_eq!(debug.files, vec!["test.fml".to_owned()]);
+    let lines: Vec<usize> = (0..program.code.length())
+        .map(|address| debug.locate(Address::from_usize(address)).unwrap().line)
+        .collect();
+    assert_eq!(lines, vec![1, 1, 1, 2, 2, 2, 2]);
+    assert_eq!(debug.locate(Address::from_usize(4)),
+               Some(SourceLocation { file: "test.fml".to_owned(), line: 2, column: 12, span: Span::new(22, 27) }));
+}
+
+#[test] fn line_table_merges_consecutive_instructions() {
+    let source = SourceFile::new("test.fml", "print(\"~~\", 1, 2)");
+
+    let program = compile_with_debug_info(&source).unwrap();
+    let debug = program.debug.unwrap();
+
+    let ranges: Vec<String> = debug.lines.iter().map(|entry| entry.code.to_string()).collect();
+    assert_eq!(ranges, vec!["0000-0002"]);
+}
+
+#[test] fn debug_info_survives_serialization() {
+    let source = SourceFile::new("test.fml", "function f(x) -> x + 1;\nprint(\"~\", f(2));\n");
+    let program = compile_with_debug_info(&source).unwrap();
+
+    let mut bytes = Vec::new();
+    program.serialize(&mut bytes).unwrap();
+    let deserialized = Program::from_bytes(&mut Cursor::new(bytes)).unwrap();
+
+    assert_eq!(deserialized.debug, program.debug);
+    assert_eq!(deserialized.code.length(), program.code.length());
+}
+
+#[test] fn plain_format_is_unchanged_without_debug_info() {
+    let source = SourceFile::new("test.fml", "print(\"~\", 1)");
+    let mut program = compile_with_debug_info(&source).unwrap();
+
+    let mut with_debug_info = Vec::new();
+    program.serialize(&mut with_debug_info).unwrap();
+    program.debug = None;
+    let mut without_debug_info = Vec::new();
+    program.serialize(&mut without_debug_info).unwrap();
+
+    assert!(with_debug_info.len() > without_debug_info.len());
+    assert_eq!(&with_debug_info[..without_debug_info.len()], without_debug_info.as_slice());
+    assert_eq!(with_debug_info[without_debug_info.len()], FLAG_DEBUG_LINES);
+    assert_eq!(Program::from_bytes(&mut Cursor::new(without_debug_info)).unwrap().debug, None);
+}
+
+#[test] fn runtime_errors_use_the_line_table() {
+    let source = SourceFile::new("test.fml", "let a = array(3, 0);\n\nprint(\"~\", a[5]);");
+    let program = compile_with_debug_info(&source).unwrap();
+
+    let error = execute(&program, Setup::default()).0.unwrap_err();
+
+    let location = error.downcast_ref::<SourceLocation>().unwrap();
+    assert_eq!(location.to_string(), "test.fml:3:12");
+}
+
+#[test] fn disassembly_shows_source_lines() {
+    let source = SourceFile::new("test.fml", "let x = 1;\n  x");
+    let program = compile_with_debug_info(&source).unwrap();
+
+    let mut disassembly = String::new();
+    program.disassemble(&mut disassembly, &[source]).unwrap();
+
+    let expected = format!("{:<32} // test.fml:2:3 | x\n", "3: get global #2");
+    assert!(disassembly.contains(&expected), "{}", disassembly);
+}
+
+fn local_names_at(program: &Program, address: usize) -> Vec<String> {
+    let debug = program.debug.as_ref().unwrap();
+    let mut names: Vec<String> = debug.locals_at(Address::from_usize(address))
+        .map(|local| format!("{}={}", local.name, local.index.value()))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test] fn local_names_follow_scopes() {
+    let source = SourceFile::new("test.fml", "function f(n) -> begin let a = n; a end;\nbegin let x = 1; x end;\nbegin let y = 2; y end;\n");
+    let program = compile_with_debug_info(&source).unwrap();
+    let debug = program.debug.as_ref().unwrap();
+
+    // f: get n, set a, drop, get a, return
+    assert_eq!(local_names_at(&program, 0), vec!["n=0"]);
+    assert_eq!(local_names_at(&program, 3), vec!["a=1", "n=0"]);
+    assert_eq!(debug.locals.len(), 2);
+
+    // The top level reuses nothing: x and y get their own slots, each live in its own block.
+    let x = program.code.length() - 6;
+    assert_eq!(debug.local_name(Address::from_usize(x + 1), &LocalFrameIndex::new(0)), Some("x"));
+    assert_eq!(debug.local_name(Address::from_usize(x + 4), &LocalFrameIndex::new(0)), None);
+    assert_eq!(debug.local_name(Address::from_usize(x + 4), &LocalFrameIndex::new(1)), Some("y"));
+}
+
+#[test] fn method_locals_include_the_receiver() {
+    let source = SourceFile::new("test.fml", "let o = object begin function m(k) -> this; end;");
+    let program = compile_with_debug_info(&source).unwrap();
+
+    assert_eq!(local_names_at(&program, 0), vec!["k=1", "this=0"]);
+}
+
+#[test] fn local_names_survive_serialization() {
+    let source = SourceFile::new("test.fml", "function f(counter) -> counter;");
+    let mut program = compile_with_debug_info(&source).unwrap();
+
+    let mut bytes = Vec::new();
+    program.serialize(&mut bytes).unwrap();
+    let debug = program.debug.take();
+    let mut without_debug_info = Vec::new();
+    program.serialize(&mut without_debug_info).unwrap();
+
+    assert_eq!(bytes[without_debug_info.len()], FLAG_DEBUG_LINES | FLAG_DEBUG_LOCALS);
+    assert_eq!(Program::from_bytes(&mut Cursor::new(bytes)).unwrap().debug, debug);
+}
+
+#[test] fn disassembly_shows_local_names() {
+    let source = SourceFile::new("test.fml", "function f(counter) -> counter;");
+    let program = compile_with_debug_info(&source).unwrap();
+
+    let disassembly = program.to_string();
+
+    assert!(disassembly.contains("0: get local ::0 (counter)"), "{}", disassembly);
+}
+
+#[test] fn runtime_errors_show_local_names() {
+    let mut constants = ConstantPool::new();
+    let name = constants.push(ProgramObject::from_str("λ:"));
+    let method = constants.push(ProgramObject::Method {
+        name, parameters: Arity::new(0), locals: Size::new(0), code: AddressRange::from(0, 2),
+    });
+    let code = Code::from(vec![OpCode::GetLocal { index: LocalFrameIndex::new(3) }, OpCode::Return]);
+    let mut program = Program::from(code, constants, Globals::new(), Entry::from(method)).unwrap();
+    let names = vec![LocalName { index: LocalFrameIndex::new(3), name: "counter".to_owned(), live: AddressRange::from(0, 2) }];
+    program.debug = Some(DebugInfo { locals: vec![MethodLocals { method, names }], ..DebugInfo::default() });
+
+    let error = execute(&program, Setup::default()).0.unwrap_err();
+
+    assert!(error.chain().any(|cause| cause.to_string() == "Error evaluating get local ::3 (counter):"),
+            "{:?}", error);
+}
@@ -0,0 +1,105 @@
+use crate::bytecode::state::*;
+use crate::bytecode::interpreter::*;
+use crate::parser::{AST, Identifier, Span};
+use crate::parser::diagnostics::*;
+
+#[test] fn locations_count_lines_and_characters() {
+    let source = SourceFile::new("test.fml", "let x = 1;\nlet λ = 2;\n");
+
+    assert_eq!(source.location(0), (1, 1));
+    assert_eq!(source.location(4), (1, 5));
+    assert_eq!(source.location(11), (2, 1));
+    assert_eq!(source.location(19), (2, 8));
+    assert_eq!(source.location(1000), (3, 1));
+}
+
+#[test] fn render_underlines_the_span() {
+    let source = SourceFile::new("test.fml", "let x = 1;\nx.foo(2);\n");
+
+    assert_eq!(source.render(&Span::new(11, 19), "no method `foo`"),
+               "test.fml:2:1: error: no method `foo`\n  |\n2 | x.foo(2);\n  | ^^^^^^^^\n");
+}
+
+#[test] fn render_underlines_only_the_first_line_of_the_span() {
+    let source = SourceFile::new("test.fml", "begin\n  1\nend");
+
+    assert_eq!(source.render(&Span::new(0, 13), "oops"),
+               "test.fml:1:1: error: oops\n  |\n1 | begin\n  | ^^^^^\n");
+}
+
+#[test] fn render_empty_span_at_end_of_file() {
+    let source = SourceFile::new("test.fml", "let x =");
+
+    assert_eq!(source.render(&Span::new(7, 7), "unexpected end of file"),
+               "test.fml:1:8: error: unexpected end of file\n  |\n1 | let x =\n  |        ^\n");
+}
+
+#[test] fn parse_errors_have_spans() {
+    let diagnostics = crate::parser::parse("let y = (x + ;").unwrap_err();
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].span, Span::new(13, 14));
+    assert!(diagnostics[0].message.starts_with("unexpected `;`, expected one of"), "{}", diagnostics[0].message);
+    assert!(diagnostics[0].expected.contains(&"LPAREN".to_owned()), "{:?}", diagnostics[0].expected);
+}
+
+#[test] fn parse_errors_are_all_reported() {
+    let source = "let a = 1 +;
+print(\"~\", a);
+let b = );
+let c = 3";
+
+    let (ast, diagnostics) = crate::parser::parse_with_recovery(source);
+
+    let spans: Vec<Span> = diagnostics.iter().map(|diagnostic| diagnostic.span).collect();
+    assert_eq!(spans, vec![Span::new(11, 12), Span::new(36, 37)]);
+    let ast = ast.unwrap().strip_locations();
+    assert_eq!(ast, AST::top(vec![
+        AST::print("~".to_owned(), vec![AST::access_variable(Identifier::from("a"))]),
+        AST::variable(Identifier::from("c"), AST::integer(3)),
+    ]));
+}
+
+#[test] fn parse_errors_in_blocks_and_members_are_recovered() {
+    let source = "begin let x = ; x end;\nlet o = object begin let f = 1; function g( -> 2; let h = 3 end;";
+
+    let (ast, diagnostics) = crate::parser::parse_with_recovery(source);
+
+    assert_eq!(diagnostics.len(), 2, "{:?}", diagnostics);
+    assert_eq!(ast.unwrap().strip_locations(), AST::top(vec![
+        AST::block(vec![AST::access_variable(Identifier::from("x"))]),
+        AST::variable(Identifier::from("o"), AST::object(AST::null(), vec![
+            AST::variable(Identifier::from("f"), AST::integer(1)),
+            AST::variable(Identifier::from("h"), AST::integer(3)),
+        ])),
+    ]));
+}
+
+#[test] fn parse_errors_at_end_of_file_leave_out_the_last_statement() {
+    let (ast, diagnostics) = crate::parser::parse_with_recovery("print(\"~\", 1");
+
+    assert_eq!(ast, Some(AST::top(vec![])));
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].message.starts_with("unexpected end of file"), "{}", diagnostics[0].message);
+}
+
+#[test] fn compiler_errors_have_spans() {
+    let source = "function f() -> begin\n  let y = 1;\n  let y = 2;\nend;";
+    let ast = crate::parser::parse_with_spans(source).unwrap();
+
+    let error = crate::bytecode::compile(&ast).unwrap_err();
+
+    assert_eq!(error.downcast_ref::<Span>(), Some(&Span::new(37, 46)));
+}
+
+#[test] fn runtime_errors_have_spans() {
+    let source = "let a = array(3, 0);\nprint(\"~\", a[5]);";
+    let ast = crate::parser::parse_with_spans(source).unwrap();
+    let program = crate::bytecode::compile(&ast).unwrap();
+    let mut state = State::from(&program).unwrap();
+    let mut output = String::new();
+
+    let error = evaluate_with(&program, &mut state, &mut output).unwrap_err();
+
+    assert_eq!(error.downcast_ref::<Span>(), Some(&Span::new(32, 36)));
+}
@@ -147,7 +147,7 @@ fn feeny_method_argument_order_bytes() -> Vec<u8> {
 }
 
 #[test] fn feeny_method_argument_order_deserialize() {
-    let object = Program::from_bytes(&mut Cursor::new(feeny_method_argument_order_bytes()));
+    let object = Program::from_bytes(&mut Cursor::new(feeny_method_argument_order_bytes())).unwrap();
     assert_eq!(feeny_method_argument_order_program(), object);
 }
 
@@ -163,6 +163,11 @@ fn feeny_method_argument_order_bytes() -> Vec<u8> {
     assert_eq!(&String::from_utf8(bytes).unwrap(), feeny_method_argument_order_source());
 }
 
+#[test] fn feeny_method_argument_order_assemble() {
+    let program = crate::bytecode::assemble(feeny_method_argument_order_source()).unwrap();
+    assert_eq!(feeny_method_argument_order_program(), program);
+}
+
 #[test] fn feeny_method_argument_order_eval() {
     let program = feeny_method_argument_order_program();
     let mut state = State::from(&program).unwrap();
@@ -343,7 +348,7 @@ fn feeny_object_member_order_bytes() -> Vec<u8> {
 }
 
 #[test] fn feeny_object_member_order_deserialize() {
-    let object = Program::from_bytes(&mut Cursor::new(feeny_object_member_order_bytes()));
+    let object = Program::from_bytes(&mut Cursor::new(feeny_object_member_order_bytes())).unwrap();
     assert_eq!(feeny_object_member_order_program(), object);
 }
 
@@ -359,6 +364,11 @@ fn feeny_object_member_order_bytes() -> Vec<u8> {
     assert_eq!(&String::from_utf8(bytes).unwrap(), feeny_object_member_order_source());
 }
 
+#[test] fn feeny_object_member_order_assemble() {
+    let program = crate::bytecode::assemble(feeny_object_member_order_source()).unwrap();
+    assert_eq!(feeny_object_member_order_program(), program);
+}
+
 #[test] fn feeny_object_member_order_eval() {
     let program = feeny_object_member_order_program();
     let mut state = State::from(&program).unwrap();
@@ -453,7 +463,7 @@ fn feeny_print_argument_order_bytes() -> Vec<u8> {
 }
 
 #[test] fn feeny_print_argument_order_deserialize() {
-    let object = Program::from_bytes(&mut Cursor::new(feeny_print_argument_order_bytes()));
+    let object = Program::from_bytes(&mut Cursor::new(feeny_print_argument_order_bytes())).unwrap();
     assert_eq!(feeny_print_argument_order_program(), object);
 }
 
@@ -469,6 +479,11 @@ fn feeny_print_argument_order_bytes() -> Vec<u8> {
     assert_eq!(&String::from_utf8(bytes).unwrap(), feeny_print_argument_order_source());
 }
 
+#[test] fn feeny_print_argument_order_assemble() {
+    let program = crate::bytecode::assemble(feeny_print_argument_order_source()).unwrap();
+    assert_eq!(feeny_print_argument_order_program(), program);
+}
+
 #[test] fn feeny_print_argument_order_eval() {
     let program = feeny_print_argument_order_program();
     let mut state = State::from(&program).unwrap();
@@ -600,7 +615,7 @@ fn feeny_function_argument_order_bytes() -> Vec<u8> {
 }
 
 #[test] fn feeny_function_argument_order_deserialize() {
-    let object = Program::from_bytes(&mut Cursor::new(feeny_function_argument_order_bytes()));
+    let object = Program::from_bytes(&mut Cursor::new(feeny_function_argument_order_bytes())).unwrap();
     assert_eq!(feeny_function_argument_order_program(), object);
 }
 
@@ -616,6 +631,11 @@ fn feeny_function_argument_order_bytes() -> Vec<u8> {
     assert_eq!(&String::from_utf8(bytes).unwrap(), feeny_function_argument_order_source());
 }
 
+#[test] fn feeny_function_argument_order_assemble() {
+    let program = crate::bytecode::assemble(feeny_function_argument_order_source()).unwrap();
+    assert_eq!(feeny_function_argument_order_program(), program);
+}
+
 #[test] fn feeny_function_argument_order_eval() {
     let program = feeny_function_argument_order_program();
     let mut state = State::from(&program).unwrap();
@@ -690,7 +710,7 @@ fn feeny_hello_world_bytes() -> Vec<u8> {
 }
 
 #[test] fn feeny_hello_world_deserialize() {
-    let object = Program::from_bytes(&mut Cursor::new(feeny_hello_world_bytes()));
+    let object = Program::from_bytes(&mut Cursor::new(feeny_hello_world_bytes())).unwrap();
     assert_eq!(feeny_hello_world_program(), object);
 }
 
@@ -706,6 +726,11 @@ fn feeny_hello_world_bytes() -> Vec<u8> {
     assert_eq!(&String::from_utf8(bytes).unwrap(), feeny_hello_world_source());
 }
 
+#[test] fn feeny_hello_world_assemble() {
+    let program = crate::bytecode::assemble(feeny_hello_world_source()).unwrap();
+    assert_eq!(feeny_hello_world_program(), program);
+}
+
 #[test] fn feeny_hello_world_eval() {
     let program = feeny_hello_world_program();
     let mut state = State::from(&program).unwrap();
@@ -1043,7 +1068,7 @@ fn feeny_fibonacci_program () -> Program {
 }
 
 #[test] fn feeny_fibonacci_deserialize() {
-    let object = Program::from_bytes(&mut Cursor::new(feeny_fibonacci_bytes()));
+    let object = Program::from_bytes(&mut Cursor::new(feeny_fibonacci_bytes())).unwrap();
     assert_eq!(feeny_fibonacci_program(), object);
 }
 
@@ -1059,6 +1084,11 @@ fn feeny_fibonacci_program () -> Program {
     assert_eq!(&String::from_utf8(bytes).unwrap(), feeny_fibonacci_source());
 }
 
+#[test] fn feeny_fibonacci_assemble() {
+    let program = crate::bytecode::assemble(feeny_fibonacci_source()).unwrap();
+    assert_eq!(feeny_fibonacci_program(), program);
+}
+
 #[test] fn feeny_fibonacci_eval() {
     let program = feeny_fibonacci_program();
     let mut state = State::from(&program).unwrap();
@@ -0,0 +1,355 @@
+use indexmap::map::IndexMap;
+
+use crate::bytecode::heap::*;
+use crate::bytecode::state::*;
+use crate::bytecode::program::LocalFrameIndex;
+use super::{compile, execute, run_with, Setup};
+
+fn run_and_collect(source: &str) -> State {
+    let (result, mut state, _) = run_with(source, Setup::default());
+    result.unwrap();
+    state.collect_garbage();
+    state
+}
+
+#[test] fn collect_unreachable_array() {
+    let mut state = State::minimal();
+    let garbage = state.heap.allocate(HeapObject::from_pointers(vec![Pointer::Null]));
+
+    state.collect_garbage();
+
+    assert!(state.heap.dereference(&garbage).is_err());
+    assert_eq!(state.heap.size(), 0);
+}
+
+#[test] fn keep_arrays_reachable_from_operand_stack() {
+    let mut state = State::minimal();
+    let inner = state.heap.allocate(HeapObject::from_pointers(vec![Pointer::from(1)]));
+    let outer = state.heap.allocate(HeapObject::from_pointers(vec![Pointer::from(inner)]));
+    state.operand_stack.push(Pointer::from(outer));
+
+    state.collect_garbage();
+
+    assert!(state.heap.dereference(&inner).is_ok());
+    assert!(state.heap.dereference(&outer).is_ok());
+}
+
+#[test] fn keep_objects_reachable_from_locals_and_parents() {
+    let mut state = State::minimal();
+    let parent = state.heap.allocate(HeapObject::empty_object());
+    let field = state.heap.allocate(HeapObject::empty_array());
+    let mut fields = IndexMap::new();
+    fields.insert("x".to_owned(), Pointer::from(field));
+    let child = state.heap.allocate(HeapObject::new_object(Pointer::from(parent), fields, IndexMap::new()));
+    state.frame_stack = FrameStack::from(Frame::from(None, vec![Pointer::from(child)]));
+
+    state.collect_garbage();
+
+    assert!(state.heap.dereference(&parent).is_ok());
+    assert!(state.heap.dereference(&field).is_ok());
+    assert!(state.heap.dereference(&child).is_ok());
+}
+
+#[test] fn reuse_freed_slots() {
+    let mut state = State::minimal();
+    let garbage = state.heap.allocate(HeapObject::empty_array());
+    state.collect_garbage();
+
+    let reused = state.heap.allocate(HeapObject::empty_object());
+
+    assert_eq!(garbage, reused);
+    assert_eq!(state.heap.dereference(&reused).unwrap(), &HeapObject::empty_object());
+}
+
+#[test] fn collect_loop_temporaries() {
+    let state = run_and_collect(r#"
+        let keep = array(3, 0);
+        let i = 0;
+        while i < 100 do begin
+            object begin let value = i; end;
+            i <- i + 1;
+        end;
+    "#);
+
+    let keep = state.frame_stack.globals.get("keep").unwrap().into_heap_reference().unwrap();
+    assert!(state.heap.dereference(&keep).is_ok());
+    assert_eq!(state.heap.size(), HeapObject::from_pointers(vec![Pointer::from(0); 3]).size());
+}
+
+fn run_with_heap_size(source: &str, size: usize) -> anyhow::Result<State> {
+    let (result, state, _) = run_with(source, Setup::heap(HeapConfig { size, ..HeapConfig::default() }));
+    result.map(|_| state)
+}
+
+#[test] fn collect_to_stay_within_heap_size() {
+    let state = run_with_heap_size(r#"
+        let i = 0;
+        while i < 200 do begin
+            array(10000, i);
+            i <- i + 1;
+        end;
+    "#, 1);
+
+    assert!(state.unwrap().heap.size() <= 1024 * 1024);
+}
+
+#[test] fn heap_exhausted() {
+    let error = run_with_heap_size("array(100000, 0)", 1).unwrap_err();
+
+    let exhausted = error.chain()
+        .find_map(|cause| cause.downcast_ref::<HeapExhausted>())
+        .expect("expecting a heap exhausted error");
+
+    assert_eq!(exhausted.limit, 1024 * 1024);
+    assert_eq!(exhausted.live, 0);
+    assert_eq!(exhausted.requested, HeapObject::from_pointers(vec![Pointer::from(0); 100000]).size());
+}
+
+#[test] fn heap_exhausted_by_live_objects() {
+    let error = run_with_heap_size(r#"
+        let a = array(40000, 0);
+        let b = array(40000, 0);
+    "#, 1).unwrap_err();
+
+    let exhausted = error.chain()
+        .find_map(|cause| cause.downcast_ref::<HeapExhausted>())
+        .expect("expecting a heap exhausted error");
+
+    assert_eq!(exhausted.live, HeapObject::from_pointers(vec![Pointer::from(0); 40000]).size());
+    assert!(exhausted.collected);
+    assert!(exhausted.to_string().ends_with("are still live after garbage collection"));
+}
+
+#[test] fn copying_compacts_live_objects() {
+    let mut state = State::minimal();
+    state.heap.set_collector(GarbageCollector::Copying);
+    let _garbage = state.heap.allocate(HeapObject::empty_array());
+    let inner = state.heap.allocate(HeapObject::from_pointers(vec![Pointer::from(42)]));
+    let outer = state.heap.allocate(HeapObject::from_pointers(vec![Pointer::from(inner)]));
+    state.operand_stack.push(Pointer::from(outer));
+
+    state.collect_garbage();
+
+    let expected_inner = HeapObject::from_pointers(vec![Pointer::from(42)]);
+    let expected_outer = HeapObject::from_pointers(vec![Pointer::from(1usize)]);
+    assert_eq!(state.operand_stack, OperandStack::from(vec![Pointer::from(0usize)]));
+    assert_eq!(state.heap, Heap::from(vec![expected_outer, expected_inner]));
+}
+
+#[test] fn copying_rewrites_frames_globals_and_parents() {
+    let mut state = State::minimal();
+    state.heap.set_collector(GarbageCollector::Copying);
+    let _garbage = state.heap.allocate(HeapObject::empty_array());
+    let parent = state.heap.allocate(HeapObject::empty_object());
+    let child = state.heap.allocate(HeapObject::new_object(Pointer::from(parent), IndexMap::new(), IndexMap::new()));
+    state.frame_stack = FrameStack::from(Frame::from(None, vec![Pointer::from(child)]));
+    state.frame_stack.globals.define("parent".to_owned(), Pointer::from(parent)).unwrap();
+
+    state.collect_garbage();
+
+    let child = state.frame_stack.get_locals().unwrap().get(&LocalFrameIndex::new(0)).unwrap().into_heap_reference().unwrap();
+    let parent = state.frame_stack.globals.get("parent").unwrap().into_heap_reference().unwrap();
+    let child_object = state.heap.dereference(&child).unwrap().as_object_instance().unwrap();
+    assert_eq!(child_object.parent, Pointer::from(parent));
+    assert_eq!(state.heap.dereference(&parent).unwrap(), &HeapObject::empty_object());
+    assert_eq!(state.heap.size(), 2 * HeapObject::empty_object().size());
+}
+
+#[test] fn copying_within_heap_size() {
+    let source = r#"
+        let keep = object begin let list = null; end;
+        let i = 0;
+        while i < 200 do begin
+            array(10000, i);
+            keep.list <- object begin let value = i; let next = keep.list; end;
+            i <- i + 1;
+        end;
+        print("~", keep.list.next.next.value);
+    "#;
+    let setup = Setup::heap(HeapConfig { size: 1, collector: GarbageCollector::Copying, ..HeapConfig::default() });
+
+    let (result, _, output) = run_with(source, setup);
+
+    result.unwrap();
+    assert_eq!(output, "197");
+}
+
+#[test] fn no_collector_exhausts_heap() {
+    let source = r#"
+        let i = 0;
+        while i < 200 do begin
+            array(10000, i);
+            i <- i + 1;
+        end;
+    "#;
+    let setup = Setup::heap(HeapConfig { size: 1, collector: GarbageCollector::None, ..HeapConfig::default() });
+
+    let error = run_with(source, setup).0.unwrap_err();
+
+    let exhausted = error.chain()
+        .find_map(|cause| cause.downcast_ref::<HeapExhausted>())
+        .expect("expecting a heap exhausted error");
+
+    assert!(!exhausted.collected);
+    assert!(exhausted.to_string().ends_with("are in use with garbage collection disabled"));
+}
+
+fn generational_heap(promotion_age: usize) -> Heap {
+    let mut heap = Heap::new();
+    heap.set_collector(GarbageCollector::Generational);
+    heap.set_generations(GenerationConfig { nursery_size: 1, old_size: 1, promotion_age });
+    heap
+}
+
+#[test] fn minor_collection_promotes_survivors() {
+    let mut heap = generational_heap(2);
+    let mut root = Pointer::from(heap.allocate(HeapObject::empty_object()));
+    let garbage = heap.allocate(HeapObject::empty_array());
+
+    heap.collect_garbage(vec![&mut root], Collection::Minor);
+    assert!(heap.is_young(&root));
+    assert!(heap.dereference(&garbage).is_err());
+
+    heap.collect_garbage(vec![&mut root], Collection::Minor);
+    assert!(!heap.is_young(&root));
+}
+
+#[test] fn minor_collection_skips_old_objects() {
+    let mut heap = generational_heap(1);
+    let mut root = Pointer::from(heap.allocate(HeapObject::empty_object()));
+    heap.collect_garbage(vec![&mut root], Collection::Minor);
+    let old = root.into_heap_reference().unwrap();
+
+    heap.collect_garbage(vec![], Collection::Minor);
+    assert!(heap.dereference(&old).is_ok());
+
+    heap.collect_garbage(vec![], Collection::Major);
+    assert!(heap.dereference(&old).is_err());
+}
+
+#[test] fn write_barrier_remembers_old_objects() {
+    let mut heap = generational_heap(1);
+    let mut fields = IndexMap::new();
+    fields.insert("x".to_owned(), Pointer::Null);
+    let object = HeapObject::new_object(Pointer::Null, fields, IndexMap::new());
+    let mut root = Pointer::from(heap.allocate(object));
+    heap.collect_garbage(vec![&mut root], Collection::Minor);
+    let old = root.into_heap_reference().unwrap();
+
+    let young = heap.allocate(HeapObject::empty_array());
+    heap.dereference_mut(&old).unwrap()
+        .as_object_instance_mut().unwrap()
+        .set_field("x", Pointer::from(young)).unwrap();
+    heap.write_barrier(&old, &Pointer::from(young));
+
+    heap.collect_garbage(vec![], Collection::Minor);
+    assert!(heap.dereference(&young).is_ok());
+    assert!(!heap.is_young(&Pointer::from(young)));
+}
+
+#[test] fn generational_within_heap_size() {
+    let source = r#"
+        let keep = object begin let list = null; end;
+        let all = array(10, null);
+        let i = 0;
+        while i < 200 do begin
+            array(10000, i);
+            keep.list <- object begin let value = i; let next = keep.list; end;
+            all.set(i % 10, array(2, i));
+            i <- i + 1;
+        end;
+        print("~ ~", keep.list.next.next.value, all.get(3).get(1));
+    "#;
+    let setup = Setup::heap(HeapConfig {
+        size: 1,
+        collector: GarbageCollector::Generational,
+        generations: GenerationConfig { nursery_size: 64, old_size: 1, promotion_age: 1 },
+        ..HeapConfig::default()
+    });
+
+    let (result, _, output) = run_with(source, setup);
+
+    result.unwrap();
+    assert_eq!(output, "197 193");
+}
+
+#[test] fn log_distinguishes_minor_and_major_collections() {
+    let source = r#"
+        let i = 0;
+        while i < 200 do begin
+            array(10000, i);
+            i <- i + 1;
+        end;
+    "#;
+    let log = std::env::temp_dir().join("fml-generational-heap-log.csv");
+    let setup = Setup::heap(HeapConfig {
+        log: Some(log.clone()),
+        collector: GarbageCollector::Generational,
+        generations: GenerationConfig { nursery_size: 64, old_size: 1, promotion_age: 1 },
+        ..HeapConfig::default()
+    });
+
+    let (result, mut state, _) = run_with(source, setup);
+    result.unwrap();
+    state.collect_garbage();
+    drop(state);
+
+    let contents = std::fs::read_to_string(&log).unwrap();
+    assert!(contents.lines().any(|line| line.split(',').nth(1) == Some("N")));
+    assert!(contents.lines().any(|line| line.split(',').nth(1) == Some("G")));
+}
+
+#[test] fn stress_misc_programs() {
+    let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("misc");
+    let mut paths: Vec<_> = std::fs::read_dir(directory).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |extension| extension == "fml"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let source = std::fs::read_to_string(&path).unwrap();
+        let expected: Vec<&str> = source.lines()
+            .filter_map(|line| line.find("// >").map(|start| &line[start + 4..]))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .collect();
+
+        let program = compile(&source).unwrap();
+
+        for collector in vec![GarbageCollector::MarkAndSweep, GarbageCollector::Copying, GarbageCollector::Generational] {
+            let (result, _, output) = execute(&program, Setup::heap(HeapConfig { collector, stress: true, ..HeapConfig::default() }));
+
+            result.unwrap_or_else(|error| panic!("{} ({:?}): {:?}", path.display(), collector, error));
+
+            assert_eq!(output.lines().collect::<Vec<&str>>(), expected, "{} ({:?})", path.display(), collector);
+        }
+    }
+}
+
+#[test] fn verify_detects_dangling_references() {
+    let mut heap = Heap::new();
+    let first = heap.allocate(HeapObject::empty_array());
+    let second = heap.allocate(HeapObject::empty_array());
+    heap.collect_garbage(vec![], Collection::Major);
+
+    let holder = heap.allocate(HeapObject::from_pointers(vec![Pointer::Null]));
+    let freed = if holder == first { second } else { first };
+    heap.dereference_mut(&holder).unwrap()
+        .as_array_instance_mut().unwrap()
+        .set_element(0, Pointer::from(freed)).unwrap();
+
+    assert!(heap.verify(vec![]).is_ok());
+    assert!(heap.verify(vec![holder]).is_err());
+}
+
+#[test] fn verify_detects_dangling_parents() {
+    let mut heap = Heap::new();
+    let array = heap.allocate(HeapObject::empty_array());
+    let child = heap.allocate(HeapObject::new_object(Pointer::from(array), IndexMap::new(), IndexMap::new()));
+    let dangling = Pointer::from(HeapIndex::from(100));
+    let orphan = heap.allocate(HeapObject::new_object(dangling, IndexMap::new(), IndexMap::new()));
+
+    assert!(heap.verify(vec![child]).is_ok());
+    assert!(heap.verify(vec![orphan]).is_err());
+}
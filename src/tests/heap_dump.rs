@@ -0,0 +1,87 @@
+use crate::bytecode::heap::*;
+use crate::bytecode::heap_dump::*;
+use crate::bytecode::state::*;
+use crate::bytecode::interpreter::*;
+use super::{compile, run, run_with, Setup};
+
+const PROGRAM: &str = r#"
+    let a = array(2, 0);
+    let o = object extends a begin let x = a; let y = 3; function m() -> 1; end;
+"#;
+
+#[test] fn snapshot_objects_and_roots() {
+    let (result, state, _) = run_with(PROGRAM, Setup::default());
+    result.unwrap();
+    let snapshot = HeapSnapshot::from(&state);
+
+    let reference = |reference| DumpValue::Reference { reference };
+    assert_eq!(snapshot.roots.globals.get("a"), Some(&reference(0)));
+    assert_eq!(snapshot.roots.globals.get("o"), Some(&reference(1)));
+    assert_eq!(snapshot.objects[0], DumpObject::Array {
+        index: 0, elements: vec![DumpValue::Integer(0), DumpValue::Integer(0)]
+    });
+    match &snapshot.objects[1] {
+        DumpObject::Object { index, parent, fields, methods } => {
+            assert_eq!(*index, 1);
+            assert_eq!(*parent, reference(0));
+            assert_eq!(fields.get("x"), Some(&reference(0)));
+            assert_eq!(fields.get("y"), Some(&DumpValue::Integer(3)));
+            assert_eq!(methods, &vec!["m".to_owned()]);
+        }
+        object => panic!("expected an object, found {:?}", object),
+    }
+}
+
+#[test] fn snapshot_as_json() {
+    let (result, state, _) = run_with(PROGRAM, Setup::default());
+    result.unwrap();
+    let json: serde_json::Value = serde_json::from_str(&HeapSnapshot::from(&state).to_json()).unwrap();
+
+    assert_eq!(json["roots"]["globals"]["o"]["ref"], 1);
+    assert_eq!(json["objects"][0]["kind"], "array");
+    assert_eq!(json["objects"][1]["kind"], "object");
+    assert_eq!(json["objects"][1]["parent"]["ref"], 0);
+    assert_eq!(json["objects"][1]["fields"]["y"], 3);
+    assert_eq!(json["objects"][1]["methods"][0], "m");
+}
+
+#[test] fn snapshot_as_dot() {
+    let (result, state, _) = run_with(PROGRAM, Setup::default());
+    result.unwrap();
+    let dot = HeapSnapshot::from(&state).to_dot();
+
+    assert!(dot.starts_with("digraph heap {"));
+    assert!(dot.contains("o0 [label=\"{array #0|{<e0> 0|<e1> 0}}\"];"));
+    assert!(dot.contains("o1 [label=\"{object #1|<f0> x = •|<f1> y = 3|methods: m}\"];"));
+    assert!(dot.contains("roots:g1 -> o1;"));
+    assert!(dot.contains("o1:f0 -> o0;"));
+    assert!(dot.contains("o1 -> o0 [style=dashed, label=\"parent\"];"));
+}
+
+#[test] fn heap_dump_builtin() {
+    let output = run("let a = array(1, true); heap_dump(); print(\"done\");").unwrap();
+
+    let (json, rest) = output.split_at(output.rfind("done").unwrap());
+    let json: serde_json::Value = serde_json::from_str(json).unwrap();
+    assert_eq!(json["objects"][0]["elements"][0], true);
+    assert_eq!(json["roots"]["globals"]["a"]["ref"], 0);
+    assert_eq!(rest, "done");
+}
+
+#[test] fn heap_dump_defined_by_program() {
+    let output = run("function heap_dump() -> print(\"mine\"); heap_dump();").unwrap();
+    assert_eq!(output, "mine");
+}
+
+#[test] fn heap_dump_on_error() {
+    let source = "let a = array(1, 0); a.missing";
+    let program = compile(source).unwrap();
+    let path = std::env::temp_dir().join("fml-heap-dump-on-error.json");
+    let _ = std::fs::remove_file(&path);
+
+    let dumps = HeapDumpConfig { at_exit: None, on_error: Some(path.clone()) };
+    assert!(evaluate_with_memory_config(&program, HeapConfig::default(), dumps, StackLimits::default(), Budget::unlimited()).is_err());
+
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(json["objects"][0]["kind"], "array");
+}
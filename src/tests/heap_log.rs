@@ -0,0 +1,102 @@
+use crate::bytecode::heap_log::*;
+use crate::bytecode::heap::HeapConfig;
+use crate::bytecode::program::Address;
+use super::{run_with, Setup};
+
+fn log_program(source: &str, log_format: HeapLogFormat, name: &str) -> Vec<HeapEvent> {
+    let path = std::env::temp_dir().join(name);
+    let (result, mut state, _) = run_with(source, Setup::heap(HeapConfig { log: Some(path.clone()), log_format, ..HeapConfig::default() }));
+    result.unwrap();
+    state.collect_garbage();
+    drop(state);
+
+    let file = std::fs::File::open(&path).unwrap();
+    read_heap_log(std::io::BufReader::new(file)).unwrap()
+}
+
+const PROGRAM: &str = r#"
+    function make(n) -> array(n, 0);
+    let i = 0;
+    while i < 3 do begin
+        make(i);
+        i <- i + 1;
+    end;
+    object begin let x = 1; end;
+"#;
+
+#[test] fn csv_log_records_allocation_sites() {
+    let events = log_program(PROGRAM, HeapLogFormat::Csv, "fml-heap-log-sites.csv");
+
+    assert_eq!(events.first().map(|event| event.event.as_str()), Some("S"));
+    let allocations: Vec<&HeapEvent> = events.iter().filter(|event| event.is_allocation()).collect();
+    assert_eq!(allocations.len(), 4);
+    assert!(allocations[..3].iter().all(|event| event.kind.as_deref() == Some("array")));
+    assert!(allocations[..3].iter().all(|event| event.function.as_deref() == Some("make")));
+    assert_eq!(allocations[3].kind.as_deref(), Some("object"));
+    assert_eq!(allocations[3].function.as_deref(), Some("λ:"));
+    assert!(allocations.iter().all(|event| event.address.is_some() && event.size.is_some()));
+
+    let collection = events.iter().find(|event| event.is_collection()).unwrap();
+    assert_eq!(collection.event, "G");
+    assert_eq!(collection.live, Some(collection.heap));
+    assert!(collection.freed.unwrap() > 0);
+    assert!(collection.pause.is_some());
+}
+
+#[test] fn csv_and_json_lines_logs_agree() {
+    let csv = log_program(PROGRAM, HeapLogFormat::Csv, "fml-heap-log-agree.csv");
+    let json = log_program(PROGRAM, HeapLogFormat::JsonLines, "fml-heap-log-agree.jsonl");
+
+    let strip = |events: Vec<HeapEvent>| events.into_iter()
+        .map(|event| HeapEvent { timestamp: 0, pause: event.pause.map(|_| 0), ..event })
+        .collect::<Vec<HeapEvent>>();
+    assert_eq!(strip(csv), strip(json));
+}
+
+#[test] fn read_legacy_csv_log() {
+    let log = "timestamp,event,heap,copied,reclaimed\n\
+               100,S,0,,\n\
+               200,A,40,,\n\
+               300,A,100,,\n\
+               400,G,40,0,60\n";
+
+    let events = read_heap_log(log.as_bytes()).unwrap();
+    let report = HeapReport::from(&events);
+
+    assert_eq!(events.len(), 4);
+    assert_eq!(report.allocations, 2);
+    assert_eq!(report.allocated, 100);
+    assert_eq!(report.peak, 100);
+    assert_eq!(report.major_collections, 1);
+    assert_eq!(report.freed, 60);
+    assert_eq!(report.duration, 300);
+}
+
+#[test] fn report_summarizes_sites_and_pauses() {
+    let site = |function: &str, address: usize| AllocationSite {
+        address: Some(Address::from_usize(address)),
+        function: Some(function.to_owned()),
+    };
+    let mut events = vec![
+        HeapEvent::start(),
+        HeapEvent::allocation(10, "array", 10, &site("f", 1)),
+        HeapEvent::allocation(40, "object", 30, &site("g", 7)),
+        HeapEvent::allocation(50, "array", 10, &site("f", 1)),
+        HeapEvent::collection(true, 30, 20, 30, 1_000),
+        HeapEvent::collection(false, 0, 30, 0, 5_000),
+    ];
+    for (index, event) in events.iter_mut().enumerate() {
+        event.timestamp = index as u64 * 1_000_000_000;
+    }
+
+    let report = HeapReport::from(&events);
+
+    assert_eq!(report.allocations, 3);
+    assert_eq!(report.allocated, 50);
+    assert_eq!(report.allocation_rate(), 10.0);
+    assert_eq!(report.peak, 50);
+    assert_eq!((report.minor_collections, report.major_collections), (1, 1));
+    assert_eq!((report.total_pause, report.max_pause), (6_000, 5_000));
+    assert_eq!(report.sites.keys().collect::<Vec<&String>>(), vec!["g @ 0007", "f @ 0001"]);
+    assert_eq!(report.sites["f @ 0001"], SiteSummary { allocations: 2, bytes: 20 });
+}
@@ -1562,3 +1562,52 @@ fn call_method_boolean(receiver: bool, argument: bool, operation: &str, result:
     call_method_on_pointers(Pointer::from(1i32), Pointer::Null, "neq", Pointer::from(true));
 }
 
+
+// In stress mode every allocation collects first. Values popped off the operand stack for the new
+// object are only reachable through it, so they must survive, wherever the collector moves them.
+fn stressed_step<F>(code: Code, constants: ConstantPool, operands: Vec<Pointer>, check: F) where F: Fn(&State, &Pointer) {
+    let program = Program::from(code, constants, Globals::new(), Entry::from(0)).unwrap();
+    for collector in vec![GarbageCollector::MarkAndSweep, GarbageCollector::Copying, GarbageCollector::Generational] {
+        let mut state = State::minimal();
+        state.heap.set_collector(collector);
+        state.heap.set_stress(true);
+        let held = state.heap.allocate(HeapObject::from_pointers(vec![Pointer::from(7)]));
+        operands.iter().for_each(|pointer| state.operand_stack.push(*pointer));
+        state.operand_stack.push(Pointer::from(held));
+
+        step_with(&program, &mut state, &mut String::new()).unwrap();
+
+        let result = *state.operand_stack.peek().unwrap();
+        assert!(state.heap.verify(result.as_heap_reference().copied()).is_ok(), "{:?}", collector);
+        check(&state, &result);
+    }
+}
+
+fn array_elements(state: &State, pointer: &Pointer) -> Vec<Pointer> {
+    let index = pointer.as_heap_reference().unwrap();
+    state.heap.dereference(index).unwrap().as_array_instance().unwrap().iter().copied().collect()
+}
+
+#[test] fn stress_array_keeps_popped_value() {
+    let code = Code::from(vec!(OpCode::Array, OpCode::Return));
+    stressed_step(code, ConstantPool::new(), vec![Pointer::from(2i32)], |state, result| {
+        let array = array_elements(state, result);
+        assert_eq!(array.len(), 2);
+        assert_eq!(array[0], array[1]);
+        assert_eq!(array_elements(state, &array[0]), vec![Pointer::from(7)]);
+    });
+}
+
+#[test] fn stress_object_keeps_popped_field() {
+    let code = Code::from(vec!(OpCode::Object { class: ConstantPoolIndex::new(2) }, OpCode::Return));
+    let constants = ConstantPool::from(vec![
+        /*0*/ ProgramObject::String ("x".to_string()),
+        /*1*/ ProgramObject::Slot { name: ConstantPoolIndex::new(0) },
+        /*2*/ ProgramObject::Class(vec!(ConstantPoolIndex::new(1))),
+    ]);
+    stressed_step(code, constants, vec![Pointer::Null], |state, result| {
+        let index = result.as_heap_reference().unwrap();
+        let object = state.heap.dereference(index).unwrap().as_object_instance().unwrap();
+        assert_eq!(array_elements(state, object.fields.get("x").unwrap()), vec![Pointer::from(7)]);
+    });
+}
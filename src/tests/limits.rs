@@ -0,0 +1,135 @@
+use crate::bytecode::state::*;
+use crate::bytecode::interpreter::*;
+use super::{compile, run_with, Setup};
+
+fn stack_overflow(result: anyhow::Result<()>) -> StackOverflow {
+    let error = result.unwrap_err();
+    let overflow = error.chain().find_map(|cause| cause.downcast_ref::<StackOverflow>()).cloned();
+    overflow.unwrap_or_else(|| panic!("expected a stack overflow, found {:?}", error))
+}
+
+#[test] fn infinite_recursion_overflows() {
+    let source = "function forever(n) -> forever(n + 1); forever(0);";
+    let limits = StackLimits { max_frame_depth: 100, max_operands: 0 };
+
+    let (result, state, _) = run_with(source, Setup::limits(limits));
+
+    assert_eq!(stack_overflow(result),
+               StackOverflow::Frames { function: "forever".to_owned(), depth: 100, limit: 100 });
+    assert_eq!(state.frame_stack.depth(), 100);
+}
+
+#[test] fn method_recursion_overflows() {
+    let source = r#"
+        let o = object begin function down(n) -> this.down(n + 1); end;
+        o.down(0);
+    "#;
+    let limits = StackLimits { max_frame_depth: 50, max_operands: 0 };
+
+    let (result, _, _) = run_with(source, Setup::limits(limits));
+
+    assert_eq!(stack_overflow(result),
+               StackOverflow::Frames { function: "down".to_owned(), depth: 50, limit: 50 });
+}
+
+#[test] fn recursion_within_limit() {
+    let source = r#"
+        function count(n) -> if n == 0 then 0 else 1 + count(n - 1);
+        print("~", count(98));
+    "#;
+    let limits = StackLimits { max_frame_depth: 100, max_operands: 0 };
+
+    let (result, _, output) = run_with(source, Setup::limits(limits));
+
+    result.unwrap();
+    assert_eq!(output, "98");
+}
+
+#[test] fn operand_stack_overflows() {
+    let source = r#"
+        function sum(n) -> if n == 0 then 0 else n + sum(n - 1);
+        sum(100);
+    "#;
+    let limits = StackLimits { max_frame_depth: 0, max_operands: 20 };
+
+    let (result, _, _) = run_with(source, Setup::limits(limits));
+
+    assert_eq!(stack_overflow(result),
+               StackOverflow::Operands { function: "sum".to_owned(), size: 21, limit: 20 });
+}
+
+#[test] fn unlimited_stacks() {
+    let source = r#"
+        function count(n) -> if n == 0 then 0 else 1 + count(n - 1);
+        print("~", count(20000));
+    "#;
+    let limits = StackLimits { max_frame_depth: 0, max_operands: 0 };
+
+    let (result, _, output) = run_with(source, Setup::limits(limits));
+
+    result.unwrap();
+    assert_eq!(output, "20000");
+}
+
+fn budget_exhausted(result: anyhow::Result<()>) -> BudgetExhausted {
+    let error = result.unwrap_err();
+    error.downcast_ref::<BudgetExhausted>().cloned()
+        .unwrap_or_else(|| panic!("expected an exhausted budget, found {:?}", error))
+}
+
+const LOOP: &str = r#"
+    let i = 0;
+    while i < 100 do begin
+        print("~ ", i);
+        i <- i + 1;
+    end;
+"#;
+
+#[test] fn instruction_budget_stops_execution() {
+    let program = compile(LOOP).unwrap();
+    let mut state = State::from(&program).unwrap();
+    let mut output = String::new();
+
+    let result = evaluate_with_budget(&program, &mut state, &mut output, Budget::instructions(50));
+
+    assert_eq!(budget_exhausted(result), BudgetExhausted::Instructions { limit: 50 });
+    assert_eq!(state.instruction_count, 50);
+    assert!(state.instruction_pointer.get().is_some());
+    assert!(!output.is_empty());
+}
+
+#[test] fn resume_in_slices() {
+    let program = compile(LOOP).unwrap();
+    let mut expected_output = String::new();
+    let mut expected_state = State::from(&program).unwrap();
+    evaluate_with(&program, &mut expected_state, &mut expected_output).unwrap();
+
+    let mut state = State::from(&program).unwrap();
+    let mut output = String::new();
+    let mut slices = 0;
+    loop {
+        slices += 1;
+        match evaluate_with_budget(&program, &mut state, &mut output, Budget::instructions(7)) {
+            Ok(()) => break,
+            Err(error) => assert!(error.is::<BudgetExhausted>(), "{:?}", error),
+        }
+    }
+
+    assert!(slices > 1);
+    assert_eq!(output, expected_output);
+    assert_eq!(state.instruction_count, expected_state.instruction_count);
+    assert_eq!(state.frame_stack, expected_state.frame_stack);
+    assert_eq!(state.operand_stack, expected_state.operand_stack);
+}
+
+#[test] fn timeout_stops_infinite_loop() {
+    let program = compile("while true do null;").unwrap();
+    let mut state = State::from(&program).unwrap();
+    let mut output = String::new();
+    let budget = Budget { instructions: None, time: Some(std::time::Duration::from_millis(50)) };
+
+    let result = evaluate_with_budget(&program, &mut state, &mut output, budget);
+
+    assert_eq!(budget_exhausted(result), BudgetExhausted::Time { limit: budget.time.unwrap() });
+    assert!(state.instruction_count > 0);
+}
@@ -0,0 +1,80 @@
+use super::{run, error};
+
+#[test] fn continue_skips_to_the_condition() {
+    let source = r#"
+        let i = 0;
+        while i < 6 do begin
+            i <- i + 1;
+            if i % 2 == 0 then continue;
+            print("~ ", i);
+        end
+    "#;
+    assert_eq!(run(source).unwrap(), "1 3 5 ");
+}
+
+#[test] fn break_leaves_the_innermost_loop() {
+    let source = r#"
+        let i = 0;
+        while i < 3 do begin
+            i <- i + 1;
+            let j = 0;
+            while true do begin j <- j + 1; if j > i then break; print("~", j); end;
+            print(";");
+        end
+    "#;
+    assert_eq!(run(source).unwrap(), "1;12;123;");
+}
+
+#[test] fn break_value_is_the_result_of_the_loop() {
+    let source = r#"
+        function find(xs, n, x) -> begin
+            let i = 0;
+            let found = while i < n do begin if xs[i] == x then break i; i <- i + 1; end;
+            found
+        end;
+        let xs = array(4, 0);
+        xs[2] <- 7;
+        print("~ ~ ~", find(xs, 4, 7), find(xs, 4, 8), while true do break)
+    "#;
+    assert_eq!(run(source).unwrap(), "2 null null");
+}
+
+#[test] fn break_and_continue_drop_pending_operands() {
+    let source = r#"
+        let i = 0;
+        let r = while true do begin
+            i <- i + 1;
+            print("~ ~;", i, if i == 2 then continue else i * 10);
+            let o = object begin let a = i; let b = if i == 4 then break (i + 100) else 0; end;
+        end;
+        print("~", r)
+    "#;
+    assert_eq!(run(source).unwrap(), "1 10;3 30;4 40;104");
+}
+
+#[test] fn break_with_an_expression() {
+    let source = r#"
+        let k = 0;
+        print("~", while true do begin k <- k + 1; if k == 3 then break k * 10 end)
+    "#;
+    assert_eq!(run(source).unwrap(), "30");
+}
+
+#[test] fn break_inside_array_initializer() {
+    let source = r#"
+        let i = 0;
+        while i < 3 do begin
+            i <- i + 1;
+            print("~", array(2, if i == 2 then continue else i));
+        end;
+        print("~", while true do array(3, break 5))
+    "#;
+    assert_eq!(run(source).unwrap(), "[1, 1][3, 3]5");
+}
+
+#[test] fn break_and_continue_outside_of_loops() {
+    assert!(error("break 1").contains("Cannot use `break` outside of a loop"));
+    assert!(error("function f() -> continue; f()").contains("Cannot use `continue` outside of a loop"));
+    assert!(error("while true do let f = function () -> break").contains("Cannot use `break` outside of a loop"));
+    assert!(error("while true do object begin function m() -> continue; end").contains("Cannot use `continue` outside of a loop"));
+}
@@ -3,5 +3,91 @@
 #[cfg(test)] mod interpreter;
 #[cfg(test)] mod feeny;
 #[cfg(test)] mod compiler;
+#[cfg(test)] mod heap;
+#[cfg(test)] mod heap_log;
+#[cfg(test)] mod heap_dump;
+#[cfg(test)] mod limits;
+#[cfg(test)] mod stack_trace;
+#[cfg(test)] mod diagnostics;
+#[cfg(test)] mod debug_info;
+#[cfg(test)] mod session;
+#[cfg(test)] mod cli;
+#[cfg(test)] mod assembler;
+#[cfg(test)] mod verifier;
+#[cfg(test)] mod strings;
+#[cfg(test)] mod closures;
+#[cfg(test)] mod loops;
+#[cfg(test)] mod returns;
 
+#[cfg(test)] use crate::bytecode::heap::HeapConfig;
+#[cfg(test)] use crate::bytecode::program::Program;
+#[cfg(test)] use crate::bytecode::state::{State, StackLimits};
+#[cfg(test)] use crate::parser::diagnostics::SourceFile;
 
+// Shared by the tests that run FML source. Programs are verified before they run, so the tests
+// also check that the compiler emits verifiable bytecode.
+#[cfg(test)]
+fn compile(source: &str) -> anyhow::Result<Program> {
+    let ast = crate::parser::parse_with_spans(source).unwrap();
+    let program = crate::bytecode::compile(&ast)?;
+    crate::bytecode::verifier::verify(&program)?;
+    Ok(program)
+}
+
+#[cfg(test)]
+fn compile_with_debug_info(source: &SourceFile) -> anyhow::Result<Program> {
+    let ast = crate::parser::parse_with_spans(&source.text).unwrap();
+    let program = crate::bytecode::compile_with_debug_info(&ast, source)?;
+    crate::bytecode::verifier::verify(&program)?;
+    Ok(program)
+}
+
+// How a test sets up the state it runs a program in. The default is the setup of `fml run`.
+#[cfg(test)]
+#[derive(Default)]
+struct Setup {
+    heap: HeapConfig,
+    limits: StackLimits,
+}
+
+#[cfg(test)]
+impl Setup {
+    fn heap(heap: HeapConfig) -> Self {
+        Setup { heap, ..Setup::default() }
+    }
+    fn limits(limits: StackLimits) -> Self {
+        Setup { limits, ..Setup::default() }
+    }
+}
+
+// Returns the result of running the program, the state it leaves behind, and what it printed.
+#[cfg(test)]
+fn execute(program: &Program, setup: Setup) -> (anyhow::Result<()>, State, String) {
+    let mut state = State::from(program).unwrap();
+    state.heap.configure(setup.heap);
+    state.limits = setup.limits;
+    let mut output = String::new();
+    let result = crate::bytecode::interpreter::evaluate_with(program, &mut state, &mut output);
+    (result, state, output)
+}
+
+#[cfg(test)]
+fn run_with(source: &str, setup: Setup) -> (anyhow::Result<()>, State, String) {
+    execute(&compile(source).unwrap(), setup)
+}
+
+#[cfg(test)]
+fn run(source: &str) -> anyhow::Result<String> {
+    let (result, _, output) = execute(&compile(source)?, Setup::default());
+    result.map(|_| output)
+}
+
+#[cfg(test)]
+fn error(source: &str) -> String {
+    format!("{:#}", run(source).unwrap_err())
+}
+
+#[cfg(test)]
+fn compile_error(source: &str) -> String {
+    format!("{:#}", compile(source).unwrap_err())
+}
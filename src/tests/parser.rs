@@ -1,9 +1,8 @@
-use crate::fml::TopLevelParser;
 use crate::parser::*;
 
 #[allow(dead_code)]
 pub fn parse(input: &str) -> Result<crate::parser::AST, String> {
-    match crate::fml::TopLevelParser::new().parse(input) {
+    match crate::parser::parse(input) {
         Err(e) => Err(format!("{:?}", e)),
         Ok(ast) => Ok(ast),
     }
@@ -20,13 +19,13 @@ fn parse_ok(input: &str, correct: AST) {
         }
     }
     println!();
-    assert_eq!(TopLevelParser::new().parse(input), Ok(AST::Top(vec!(Box::new(correct)))));
+    assert_eq!(crate::parser::parse(input), Ok(AST::Top(vec!(Box::new(correct)))));
 }
 
 #[allow(dead_code)]
 fn parse_err(input: &str) {
     println!("{}", input);
-    assert!(TopLevelParser::new().parse(input).is_err());
+    assert!(crate::parser::parse(input).is_err());
 }
 
 #[test] fn test_unit()         { parse_ok("null", AST::null());        }
@@ -93,6 +92,13 @@ fn parse_err(input: &str) {
                  AST::access_variable(Identifier::from("x"))));
 }
 
+#[test] fn test_lambda() {
+    parse_ok("function (x, y) -> x",
+             AST::lambda(
+                 vec!(Identifier::from("x"), Identifier::from("y")),
+                 AST::access_variable(Identifier::from("x"))));
+}
+
 #[test] fn test_application_no_args() {
     parse_ok("f ()",
              AST::call_function(
@@ -221,6 +227,62 @@ fn parse_err(input: &str) {
                  AST::null()))
 }
 
+#[test] fn test_loop_break_continue() {
+    parse_ok("while true do begin continue; break; break x; break (1 + 2) end",
+             AST::loop_de_loop(
+                 AST::boolean(true),
+                 AST::block(vec!(
+                     AST::continue_loop(),
+                     AST::break_loop(AST::null()),
+                     AST::break_loop(AST::access_variable(Identifier::from("x"))),
+                     AST::break_loop(AST::operation(Operator::Addition, AST::integer(1), AST::integer(2)))))))
+}
+
+#[test] fn test_break_with_expression() {
+    parse_ok("while true do if x then break k * 10 else break let y = k",
+             AST::loop_de_loop(
+                 AST::boolean(true),
+                 AST::conditional(
+                     AST::access_variable(Identifier::from("x")),
+                     AST::break_loop(AST::operation(Operator::Multiplication,
+                                                    AST::access_variable(Identifier::from("k")),
+                                                    AST::integer(10))),
+                     AST::break_loop(AST::variable(Identifier::from("y"),
+                                                   AST::access_variable(Identifier::from("k")))))))
+}
+
+#[test] fn test_break_before_members() {
+    parse_ok("while true do object extends if x then break 1 else break begin end begin end",
+             AST::loop_de_loop(
+                 AST::boolean(true),
+                 AST::object(
+                     AST::conditional(
+                         AST::access_variable(Identifier::from("x")),
+                         AST::break_loop(AST::integer(1)),
+                         AST::break_loop(AST::null())),
+                     vec!())))
+}
+
+#[test] fn test_bare_break_before_members() {
+    parse_err("while true do object extends break begin end");
+}
+
+#[test] fn test_return() {
+    parse_ok("function f (x) -> begin if x then return x + 1; 0 end",
+             AST::function(
+                 Identifier::from("f"),
+                 vec!(Identifier::from("x")),
+                 AST::block(vec!(
+                     AST::conditional(
+                         AST::access_variable(Identifier::from("x")),
+                         AST::return_value(AST::operation(
+                             Operator::Addition,
+                             AST::access_variable(Identifier::from("x")),
+                             AST::integer(1))),
+                         AST::null()),
+                     AST::integer(0)))))
+}
+
 #[test] fn test_conditional() {
     parse_ok("if true then false else true",
              AST::conditional(
@@ -788,4 +850,61 @@ fn test_object_with_many_members() {
 
 #[test] fn test_multiline_comment() {
     parse_ok("/* \n\n\n */", AST::null());
-}
\ No newline at end of file
+}
+#[test] fn test_spans() {
+    let ast = crate::parser::parse_with_spans("let x = 1 + 22; x").unwrap();
+
+    let expected = AST::top(vec![
+        AST::variable(Identifier::from("x"),
+                      AST::operation(Operator::Addition, AST::integer(1), AST::integer(22)).located(8, 14)).located(0, 14),
+        AST::access_variable(Identifier::from("x")).located(16, 17),
+    ]);
+    assert_eq!(ast, expected);
+}
+
+#[test] fn test_spans_of_field_chain() {
+    let ast = crate::parser::parse_with_spans("o.a.b").unwrap();
+
+    let expected = AST::access_field(
+        AST::access_field(AST::access_variable(Identifier::from("o")).located(0, 1),
+                          Identifier::from("a")).located(0, 3),
+        Identifier::from("b")).located(0, 5);
+    assert_eq!(ast, AST::top(vec![expected]));
+}
+
+#[test] fn test_spans_survive_serializers() {
+    let ast = crate::parser::parse_with_spans("function f(x) -> x.g(1); print(\"~\", f(2));").unwrap();
+
+    for serializer in vec![crate::ASTSerializer::JSON, crate::ASTSerializer::LISP, crate::ASTSerializer::YAML] {
+        let serialized = serializer.serialize(&ast).unwrap();
+        assert_eq!(serializer.deserialize(&serialized).unwrap(), ast, "{:?}", serializer);
+    }
+}
+
+#[test] fn test_parse_leaves_out_spans() {
+    let ast = crate::parser::parse_with_spans("let x = y").unwrap();
+
+    assert_ne!(ast, crate::parser::parse("let x = y").unwrap());
+    assert_eq!(ast.strip_locations(), crate::parser::parse("let x = y").unwrap());
+}
+
+#[test] fn test_spans_serialize_as_fields() {
+    let ast = crate::parser::parse_with_spans("let x = 1").unwrap();
+    let serialized = crate::ASTSerializer::JSON.serialize(&ast).unwrap();
+    assert_eq!(serialized.trim_end(), r#"{"Top":[{"Variable":{"name":"x","value":{"Integer":1},"span":{"start":0,"end":9}}}]}"#);
+
+    let serialized = crate::ASTSerializer::JSON.serialize(&ast.strip_locations()).unwrap();
+    assert_eq!(serialized.trim_end(), r#"{"Top":[{"Variable":{"name":"x","value":{"Integer":1}}}]}"#);
+}
+
+#[test] fn test_spans_are_optional_in_serialized_asts() {
+    let ast = crate::ASTSerializer::JSON.deserialize(r#"{"Top":[{"Integer":1}]}"#).unwrap();
+    assert_eq!(ast, AST::top(vec![AST::integer(1)]));
+}
+
+#[test] fn test_string()         { parse_ok(r#""hello""#,   AST::string("hello".to_owned()));     }
+#[test] fn test_empty_string()   { parse_ok(r#""""#,        AST::string(String::new()));           }
+#[test] fn test_string_escapes() { parse_ok(r#""a\n\"\\""#, AST::string("a\n\"\\".to_owned()));   }
+#[test] fn test_string_concatenation() {
+    parse_ok(r#""a" + "b""#, AST::operation(Operator::Addition, AST::string("a".to_owned()), AST::string("b".to_owned())));
+}
@@ -0,0 +1,58 @@
+use super::{run, error};
+
+#[test] fn return_leaves_the_function_early() {
+    let source = r#"
+        function sign(x) -> begin
+            if x < 0 then return -1;
+            if x == 0 then return 0;
+            1
+        end;
+        print("~ ~ ~", sign(-5), sign(0), sign(5))
+    "#;
+    assert_eq!(run(source).unwrap(), "-1 0 1");
+}
+
+#[test] fn return_from_inside_loops() {
+    let source = r#"
+        function index_of(xs, n, x) -> begin
+            let i = 0;
+            while i < n do begin
+                let j = 0;
+                while j < 1 do begin if xs[i] == x then return i; j <- j + 1; end;
+                i <- i + 1;
+            end;
+            -1
+        end;
+        let xs = array(3, 0);
+        xs[1] <- 9;
+        print("~ ~", index_of(xs, 3, 9), index_of(xs, 3, 8))
+    "#;
+    assert_eq!(run(source).unwrap(), "1 -1");
+}
+
+#[test] fn return_drops_pending_operands() {
+    let source = r#"
+        function f(x) -> 1 + 2 * (if x then return 100 else 3);
+        function g(x) -> begin print("~", array(2, if x then return 5 else 0)); 7 end;
+        function h(x) -> begin let o = object begin let a = 1; let b = return x; end; 0 end;
+        print(" ~ ~ ~ ~ ~", f(true), f(false), g(true), g(false), h(11))
+    "#;
+    assert_eq!(run(source).unwrap(), "[0, 0] 100 7 5 7 11");
+}
+
+#[test] fn return_from_methods_and_lambdas() {
+    let source = r#"
+        let o = object begin
+            let v = 5;
+            function m(x) -> begin if x then return this.v; 0 end;
+        end;
+        let f = function (x) -> begin while true do return x * 2; 0 end;
+        print("~ ~ ~", o.m(true), o.m(false), f(4))
+    "#;
+    assert_eq!(run(source).unwrap(), "5 0 8");
+}
+
+#[test] fn return_at_top_level() {
+    assert!(error("return 1").contains("Cannot use `return` outside of a function"));
+    assert!(error("begin let x = 1; if x == 1 then return x; end").contains("Cannot use `return` outside of a function"));
+}
@@ -0,0 +1,98 @@
+use crate::bytecode::session::Session;
+use crate::bytecode::debug_info::SourceLocation;
+use crate::parser::Span;
+
+fn evaluate(session: &mut Session, input: &str) -> anyhow::Result<(Option<String>, String)> {
+    let ast = session.parse(input).unwrap();
+    let mut output = String::new();
+    let value = session.evaluate(input, &ast, &mut output)?;
+    Ok((value, output))
+}
+
+fn value(session: &mut Session, input: &str) -> Option<String> {
+    evaluate(session, input).unwrap().0
+}
+
+#[test] fn globals_survive_between_inputs() {
+    let mut session = Session::new();
+
+    assert_eq!(value(&mut session, "let x = 1;\n"), Some("1".to_owned()));
+    assert_eq!(value(&mut session, "x <- x + 41;\n"), Some("42".to_owned()));
+    assert_eq!(value(&mut session, "x\n"), Some("42".to_owned()));
+}
+
+#[test] fn functions_survive_between_inputs_and_can_be_redefined() {
+    let mut session = Session::new();
+
+    assert_eq!(value(&mut session, "function f(n) -> n + 1;\n"), None);
+    assert_eq!(value(&mut session, "f(1)\n"), Some("2".to_owned()));
+    assert_eq!(value(&mut session, "function f(n) -> n * 10;\n"), None);
+    assert_eq!(value(&mut session, "f(2)\n"), Some("20".to_owned()));
+}
+
+#[test] fn heap_objects_survive_between_inputs() {
+    let mut session = Session::new();
+
+    value(&mut session, "let a = array(3, 0);\n");
+    value(&mut session, "let o = object begin let v = a; function get(i) -> this.v[i]; end;\n");
+    value(&mut session, "a[2] <- 5;\n");
+
+    assert_eq!(value(&mut session, "o.get(2)\n"), Some("5".to_owned()));
+    assert_eq!(value(&mut session, "a\n"), Some("[0, 0, 5]".to_owned()));
+}
+
+#[test] fn locals_in_blocks_work_between_inputs() {
+    let mut session = Session::new();
+
+    assert_eq!(value(&mut session, "begin let a = 1; a + 1 end\n"), Some("2".to_owned()));
+    assert_eq!(value(&mut session, "begin let b = 5; let c = 6; b * c end\n"), Some("30".to_owned()));
+}
+
+#[test] fn output_and_null_results() {
+    let mut session = Session::new();
+
+    let (value, output) = evaluate(&mut session, "print(\"~ and ~\\n\", 1, true)\n").unwrap();
+
+    assert_eq!(value, None);
+    assert_eq!(output, "1 and true\n");
+}
+
+#[test] fn compile_errors_leave_the_session_unchanged() {
+    let mut session = Session::new();
+    value(&mut session, "let x = 1;\n");
+
+    assert!(evaluate(&mut session, "let x = 2;\n").is_err());
+
+    assert_eq!(session.transcript.text, "let x = 1;\n");
+    assert_eq!(value(&mut session, "x\n"), Some("1".to_owned()));
+}
+
+#[test] fn runtime_errors_keep_earlier_effects() {
+    let mut session = Session::new();
+
+    let error = evaluate(&mut session, "let x = 1;\nx.nope();\n").unwrap_err();
+
+    let location = error.downcast_ref::<SourceLocation>().unwrap();
+    assert_eq!(location.to_string(), "<repl>:2:1");
+    assert_eq!(value(&mut session, "x + 1\n"), Some("2".to_owned()));
+}
+
+#[test] fn spans_point_into_the_transcript() {
+    let mut session = Session::new();
+    value(&mut session, "let x = 1;\n");
+
+    let error = evaluate(&mut session, "x.nope()\n").unwrap_err();
+
+    assert_eq!(error.downcast_ref::<SourceLocation>().map(|location| location.span), Some(Span::new(11, 19)));
+    let diagnostics = session.parse("1 +;\n").unwrap_err();
+    assert_eq!(diagnostics[0].span, Span::new(23, 24));
+}
+
+#[test] fn unfinished_inputs_are_incomplete() {
+    assert!(crate::parser::is_incomplete("begin\n  let x = 1;\n"));
+    assert!(crate::parser::is_incomplete("f(1,\n"));
+    assert!(crate::parser::is_incomplete("let x =\n"));
+    assert!(!crate::parser::is_incomplete("begin 1 end\n"));
+    assert!(!crate::parser::is_incomplete("1 +;\n"));
+    assert!(!crate::parser::is_incomplete("\n"));
+}
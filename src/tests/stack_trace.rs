@@ -0,0 +1,88 @@
+use crate::bytecode::state::*;
+use crate::bytecode::interpreter::*;
+use crate::bytecode::stack_trace::*;
+use super::{compile, run, run_with, Setup};
+
+fn stack_trace(source: &str) -> StackTrace {
+    let error = run(source).unwrap_err();
+    error.downcast_ref::<StackTrace>().cloned()
+        .unwrap_or_else(|| panic!("expected a stack trace, found {:?}", error))
+}
+
+fn rendered(trace: &StackTrace) -> Vec<String> {
+    trace.entries.iter().map(|entry| entry.to_string()).collect()
+}
+
+#[test] fn trace_through_functions_and_methods() {
+    let source = r#"
+        function inner(o, n) -> o.missing(n);
+        let obj = object begin let x = 1; function go(a) -> inner(this, a); end;
+        function outer(k, b) -> obj.go(k + 1);
+        outer(41, true);
+    "#;
+
+    let trace = stack_trace(source);
+
+    assert_eq!(rendered(&trace), vec![
+        "inner(object(x=1), 42) at 0002",
+        "object(x=1).go(42) at 0006",
+        "outer(41, true) at 0012",
+        "<top level>",
+    ]);
+}
+
+#[test] fn trace_keeps_the_original_error() {
+    let source = "function f(x) -> x.nope(); f(null);";
+
+    let error = run(source).unwrap_err();
+
+    assert!(error.downcast_ref::<StackTrace>().is_some());
+    assert!(error.chain().any(|cause| cause.to_string().contains("nope")), "{:?}", error);
+}
+
+#[test] fn trace_renders_arguments_shallowly() {
+    let source = r#"
+        function f(a) -> a.nope();
+        f(array(7, array(1, 0)));
+    "#;
+
+    let trace = stack_trace(source);
+
+    assert_eq!(trace.entries[0].arguments, vec!["[[..], [..], [..], [..], [..], ..]"]);
+}
+
+#[test] fn trace_of_cyclic_argument() {
+    let source = r#"
+        function f(a) -> a.nope();
+        let cycle = object begin let self = null; end;
+        cycle.self <- cycle;
+        f(cycle);
+    "#;
+
+    let trace = stack_trace(source);
+
+    assert_eq!(trace.entries[0].arguments, vec!["object(self=object(..))"]);
+}
+
+#[test] fn stack_overflow_trace_is_abbreviated() {
+    let source = "function forever(n) -> forever(n + 1); forever(0);";
+    let limits = StackLimits { max_frame_depth: 100, max_operands: 0 };
+
+    let error = run_with(source, Setup::limits(limits)).0.unwrap_err();
+    let trace = error.downcast_ref::<StackTrace>().unwrap();
+
+    assert_eq!(trace.entries.len(), 100);
+    let rendered = trace.to_string();
+    assert!(rendered.contains("  ... 80 frames omitted ...\n"), "{}", rendered);
+    assert!(rendered.ends_with("  in forever(0) at 0003\n  in <top level>\n"), "{}", rendered);
+}
+
+#[test] fn budget_errors_have_no_trace() {
+    let program = compile("function forever(n) -> forever(n); forever(0);").unwrap();
+    let mut state = State::from(&program).unwrap();
+    let mut output = String::new();
+
+    let error = evaluate_with_budget(&program, &mut state, &mut output, Budget::instructions(10)).unwrap_err();
+
+    assert!(error.downcast_ref::<StackTrace>().is_none());
+}
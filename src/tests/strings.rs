@@ -0,0 +1,69 @@
+use crate::bytecode::heap::*;
+use super::{compile, execute, run, error, Setup};
+
+#[test] fn string_literals_are_values() {
+    assert_eq!(run(r#"let s = "Hello"; print("~ ~\n", s, s)"#).unwrap(), "Hello Hello\n");
+}
+
+#[test] fn string_literals_resolve_escapes() {
+    assert_eq!(run(r#"print("[~]", "a\tb\n\"c\"\\~")"#).unwrap(), "[a\tb\n\"c\"\\~]");
+}
+
+#[test] fn concatenation() {
+    assert_eq!(run(r#"print("~", "abc" + "def" + 1 + true + null)"#).unwrap(), "abcdef1truenull");
+}
+
+#[test] fn equality_compares_contents() {
+    let source = r#"
+        let s = "ab";
+        print("~ ~ ~ ~ ~", s == "a" + "b", s != "ab", s == "ba", s == 1, 1 == s)
+    "#;
+    assert_eq!(run(source).unwrap(), "true false false false false");
+}
+
+#[test] fn length_get_and_substring() {
+    let source = r#"
+        let s = "héllo";
+        print("~ ~ ~ ~ ~", s.length(), s.get(1), s[4], s.substring(1, 3), s.substring(2, 2).length())
+    "#;
+    assert_eq!(run(source).unwrap(), "5 é o él 0");
+}
+
+#[test] fn to_int() {
+    assert_eq!(run(r#"print("~ ~ ~", "42".to_int() + 1, " -7 ".to_int(), "4x".to_int())"#).unwrap(), "43 -7 null");
+}
+
+#[test] fn strings_in_arrays_and_objects() {
+    let source = r#"
+        let a = array(2, "x");
+        a[1] <- a[0] + "y";
+        let o = object begin let name = "o"; end;
+        print("~ ~", a, o)
+    "#;
+    assert_eq!(run(source).unwrap(), "[x, xy] object(name=o)");
+}
+
+#[test] fn index_out_of_range() {
+    assert!(error(r#""abc"[3]"#).contains("Index out of range 3 for string \"abc\" with length 3"));
+    assert!(error(r#""abc".substring(2, 4)"#).contains("Range 2..4 out of bounds for string \"abc\" with length 3"));
+}
+
+#[test] fn unknown_method() {
+    assert!(error(r#""abc".reverse()"#).contains("Call method error: no method `reverse` in string \"abc\""));
+    assert!(error(r#""abc".length(1)"#).contains("Invalid number of arguments for method `length` in string \"abc\""));
+}
+
+#[test] fn strings_survive_collection() {
+    let source = r#"
+        let s = "";
+        let i = 0;
+        while i < 20 do begin s <- s + i % 10; i <- i + 1; end;
+        print("~", s.substring(8, 12))
+    "#;
+    let program = compile(source).unwrap();
+    for collector in vec![GarbageCollector::MarkAndSweep, GarbageCollector::Copying, GarbageCollector::Generational] {
+        let (result, _, output) = execute(&program, Setup::heap(HeapConfig { collector, stress: true, ..HeapConfig::default() }));
+        result.unwrap();
+        assert_eq!(output, "8901");
+    }
+}
@@ -0,0 +1,120 @@
+use crate::bytecode::assemble;
+use crate::bytecode::verifier::*;
+
+fn violations(text: &str) -> Vec<String> {
+    let program = assemble(text).unwrap();
+    match verify(&program) {
+        Ok(()) => Vec::new(),
+        Err(error) => error.downcast::<VerificationFailed>().unwrap().0.iter()
+            .map(|violation| violation.to_string())
+            .collect(),
+    }
+}
+
+// A function `f` with the given parameters, locals and code, called from the entry method.
+fn function(parameters: usize, locals: usize, body: &str) -> String {
+    format!(r#"
+        Constants :
+            #null: Null
+            #name: String("f")
+            #f: Method(#name, nargs:{}, nlocals:{}) :
+{}
+            #main: String("main")
+            #entry: Method(#main, nargs:0, nlocals:0) :
+                  lit #null
+                  return
+            #format: String("~")
+            #class: Class(#f)
+        Globals :
+            #f
+        Entry : #entry
+    "#, parameters, locals, body)
+}
+
+#[test] fn compiled_programs_verify() {
+    let source = r#"
+        let a = array(3, 0);
+        let o = object begin let v = 1; function m(x) -> this.v + x; end;
+        function f(n) -> if n == 0 then 0 else n + f(n - 1);
+        let i = 0;
+        while i < 3 do begin a[i] <- o.m(i); i <- i + 1; end;
+        print("~ ~\n", f(4), a);
+    "#;
+    let program = crate::bytecode::compile(&crate::parser::parse(source).unwrap()).unwrap();
+    assert!(verify(&program).is_ok());
+}
+
+#[test] fn well_formed_function() {
+    assert_eq!(violations(&function(1, 1, "
+                  get local 0
+                  set local 1
+                  branch done
+                  lit #null
+                  goto end
+               label done
+                  lit #null
+               label end
+                  return")), Vec::<String>::new());
+}
+
+#[test] fn constants_of_the_wrong_kind() {
+    assert_eq!(violations(&function(0, 0, "
+                  lit #f
+                  get slot #null
+                  printf #class 1
+                  return")),
+               vec!["method `f` at 0000: #2 should be a null, an integer, a boolean, or a string, but it is `method #1 args:0 locals:0 0000-0003`",
+                    "method `f` at 0001: #0 should be a string, but it is `null`",
+                    "method `f` at 0002: #6 should be a string, but it is `class #2`"]);
+}
+
+#[test] fn locals_outside_the_frame() {
+    assert_eq!(violations(&function(1, 1, "
+                  get local 2
+                  return")),
+               vec!["method `f` at 0000: local 2 is outside the frame, which has 2 slots"]);
+}
+
+#[test] fn labels_outside_the_method() {
+    assert_eq!(violations(r#"
+        Constants :
+            #null: Null
+            #name: String("f")
+            #f: Method(#name, nargs:0, nlocals:0) :
+                  goto elsewhere
+            #main: String("main")
+            #entry: Method(#main, nargs:0, nlocals:0) :
+               label elsewhere
+                  lit #null
+                  return
+        Globals :
+            #f
+        Entry : #entry
+    "#), vec!["method `f` at 0000: label `elsewhere` is not defined in this method"]);
+}
+
+#[test] fn operand_stack_underflow() {
+    assert_eq!(violations(&function(0, 0, "
+                  lit #null
+                  call slot #format 2
+                  return")),
+               vec!["method `f` at 0001: `call slot #5 2` pops 2 operands, but the operand stack depth is 1"]);
+}
+
+#[test] fn inconsistent_depth_at_merge() {
+    assert_eq!(violations(&function(1, 0, "
+                  get local 0
+                  branch skip
+                  lit #null
+               label skip
+                  lit #null
+                  return")),
+               vec!["method `f` at 0003: the operand stack depth is 1 on one path here and 0 on another",
+                    "method `f` at 0005: the operand stack depth is 2 when the method returns, but it should be 1"]);
+}
+
+#[test] fn functions_must_return() {
+    assert_eq!(violations(&function(0, 0, "
+                  lit #null")),
+               vec!["method `f` at 0000: the method ends without returning"]);
+}